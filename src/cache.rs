@@ -0,0 +1,548 @@
+// On-disk cache of preprocessed headers
+//
+// Preprocessing a header yields its tokens and leaves macro definitions
+// behind. Both are saved to a file named after a key which covers the
+// header's path and modification time and the macros defined when it is
+// included, starting with the predefined and command-line macros. When
+// the same header is included again in the same state, in this or a
+// later compilation, the saved result is used instead of lexing and
+// preprocessing the file again, much like a precompiled header.
+//
+// The key is saved at the start of the entry and compared when it is
+// loaded, as different keys may share a file name.
+//
+// An entry also lists every file read while preprocessing the header,
+// so that it is discarded once any of them changes and so that
+// dependency output stays complete on a cache hit. The source text and
+// macro expansions the tokens refer to are saved with them and added to
+// the source map when the entry is loaded.
+
+use crate::preprocess::{Macro, MacroType};
+use crate::source::{ExpansionId, FileId, Span, SOURCE_MAP};
+use crate::token::{keyword_map, keyword_or_symbol, Token};
+use crate::{Encoding, FloatType, IntType, TokenType};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8] = b"oxide-token-cache-6\n";
+
+// A file read while preprocessing a header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dep {
+    pub path: String,
+    pub system: bool,     // found in a system directory
+    pub mtime: (u64, u32), // seconds, nanoseconds
+}
+
+impl Dep {
+    pub fn new(path: &str, system: bool) -> Option<Self> {
+        let mtime = mtime(path)?;
+        Some(Dep {
+            path: path.to_string(),
+            system,
+            mtime,
+        })
+    }
+}
+
+// The state a header is included in: everything which may change the
+// result of preprocessing it, written out field by field.
+#[derive(Default)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    pub fn u64(&mut self, val: u64) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.0.push(val as u8);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    // FNV-1a. Unlike DefaultHasher, its result does not change between
+    // builds of the compiler.
+    fn hash(&self) -> u64 {
+        self.0.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+            (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+pub struct Entry {
+    pub deps: Vec<Dep>, // the header first
+    pub tokens: Vec<Token>,
+    pub macros: Vec<(String, Option<Macro>)>, // defined, or undefined if None
+    pub once: Vec<PathBuf>,                   // files with #pragma once
+}
+
+pub fn mtime(path: &str) -> Option<(u64, u32)> {
+    let time = fs::metadata(path).ok()?.modified().ok()?;
+    let d = time.duration_since(UNIX_EPOCH).ok()?;
+    Some((d.as_secs(), d.subsec_nanos()))
+}
+
+fn entry_path(dir: &str, key: &Key) -> PathBuf {
+    Path::new(dir).join(format!("{:016x}.tok", key.hash()))
+}
+
+// Returns the entry saved under the key unless it is missing, damaged
+// or out of date. `site` is the #include directive reading the header.
+pub fn load(dir: &str, key: &Key, site: Span) -> Option<Entry> {
+    let data = fs::read(entry_path(dir, key)).ok()?;
+    let mut r = Reader::new(data.strip_prefix(MAGIC)?, site);
+    let len = r.usize()?;
+    if r.bytes(len)? != key.0 {
+        return None;
+    }
+
+    let mut deps = vec![];
+    for _ in 0..r.u64()? {
+        let path = r.str()?;
+        let system = r.bool()?;
+        let mtime = (r.u64()?, r.u64()? as u32);
+        deps.push(Dep {
+            path,
+            system,
+            mtime,
+        });
+    }
+    if deps.iter().any(|dep| Some(dep.mtime) != mtime(&dep.path)) {
+        return None;
+    }
+
+    let tokens = r.tokens()?;
+    let mut macros = vec![];
+    for _ in 0..r.u64()? {
+        let name = r.str()?;
+        let m = if r.bool()? { Some(r.macro_def()?) } else { None };
+        macros.push((name, m));
+    }
+    let mut once = vec![];
+    for _ in 0..r.u64()? {
+        once.push(PathBuf::from(r.str()?));
+    }
+    Some(Entry {
+        deps,
+        tokens,
+        macros,
+        once,
+    })
+}
+
+// Saves an entry. The cache is only an optimization, so entries which
+// cannot be written are silently dropped.
+pub fn store(dir: &str, key: &Key, entry: &Entry, site: Span) {
+    let mut w = Writer::new(site);
+    w.out.extend_from_slice(MAGIC);
+    w.u64(key.0.len() as u64);
+    w.out.extend_from_slice(&key.0);
+
+    w.u64(entry.deps.len() as u64);
+    for dep in &entry.deps {
+        w.str(&dep.path);
+        w.bool(dep.system);
+        w.u64(dep.mtime.0);
+        w.u64(dep.mtime.1 as u64);
+    }
+    if w.tokens(&entry.tokens).is_none() {
+        return;
+    }
+    w.u64(entry.macros.len() as u64);
+    for (name, m) in &entry.macros {
+        w.str(name);
+        w.bool(m.is_some());
+        if let Some(m) = m {
+            if w.macro_def(m).is_none() {
+                return;
+            }
+        }
+    }
+    w.u64(entry.once.len() as u64);
+    for path in &entry.once {
+        w.str(&path.to_string_lossy());
+    }
+
+    // Write to a temporary file first so that a concurrent compilation
+    // never reads a partial entry.
+    let path = entry_path(dir, key);
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let ok = fs::create_dir_all(dir).is_ok() && fs::write(&tmp, &w.out).is_ok();
+    if !ok || fs::rename(&tmp, &path).is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+    files: HashMap<FileId, u64>,
+    expansions: HashMap<ExpansionId, u64>,
+    keywords: HashMap<String, TokenType>,
+    site: Span, // #include of the header
+}
+
+impl Writer {
+    fn new(site: Span) -> Self {
+        Writer {
+            out: vec![],
+            files: HashMap::new(),
+            expansions: HashMap::new(),
+            keywords: keyword_map(),
+            site,
+        }
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.out.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn bool(&mut self, val: bool) {
+        self.out.push(val as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    // Tokens share their files and expansions, so each of them is
+    // written once and referred to by index afterwards. An index is
+    // taken before the contents, which may refer to other ones.
+    fn file(&mut self, id: FileId) {
+        let n = self.files.len() as u64;
+        let idx = *self.files.entry(id).or_insert(n);
+        self.u64(idx);
+        if idx != n {
+            return;
+        }
+        let f = SOURCE_MAP.lock().unwrap().file(id).clone();
+        self.str(&f.name);
+        self.str(&f.src);
+        self.u64(f.line_delta as i64 as u64);
+
+        // The header is read by a different #include next time.
+        match f.included_from {
+            None => self.out.push(0),
+            Some(span) if span == self.site => self.out.push(1),
+            Some(span) => {
+                self.out.push(2);
+                self.span(span);
+            }
+        }
+    }
+
+    fn span(&mut self, span: Span) {
+        self.file(span.file);
+        self.u64(span.lo as u64);
+        self.u64(span.hi as u64);
+    }
+
+    fn expansion(&mut self, id: Option<ExpansionId>) {
+        let id = match id {
+            Some(id) => id,
+            None => return self.bool(false),
+        };
+        self.bool(true);
+        let n = self.expansions.len() as u64;
+        let idx = *self.expansions.entry(id).or_insert(n);
+        self.u64(idx);
+        if idx != n {
+            return;
+        }
+        let e = SOURCE_MAP.lock().unwrap().expansion(id).clone();
+        self.str(&e.name);
+        self.span(e.call_site);
+        self.expansion(e.parent);
+    }
+
+    // Returns None if the token cannot be saved.
+    fn token(&mut self, t: &Token) -> Option<()> {
+        match t.ty {
+            TokenType::Num(val, ty) => {
+                self.out.push(0);
+                self.u64(val as u64);
+                self.out.push(ty as u8);
+            }
+            TokenType::FNum(val, ty) => {
+                self.out.push(9);
+                self.u64(val.to_bits());
+                self.out.push(ty as u8);
+            }
+            TokenType::Str(ref units, enc) => {
+                self.out.push(1);
+                self.u64(units.len() as u64);
+                for u in units {
+                    self.u64(*u as u64);
+                }
+                self.out.push(enc as u8);
+            }
+            TokenType::HeaderName(ref s) => {
+                self.out.push(2);
+                self.str(s);
+            }
+            TokenType::CharLiteral(ref s) => {
+                self.out.push(3);
+                self.str(s);
+            }
+            TokenType::Ident(ref s) => {
+                self.out.push(4);
+                self.str(s);
+            }
+            TokenType::Param(val) => {
+                self.out.push(5);
+                self.u64(val as u64);
+            }
+            TokenType::NewLine => self.out.push(6),
+            TokenType::Pragma => self.out.push(7),
+            TokenType::Invalid(_) => return None,
+
+            // Keywords and punctuators are restored from their spelling.
+            ref ty => {
+                let s = t.tokstr();
+                if keyword_or_symbol(&s, &self.keywords).as_ref() != Some(ty) {
+                    return None;
+                }
+                self.out.push(8);
+                self.str(&s);
+            }
+        }
+
+        self.bool(t.stringize);
+        self.bool(t.bol);
+        self.bool(t.has_space);
+        let mut hideset = t.hideset.iter().collect::<Vec<_>>();
+        hideset.sort();
+        self.u64(hideset.len() as u64);
+        for name in hideset {
+            self.str(name);
+        }
+        self.expansion(t.expansion);
+        self.span(t.span);
+        Some(())
+    }
+
+    fn tokens(&mut self, tokens: &[Token]) -> Option<()> {
+        self.u64(tokens.len() as u64);
+        for t in tokens {
+            self.token(t)?;
+        }
+        Some(())
+    }
+
+    fn macro_def(&mut self, m: &Macro) -> Option<()> {
+        match m.ty {
+            MacroType::Objlike => self.out.push(0),
+            MacroType::Funclike(ref params, variadic) => {
+                self.out.push(1);
+                self.u64(params.len() as u64);
+                for param in params {
+                    self.str(param);
+                }
+                self.bool(variadic);
+            }
+            MacroType::Builtin => return None,
+        }
+        self.tokens(&m.tokens)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    files: Vec<Option<FileId>>, // None while being read
+    expansions: Vec<Option<ExpansionId>>,
+    keywords: HashMap<String, TokenType>,
+    site: Span,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], site: Span) -> Self {
+        Reader {
+            data,
+            files: vec![],
+            expansions: vec![],
+            keywords: keyword_map(),
+            site,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (v, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(v)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        self.u64()?.try_into().ok()
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        Some(self.u8()? != 0)
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn file(&mut self) -> Option<FileId> {
+        let idx = self.usize()?;
+        if idx != self.files.len() {
+            return *self.files.get(idx)?;
+        }
+        self.files.push(None);
+        let name = self.str()?;
+        let src = self.str()?;
+        let line_delta = self.u64()? as i64 as isize;
+        let included_from = match self.u8()? {
+            0 => None,
+            1 => Some(self.site),
+            2 => Some(self.span()?),
+            _ => return None,
+        };
+        let mut map = SOURCE_MAP.lock().unwrap();
+        let id = map.intern(name, src, line_delta, included_from);
+        self.files[idx] = Some(id);
+        Some(id)
+    }
+
+    fn span(&mut self) -> Option<Span> {
+        let file = self.file()?;
+        let (lo, hi) = (self.usize()?, self.usize()?);
+        let map = SOURCE_MAP.lock().unwrap();
+        let src = &map.file(file).src;
+        if lo > hi || !src.is_char_boundary(lo) || !src.is_char_boundary(hi) {
+            return None;
+        }
+        Some(Span::new(file, lo, hi))
+    }
+
+    fn expansion(&mut self) -> Option<Option<ExpansionId>> {
+        if !self.bool()? {
+            return Some(None);
+        }
+        let idx = self.usize()?;
+        if idx != self.expansions.len() {
+            return Some(Some((*self.expansions.get(idx)?)?));
+        }
+        self.expansions.push(None);
+        let name = self.str()?;
+        let call_site = self.span()?;
+        let parent = self.expansion()?;
+        let mut map = SOURCE_MAP.lock().unwrap();
+        let id = map.add_expansion(name, call_site, parent);
+        self.expansions[idx] = Some(id);
+        Some(Some(id))
+    }
+
+    fn token(&mut self) -> Option<Token> {
+        let ty = match self.u8()? {
+            0 => {
+                let val = self.u64()? as i64;
+                let ty = match self.u8()? {
+                    0 => IntType::Int,
+                    1 => IntType::UInt,
+                    2 => IntType::Long,
+                    3 => IntType::ULong,
+                    4 => IntType::UChar,
+                    5 => IntType::UShort,
+                    _ => return None,
+                };
+                TokenType::Num(val, ty)
+            }
+            1 => {
+                let mut units = vec![];
+                for _ in 0..self.u64()? {
+                    units.push(self.u64()?.try_into().ok()?);
+                }
+                let enc = match self.u8()? {
+                    0 => Encoding::Plain,
+                    1 => Encoding::UTF8,
+                    2 => Encoding::UTF16,
+                    3 => Encoding::UTF32,
+                    4 => Encoding::Wide,
+                    _ => return None,
+                };
+                TokenType::Str(units, enc)
+            }
+            2 => TokenType::HeaderName(self.str()?),
+            3 => TokenType::CharLiteral(self.str()?),
+            4 => TokenType::Ident(self.str()?),
+            5 => TokenType::Param(self.usize()?),
+            6 => TokenType::NewLine,
+            7 => TokenType::Pragma,
+            8 => keyword_or_symbol(&self.str()?, &self.keywords)?,
+            9 => {
+                let val = f64::from_bits(self.u64()?);
+                let ty = match self.u8()? {
+                    0 => FloatType::Float,
+                    1 => FloatType::Double,
+                    _ => return None,
+                };
+                TokenType::FNum(val, ty)
+            }
+            _ => return None,
+        };
+
+        let stringize = self.bool()?;
+        let bol = self.bool()?;
+        let has_space = self.bool()?;
+        let mut hideset = HashSet::new();
+        for _ in 0..self.u64()? {
+            hideset.insert(self.str()?);
+        }
+        let expansion = self.expansion()?;
+        let span = self.span()?;
+        Some(Token {
+            ty,
+            stringize,
+            bol,
+            has_space,
+            hideset,
+            expansion,
+            span,
+        })
+    }
+
+    fn tokens(&mut self) -> Option<Vec<Token>> {
+        let mut v = vec![];
+        for _ in 0..self.u64()? {
+            v.push(self.token()?);
+        }
+        Some(v)
+    }
+
+    fn macro_def(&mut self) -> Option<Macro> {
+        let ty = match self.u8()? {
+            0 => MacroType::Objlike,
+            1 => {
+                let mut params = vec![];
+                for _ in 0..self.u64()? {
+                    params.push(self.str()?);
+                }
+                MacroType::Funclike(params, self.bool()?)
+            }
+            _ => return None,
+        };
+        let mut m = Macro::new(ty);
+        m.tokens = self.tokens()?;
+        Some(m)
+    }
+}
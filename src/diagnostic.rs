@@ -0,0 +1,704 @@
+// Diagnostics
+//
+// Errors and warnings are values with a severity, a stable code, the span
+// they are about, secondary labels and notes. Each stage returns its
+// first error as the `Err` of a `Result`. Warnings do not stop anything,
+// so they are reported to a list which is rendered when the run ends, as
+// are errors a stage recovers from, up to a limit.
+//
+// The text renderer prints a diagnostic in the style of clang: the
+// location, the message, the source line with a caret under the start of
+// the span and `~` under the rest, and the macro expansions the span comes
+// from. For tools, diagnostics can be written as JSON or SARIF instead.
+
+use crate::source::{ExpansionId, Span, SOURCE_MAP};
+use std::fmt::Write;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref REPORTED: Mutex<Vec<Diagnostic>> = Mutex::new(vec![]);
+    static ref ERROR_LIMIT: Mutex<usize> = Mutex::new(20); // 0 for no limit
+    static ref FORMAT: Mutex<Format> = Mutex::new(Format::Text);
+    static ref PRAGMAS: Mutex<Vec<(Span, DiagnosticPragma)>> = Mutex::new(vec![]);
+}
+
+// Output format of diagnostics, set by -fdiagnostics-format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Sarif,
+}
+
+pub type Result<T> = std::result::Result<T, Diagnostic>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Fatal, // stops the run, such as when there are too many errors
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Fatal => "fatal error",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+// Codes appear in the output and in tools reading it, so a code keeps
+// its meaning once it is used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    InvalidToken,     // malformed literal, stray character, ...
+    Directive,        // malformed or misplaced preprocessor directive
+    Macro,            // bad macro definition or invocation
+    FileNotFound,     // source or header file which cannot be read
+    ErrorDirective,   // #error
+    IfExpr,           // bad #if expression
+    Syntax,
+    UndefinedVar,
+    Type,             // operands or conversions not allowed by their types
+    NotLvalue,
+    NoMember,
+    Statement,        // statement in a place it is not allowed, e.g. `break`
+    NotConstant,      // expression which must be an integer constant
+    DuplicateLabel,   // case value or label given twice
+    UndefinedLabel,   // goto to a label not in the function
+    ErrorLimit,       // -ferror-limit is reached
+    WarningDirective, // #warning
+    UnknownPragma,
+    UnusedLabel,
+    IgnoredPragma,    // pragma which does not apply where it is
+    UnknownWarning,   // -W option not known to #pragma GCC diagnostic
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        use self::Code::*;
+        match self {
+            InvalidToken => "E0001",
+            Directive => "E0002",
+            Macro => "E0003",
+            FileNotFound => "E0004",
+            ErrorDirective => "E0005",
+            IfExpr => "E0006",
+            Syntax => "E0100",
+            UndefinedVar => "E0200",
+            Type => "E0201",
+            NotLvalue => "E0202",
+            NoMember => "E0203",
+            Statement => "E0204",
+            NotConstant => "E0205",
+            DuplicateLabel => "E0206",
+            UndefinedLabel => "E0207",
+            ErrorLimit => "E0900",
+            WarningDirective => "W0001",
+            UnknownPragma => "W0002",
+            UnusedLabel => "W0003",
+            IgnoredPragma => "W0004",
+            UnknownWarning => "W0005",
+        }
+    }
+
+    // Returns the code of the warnings named by a -W option.
+    pub fn from_flag(flag: &str) -> Option<Code> {
+        Some(match flag {
+            "-Wcpp" => Code::WarningDirective,
+            "-Wunknown-pragmas" => Code::UnknownPragma,
+            "-Wunused" | "-Wunused-label" => Code::UnusedLabel,
+            "-Wignored-pragmas" => Code::IgnoredPragma,
+            "-Wunknown-warning-option" => Code::UnknownWarning,
+            _ => return None,
+        })
+    }
+}
+
+// `#pragma GCC diagnostic`, which changes the warnings reported after it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticPragma {
+    Push,
+    Pop,
+    Ignored(Code),
+}
+
+// A secondary span with a message, such as a previous declaration.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub msg: String,
+}
+
+// A suggested edit which replaces the text of `span`, or inserts at it
+// if it is empty.
+#[derive(Debug, Clone)]
+pub struct FixIt {
+    pub span: Span,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub msg: Box<str>, // not a String, to keep `Result`s small
+    pub span: Option<Span>,
+    pub expansion: Option<ExpansionId>, // macro expansion `span` comes from
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub fixits: Vec<FixIt>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, code: Code, msg: String) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            msg: msg.into(),
+            span: None,
+            expansion: None,
+            labels: vec![],
+            notes: vec![],
+            fixits: vec![],
+        }
+    }
+
+    pub fn error(code: Code, msg: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, code, msg.into())
+    }
+
+    pub fn warning(code: Code, msg: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Warning, code, msg.into())
+    }
+
+    pub fn at(mut self, span: Span, expansion: Option<ExpansionId>) -> Self {
+        self.span = Some(span);
+        self.expansion = expansion;
+        self
+    }
+
+    pub fn label(mut self, span: Span, msg: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            msg: msg.into(),
+        });
+        self
+    }
+
+    pub fn note(mut self, msg: impl Into<String>) -> Self {
+        self.notes.push(msg.into());
+        self
+    }
+
+    pub fn fix(mut self, span: Span, text: impl Into<String>) -> Self {
+        self.fixits.push(FixIt {
+            span,
+            text: text.into(),
+        });
+        self
+    }
+}
+
+// Records a diagnostic which does not stop the stage reporting it.
+pub fn report(d: Diagnostic) {
+    REPORTED.lock().unwrap().push(d);
+}
+
+// Records an error which the stage reporting it has recovered from.
+// Once the error limit is reached, returns an error to stop with.
+pub fn report_error(d: Diagnostic) -> Result<()> {
+    report(d);
+    let limit = *ERROR_LIMIT.lock().unwrap();
+    if limit != 0 && error_count() >= limit {
+        let msg = "too many errors emitted, stopping now".to_string();
+        let d = Diagnostic::new(Severity::Fatal, Code::ErrorLimit, msg);
+        return Err(d.note("use -ferror-limit=0 to report all errors"));
+    }
+    Ok(())
+}
+
+pub fn set_error_limit(limit: usize) {
+    *ERROR_LIMIT.lock().unwrap() = limit;
+}
+
+pub fn set_format(format: Format) {
+    *FORMAT.lock().unwrap() = format;
+}
+
+pub fn format() -> Format {
+    *FORMAT.lock().unwrap()
+}
+
+// Returns the number of errors reported so far.
+pub fn error_count() -> usize {
+    let reported = REPORTED.lock().unwrap();
+    reported.iter().filter(|d| d.severity == Severity::Error).count()
+}
+
+// Returns the diagnostics reported so far and clears the list.
+pub fn take_reported() -> Vec<Diagnostic> {
+    let mut diags = std::mem::take(&mut *REPORTED.lock().unwrap());
+    diags.retain(|d| !is_ignored(d));
+    diags
+}
+
+// Records a `#pragma GCC diagnostic` at `span`. Warnings are only
+// filtered when they are taken, so that ones reported by a stage before
+// the pragma is read are covered too.
+pub fn add_pragma(span: Span, pragma: DiagnosticPragma) {
+    PRAGMAS.lock().unwrap().push((span, pragma));
+}
+
+// Returns whether a warning is turned off by the pragmas before it. Like
+// GCC, a pragma applies from where it is, but only to the rest of the
+// file it is in.
+fn is_ignored(d: &Diagnostic) -> bool {
+    let span = match d.span {
+        Some(span) if d.severity == Severity::Warning => span,
+        _ => return false,
+    };
+    let mut ignored = vec![];
+    let mut saved = vec![];
+    for (at, pragma) in PRAGMAS.lock().unwrap().iter() {
+        if at.file != span.file || at.lo > span.lo {
+            continue;
+        }
+        match pragma {
+            DiagnosticPragma::Push => saved.push(ignored.clone()),
+            DiagnosticPragma::Pop => ignored = saved.pop().unwrap_or_default(),
+            DiagnosticPragma::Ignored(code) => ignored.push(*code),
+        }
+    }
+    ignored.contains(&d.code)
+}
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+struct Renderer {
+    out: String,
+    color: bool,
+}
+
+impl Renderer {
+    fn paint(&mut self, codes: &[&str], s: &str) {
+        if self.color {
+            self.out.push_str(&codes.concat());
+            self.out.push_str(s);
+            self.out.push_str(RESET);
+        } else {
+            self.out.push_str(s);
+        }
+    }
+
+    // Prints `file:line:col: severity: msg`.
+    fn header(&mut self, span: Option<Span>, severity: Severity, msg: &str, code: Option<Code>) {
+        let loc = match span {
+            Some(span) => {
+                let map = SOURCE_MAP.lock().unwrap();
+                let loc = map.lookup(span);
+                format!("{}:{}:{}: ", map.file(span.file).name, loc.line, loc.col)
+            }
+            None if severity == Severity::Note => String::new(),
+            None => "oxide: ".to_string(),
+        };
+        self.paint(&[BOLD], &loc);
+        let color = match severity {
+            Severity::Fatal | Severity::Error => RED,
+            Severity::Warning => MAGENTA,
+            Severity::Note => CYAN,
+        };
+        self.paint(&[BOLD, color], &format!("{}: ", severity.as_str()));
+        match code {
+            Some(code) => self.paint(&[BOLD], &format!("{} [{}]", msg, code.as_str())),
+            None => self.paint(&[BOLD], msg),
+        }
+        self.out.push('\n');
+    }
+
+    // Prints the line containing `span` and marks the span with a caret
+    // and `~`s. `ranges` on the same line are underlined as well.
+    fn snippet(&mut self, span: Span, ranges: &[Span]) {
+        let map = SOURCE_MAP.lock().unwrap();
+        let f = map.file(span.file);
+        let idx = f.line_index(span.lo);
+        let start = f.line_start(idx);
+        let text = f.line_text(idx);
+        let end = start + text.len() as u32;
+
+        // Marks are per character, so that a column lines up with the
+        // character above it whatever its width in bytes. Tabs are kept
+        // so that the terminal expands them the same way in both lines.
+        let mut marks: Vec<char> = text
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let col = |offset: u32| text[..(offset.clamp(start, end) - start) as usize].chars().count();
+        let mut underline = |s: Span, mark: char| {
+            if s.file != span.file || s.lo < start || s.lo > end {
+                return;
+            }
+            let (lo, hi) = (col(s.lo), col(s.hi).max(col(s.lo) + 1));
+            for i in lo..hi {
+                match marks.get_mut(i) {
+                    Some(m) if *m != '^' => *m = mark,
+                    Some(_) => (),
+                    None => marks.push(mark),
+                }
+            }
+        };
+        for s in ranges {
+            underline(*s, '~');
+        }
+        underline(span, '~');
+        let caret = col(span.lo);
+        if caret < marks.len() {
+            marks[caret] = '^';
+        } else {
+            marks.push('^');
+        }
+        let marks: String = marks.into_iter().collect();
+        let text = text.to_string();
+        drop(map);
+
+        self.out.push_str(&text);
+        self.out.push('\n');
+        self.paint(&[BOLD, GREEN], marks.trim_end());
+        self.out.push('\n');
+    }
+
+    // Prints the text of fix-its on the line of `site` under its marks.
+    fn fixits(&mut self, site: Span, fixits: &[FixIt]) {
+        let map = SOURCE_MAP.lock().unwrap();
+        let f = map.file(site.file);
+        let idx = f.line_index(site.lo);
+        let start = f.line_start(idx);
+        let text = f.line_text(idx);
+
+        let mut line = String::new();
+        let mut width = 0;
+        for fix in fixits {
+            if fix.span.file != site.file || f.line_index(fix.span.lo) != idx {
+                continue;
+            }
+            let col = text[..(fix.span.lo - start) as usize].chars().count();
+            if col < width {
+                continue;
+            }
+            let pad = text.chars().skip(width).take(col - width);
+            line.extend(pad.map(|c| if c == '\t' { '\t' } else { ' ' }));
+            line.push_str(&fix.text);
+            width = col + fix.text.chars().count();
+        }
+        drop(map);
+
+        if !line.is_empty() {
+            self.paint(&[GREEN], &line);
+            self.out.push('\n');
+        }
+    }
+
+    fn diagnostic(&mut self, d: &Diagnostic) {
+        let (site, notes) = layout(d);
+        if let Some(site) = site {
+            let includes = SOURCE_MAP.lock().unwrap().include_chain(site.file);
+            for inc in includes.iter().rev() {
+                let map = SOURCE_MAP.lock().unwrap();
+                let line = map.lookup(*inc).line;
+                let line = format!("In file included from {}:{}:\n", map.file(inc.file).name, line);
+                drop(map);
+                self.out.push_str(&line);
+            }
+        }
+
+        self.header(site, d.severity, &d.msg, Some(d.code));
+        if let Some(site) = site {
+            let ranges: Vec<Span> = d.labels.iter().filter(|l| l.msg.is_empty()).map(|l| l.span).collect();
+            self.snippet(site, &ranges);
+            self.fixits(site, &d.fixits);
+        }
+        for note in notes {
+            self.header(note.span, Severity::Note, &note.msg, None);
+            if let Some(span) = note.span {
+                self.snippet(span, &[]);
+            }
+        }
+    }
+}
+
+// A note shown after a diagnostic.
+struct Note {
+    span: Option<Span>,
+    msg: String,
+}
+
+// Returns where a diagnostic is shown and the notes following it. A token
+// from a macro expansion is shown where the outermost macro is invoked,
+// with a note for each expansion down to the place the token is written.
+// Labels with a message and plain notes come after those.
+fn layout(d: &Diagnostic) -> (Option<Span>, Vec<Note>) {
+    let mut notes = vec![];
+    let site = d.span.map(|span| {
+        let map = SOURCE_MAP.lock().unwrap();
+        let chain = map.expansion_chain(d.expansion);
+        for (i, e) in chain.iter().enumerate().rev() {
+            notes.push(Note {
+                span: Some(if i == 0 { span } else { chain[i - 1].call_site }),
+                msg: format!("expanded from macro '{}'", e.name),
+            });
+        }
+        map.call_site(span, d.expansion)
+    });
+    for l in d.labels.iter().filter(|l| !l.msg.is_empty()) {
+        notes.push(Note {
+            span: Some(l.span),
+            msg: l.msg.clone(),
+        });
+    }
+    for msg in &d.notes {
+        notes.push(Note {
+            span: None,
+            msg: msg.clone(),
+        });
+    }
+    (site, notes)
+}
+
+// Returns diagnostics in the format set by -fdiagnostics-format. Text has
+// ANSI colors if `color` is set.
+pub fn render(diags: &[Diagnostic], color: bool) -> String {
+    match *FORMAT.lock().unwrap() {
+        Format::Text => render_text(diags, color),
+        Format::Json => render_json(diags),
+        Format::Sarif => render_sarif(diags),
+    }
+}
+
+fn render_text(diags: &[Diagnostic], color: bool) -> String {
+    let mut r = Renderer {
+        out: String::new(),
+        color,
+    };
+    for d in diags {
+        r.diagnostic(d);
+    }
+
+    let count = |severity| diags.iter().filter(|d| d.severity == severity).count();
+    let plural = |n: usize, s: &str| format!("{} {}{}", n, s, if n == 1 { "" } else { "s" });
+    let (warnings, errors) = (count(Severity::Warning), count(Severity::Error));
+    let summary = match (warnings, errors) {
+        (0, 0) => return r.out,
+        (w, 0) => plural(w, "warning"),
+        (0, e) => plural(e, "error"),
+        (w, e) => format!("{} and {}", plural(w, "warning"), plural(e, "error")),
+    };
+    let _ = writeln!(r.out, "{} generated.", summary);
+    r.out
+}
+
+// The lines and columns a span covers, for machine-readable output.
+// Columns count characters from 1, and the end is the position just
+// after the span.
+struct Region {
+    file: String,
+    line: usize,
+    col: usize,
+    end_line: usize,
+    end_col: usize,
+}
+
+impl Region {
+    fn new(span: Span) -> Self {
+        let map = SOURCE_MAP.lock().unwrap();
+        let start = map.lookup(span);
+        let end = map.lookup(Span { lo: span.hi, ..span });
+        Region {
+            file: map.file(span.file).name.clone(),
+            line: start.line,
+            col: start.col,
+            end_line: end.line,
+            end_col: end.col,
+        }
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Returns the members giving the position of a span in a JSON object.
+fn json_position(span: Option<Span>) -> String {
+    let r = match span {
+        Some(span) => Region::new(span),
+        None => return String::new(),
+    };
+    format!(
+        ",\"file\":{},\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}",
+        json_str(&r.file),
+        r.line,
+        r.col,
+        r.end_line,
+        r.end_col
+    )
+}
+
+// Writes an array with an object for each diagnostic:
+//
+//   {"severity": "error", "code": "E0100", "message": "expected ';'",
+//    "file": "a.c", "line": 2, "column": 8, "end_line": 2, "end_column": 9,
+//    "notes": [{"message": ..., "file": ..., ...}],
+//    "fixits": [{"replacement": ";", "file": ..., ...}]}
+//
+// The position is left out if a diagnostic or note has none.
+fn render_json(diags: &[Diagnostic]) -> String {
+    let mut out = String::from("[");
+    for (i, d) in diags.iter().enumerate() {
+        let (site, notes) = layout(d);
+        let notes: Vec<String> = notes
+            .iter()
+            .map(|n| format!("{{\"message\":{}{}}}", json_str(&n.msg), json_position(n.span)))
+            .collect();
+        let fixits: Vec<String> = d
+            .fixits
+            .iter()
+            .map(|f| format!("{{\"replacement\":{}{}}}", json_str(&f.text), json_position(Some(f.span))))
+            .collect();
+        let _ = write!(
+            out,
+            "{}\n{{\"severity\":{},\"code\":{},\"message\":{}{},\"notes\":[{}],\"fixits\":[{}]}}",
+            if i == 0 { "" } else { "," },
+            json_str(d.severity.as_str()),
+            json_str(d.code.as_str()),
+            json_str(&d.msg),
+            json_position(site),
+            notes.join(","),
+            fixits.join(",")
+        );
+    }
+    out.push_str("\n]\n");
+    out
+}
+
+// Returns a file name as a relative URI reference.
+fn sarif_uri(name: &str) -> String {
+    let mut uri = String::new();
+    for b in name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            b => {
+                let _ = write!(uri, "%{:02X}", b);
+            }
+        }
+    }
+    uri
+}
+
+fn sarif_artifact(r: &Region) -> String {
+    format!("{{\"uri\":{}}}", json_str(&sarif_uri(&r.file)))
+}
+
+fn sarif_region(r: &Region) -> String {
+    format!(
+        "{{\"startLine\":{},\"startColumn\":{},\"endLine\":{},\"endColumn\":{}}}",
+        r.line, r.col, r.end_line, r.end_col
+    )
+}
+
+// Returns a SARIF location object, which may have only a message.
+fn sarif_location(span: Option<Span>, msg: Option<&str>) -> String {
+    let mut members = vec![];
+    if let Some(span) = span {
+        let r = Region::new(span);
+        members.push(format!(
+            "\"physicalLocation\":{{\"artifactLocation\":{},\"region\":{}}}",
+            sarif_artifact(&r),
+            sarif_region(&r)
+        ));
+    }
+    if let Some(msg) = msg {
+        members.push(format!("\"message\":{{\"text\":{}}}", json_str(msg)));
+    }
+    format!("{{{}}}", members.join(","))
+}
+
+fn sarif_fix(fix: &FixIt) -> String {
+    let r = Region::new(fix.span);
+    format!(
+        "{{\"artifactChanges\":[{{\"artifactLocation\":{},\"replacements\":[{{\"deletedRegion\":{},\"insertedContent\":{{\"text\":{}}}}}]}}]}}",
+        sarif_artifact(&r),
+        sarif_region(&r),
+        json_str(&fix.text)
+    )
+}
+
+// Writes a SARIF 2.1.0 log with one run. Each diagnostic is a result whose
+// rule is its code; notes are related locations.
+fn render_sarif(diags: &[Diagnostic]) -> String {
+    let mut codes: Vec<Code> = vec![];
+    let mut results = vec![];
+    for d in diags {
+        if !codes.contains(&d.code) {
+            codes.push(d.code);
+        }
+        let level = match d.severity {
+            Severity::Fatal | Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+        let (site, notes) = layout(d);
+        let mut members = vec![
+            format!("\"ruleId\":{}", json_str(d.code.as_str())),
+            format!("\"level\":{}", json_str(level)),
+            format!("\"message\":{{\"text\":{}}}", json_str(&d.msg)),
+        ];
+        if site.is_some() {
+            members.push(format!("\"locations\":[{}]", sarif_location(site, None)));
+        }
+        if !notes.is_empty() {
+            let notes: Vec<String> = notes.iter().map(|n| sarif_location(n.span, Some(&n.msg))).collect();
+            members.push(format!("\"relatedLocations\":[{}]", notes.join(",")));
+        }
+        if !d.fixits.is_empty() {
+            let fixes: Vec<String> = d.fixits.iter().map(sarif_fix).collect();
+            members.push(format!("\"fixes\":[{}]", fixes.join(",")));
+        }
+        results.push(format!("{{{}}}", members.join(",")));
+    }
+
+    let rules: Vec<String> = codes
+        .iter()
+        .map(|code| format!("{{\"id\":{}}}", json_str(code.as_str())))
+        .collect();
+    let mut out = String::new();
+    let _ = writeln!(out, "{{\"$schema\":\"https://json.schemastore.org/sarif-2.1.0.json\",\"version\":\"2.1.0\",\"runs\":[{{");
+    let _ = writeln!(out, "\"tool\":{{\"driver\":{{\"name\":\"oxide\",\"rules\":[{}]}}}},", rules.join(","));
+    let _ = writeln!(out, "\"columnKind\":\"unicodeCodePoints\",");
+    let _ = writeln!(out, "\"results\":[{}]", results.join(",\n"));
+    let _ = writeln!(out, "}}]}}");
+    out
+}
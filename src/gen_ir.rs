@@ -10,9 +10,12 @@
 // > Such infinite number of registers are mapped to a finite registers
 // > in a later pass.
 
-use crate::parse::{Node, NodeType};
+use crate::diagnostic::{Code, Result};
+use crate::parse::{Node, NodeType, Pragma};
+use crate::util::roundup;
 use crate::{Ctype, Scope, TokenType, Type};
 
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 lazy_static! {
@@ -21,9 +24,41 @@ lazy_static! {
     static ref RETURN_LABEL: Mutex<usize> = Mutex::new(0);
     static ref RETURN_REG: Mutex<usize> = Mutex::new(0);
     static ref BREAK_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref CONTINUE_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref NAMED_LABELS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    static ref SWITCHES: Mutex<Vec<SwitchLabels>> = Mutex::new(vec![]);
+    static ref ARG_AREA: Mutex<ArgArea> = Mutex::new(ArgArea::default());
     static ref CODE: Mutex<Vec<IR>> = Mutex::new(vec![]);
 }
 
+// Labels of the cases of a switch statement being compiled.
+struct SwitchLabels {
+    cases: Vec<(i64, usize)>, // (value, label)
+    default: usize,
+}
+
+// Frame slots holding the arguments of calls being compiled, after the
+// local variables of the function.
+#[derive(Default)]
+struct ArgArea {
+    base: usize, // offset of the first slot
+    used: usize, // slots taken by calls whose arguments are computed
+    max: usize,
+}
+
+// Integer and floating-point arguments are passed in this many
+// registers each, and the rest on the stack.
+pub const GP_ARGS: usize = 6;
+pub const FP_ARGS: usize = 8;
+
+// A switch statement with at least this many cases, whose values fill a
+// third of their range, jumps through a table.
+const JMP_TABLE_MIN_CASES: usize = 4;
+
+// Cases are compared one by one if there are at most this many of them,
+// and looked up by binary search otherwise.
+const COMPARE_CHAIN_MAX_CASES: usize = 3;
+
 fn add(op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
     let ir = IR::new(op, lhs, rhs);
     CODE.lock().unwrap().push(ir.clone());
@@ -50,18 +85,27 @@ pub struct Function {
     pub name: String,
     pub ir: Vec<IR>,
     pub stacksize: usize,
+    pub pragmas: Vec<Pragma>, // Pragmas given before the function
 }
 
 impl Function {
-    fn new(name: String, ir: Vec<IR>, stacksize: usize) -> Self {
+    fn new(name: String, ir: Vec<IR>, stacksize: usize, pragmas: Vec<Pragma>) -> Self {
         Function {
             name,
             ir,
             stacksize,
+            pragmas,
         }
     }
 }
 
+// An argument of a call, saved in a frame slot until the call is made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallArg {
+    pub offset: usize,
+    pub is_float: bool,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum IROp {
     Add,
@@ -74,27 +118,52 @@ pub enum IROp {
     Imm,
     Bprel,
     Mov,
-    Return,
-    Call(String, usize, [usize; 6]),
+    Return(bool), // returns float or double
+    // name, args, returns float or double. A call through a pointer has
+    // no name, and the address in rhs.
+    Call(String, Vec<CallArg>, bool),
     Label,
     LabelAddr(String),
     EQ,
     NE,
     LE,
     LT,
+    ULE,
+    ULT,
     AND,
     OR,
     XOR,
     SHL,
     SHR,
+    SAR,
     Mod,
+    UDiv,
+    UMod,
+    FAdd(u8),
+    FSub(u8),
+    FMul(u8),
+    FDiv(u8),
+    FEQ(u8),
+    FNE(u8),
+    FLE(u8),
+    FLT(u8),
+    FNeg(u8),
+    IntToFloat(u8),
+    FloatToInt(u8),
+    FloatToFloat(u8),
+    IntToInt(u8, bool), // size, is unsigned
     Neg,
     Jmp,
     If,
     Unless,
+    IfEq(i64),                 // jump if reg == imm
+    IfLt(i64),                 // jump if reg < imm
+    JmpTable(i64, Vec<usize>), // jump to labels[reg - min], or to rhs if out of range
     Load(u8),
     Store(u8),
     StoreArg(u8),
+    StoreFArg(u8),
+    StoreStackArg(u8), // lhs: offset, rhs: index of the stack slot
     Kill,
     Nop,
     AVX512Add,
@@ -139,28 +208,6 @@ pub enum IROp {
     AVX512And,
 }
 
-impl From<NodeType> for IROp {
-    fn from(node_type: NodeType) -> Self {
-        match node_type {
-            NodeType::BinOp(op, _, _) => Self::from(op),
-            e => panic!("cannot convert: {:?}", e),
-        }
-    }
-}
-
-impl From<TokenType> for IROp {
-    fn from(token_type: TokenType) -> Self {
-        match token_type {
-            TokenType::Plus => IROp::Add,
-            TokenType::Minus => IROp::Sub,
-            TokenType::Mul => IROp::Mul,
-            TokenType::Div => IROp::Div,
-            TokenType::LeftAngleBracket | TokenType::RightAngleBracket => IROp::LT,
-            e => panic!("cannot convert: {:?}", e),
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct IR {
     pub op: IROp,
@@ -186,6 +233,17 @@ fn jmp(x: Option<usize>) {
     add(IROp::Jmp, x, None);
 }
 
+fn new_label() -> usize {
+    let x = *NLABEL.lock().unwrap();
+    *NLABEL.lock().unwrap() += 1;
+    x
+}
+
+// Returns the label for a label name in the current function.
+fn named_label(name: String) -> usize {
+    *NAMED_LABELS.lock().unwrap().entry(name).or_insert_with(new_label)
+}
+
 fn load(ty: &Type, dst: Option<usize>, src: Option<usize>) {
     add(IROp::Load(ty.size as u8), dst, src);
 }
@@ -217,11 +275,11 @@ fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
 //
 // > This function evaluates a given node as an lvalue.
 
-fn gen_lval(node: Box<Node>) -> Option<usize> {
-    match node.op {
-        NodeType::Deref(expr) => gen_expr(expr),
+fn gen_lval(node: Node) -> Result<Option<usize>> {
+    Ok(match node.op {
+        NodeType::Deref(expr) => gen_expr(*expr)?,
         NodeType::Dot(ref expr, _, ref offset) => {
-            let r = gen_lval(expr.clone());
+            let r = gen_lval(*expr.clone())?;
             add(IROp::AddImm, r, Some(*offset));
             r
         }
@@ -237,16 +295,18 @@ fn gen_lval(node: Box<Node>) -> Option<usize> {
             add(IROp::LabelAddr(name), r, None);
             r
         }
-        _ => unreachable!(),
-    }
+        // Sema checks the operands of `=`, `&` and prefix ++ and --, but
+        // not of postfix ones.
+        _ => return Err(node.error(Code::NotLvalue, "expression is not an lvalue")),
+    })
 }
 
-fn gen_binop(ty: IROp, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let r1 = gen_expr(lhs);
-    let r2 = gen_expr(rhs);
+fn gen_binop(ty: IROp, lhs: Node, rhs: Node) -> Result<Option<usize>> {
+    let r1 = gen_expr(lhs)?;
+    let r2 = gen_expr(rhs)?;
     add(ty, r1, r2);
     kill(r2);
-    r1
+    Ok(r1)
 }
 
 fn get_inc_scale(ty: &Type) -> usize {
@@ -256,8 +316,8 @@ fn get_inc_scale(ty: &Type) -> usize {
     }
 }
 
-fn gen_pre_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let addr = gen_lval(expr);
+fn gen_pre_inc(ty: &Type, expr: Node, num: i32) -> Result<i32> {
+    let addr = gen_lval(expr)?;
     let val = *NUM_REGS.lock().unwrap();
     *NUM_REGS.lock().unwrap() += 1;
     load(ty, Some(val), addr);
@@ -266,19 +326,138 @@ fn gen_pre_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
         Some(val),
         Some(num as usize * get_inc_scale(ty)),
     );
+    wrap(Some(val), ty);
     store(ty, addr, Some(val));
     kill(addr);
-    val as i32
+    Ok(val as i32)
 }
 
-fn gen_post_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
-    let val = gen_pre_inc(ty, expr, num);
+fn gen_post_inc(ty: &Type, expr: Node, num: i32) -> Result<i32> {
+    if ty.is_float() {
+        let addr = gen_lval(expr)?;
+        let val = *NUM_REGS.lock().unwrap();
+        let new_val = Some(val + 1);
+        *NUM_REGS.lock().unwrap() += 2;
+        load(ty, Some(val), addr);
+        add(IROp::Imm, new_val, Some(float_bits(num as f64, ty)));
+        add(IROp::FAdd(ty.size as u8), new_val, Some(val));
+        store(ty, addr, new_val);
+        kill(new_val);
+        kill(addr);
+        return Ok(val as i32);
+    }
+
+    let val = gen_pre_inc(ty, expr, num)?;
     add(
         IROp::SubImm,
         Some(val as usize),
         Some(num as usize * get_inc_scale(ty)),
     );
-    val
+    wrap(Some(val as usize), ty);
+    Ok(val)
+}
+
+// Returns the bit pattern of `val` as a float or double.
+fn float_bits(val: f64, ty: &Type) -> usize {
+    match ty.size {
+        4 => (val as f32).to_bits() as usize,
+        _ => val.to_bits() as usize,
+    }
+}
+
+// Picks the form of an operation for signed or unsigned operands. The
+// plain ones are signed, except that SHR is a logical shift.
+fn signed_op(op: IROp, unsigned: bool) -> IROp {
+    match (op, unsigned) {
+        (IROp::Div, true) => IROp::UDiv,
+        (IROp::Mod, true) => IROp::UMod,
+        (IROp::LT, true) => IROp::ULT,
+        (IROp::LE, true) => IROp::ULE,
+        (IROp::SHR, false) => IROp::SAR,
+        (op, _) => op,
+    }
+}
+
+// Picks the floating-point form of an operation on values of `size` bytes.
+fn float_op(op: IROp, size: usize) -> IROp {
+    let size = size as u8;
+    match op {
+        IROp::Add => IROp::FAdd(size),
+        IROp::Sub => IROp::FSub(size),
+        IROp::Mul => IROp::FMul(size),
+        IROp::Div => IROp::FDiv(size),
+        IROp::EQ => IROp::FEQ(size),
+        IROp::NE => IROp::FNE(size),
+        IROp::LE => IROp::FLE(size),
+        IROp::LT => IROp::FLT(size),
+        // Sema rejects float operands of the other operators.
+        op => unreachable!("float operands to {:?}", op),
+    }
+}
+
+// Converts the value in `r` from type `from` to type `to`.
+fn gen_conv(r: Option<usize>, from: &Type, to: &Type) {
+    match (from.is_float(), to.is_float()) {
+        (false, true) => add(IROp::IntToFloat(to.size as u8), r, None),
+        (true, false) => add(IROp::FloatToInt(from.size as u8), r, None),
+        (true, true) if from.size != to.size => add(IROp::FloatToFloat(to.size as u8), r, None),
+        _ => (),
+    }
+
+    // An integer narrower than 8 bytes is held extended to 64 bits, as it
+    // is by a load.
+    let narrows = from.size > to.size || from.is_unsigned != to.is_unsigned;
+    if !from.is_float() && to.is_integer() && to.size < 8 && narrows {
+        add(IROp::IntToInt(to.size as u8, to.is_unsigned), r, None);
+    }
+}
+
+// Integer operations are done on 64-bit registers, so an unsigned result
+// of a narrower type is truncated to be held zero-extended, as gen_conv
+// leaves it.
+fn wrap(r: Option<usize>, ty: &Type) -> Option<usize> {
+    if ty.is_integer() && ty.is_unsigned && ty.size < 8 {
+        add(IROp::IntToInt(ty.size as u8, true), r, None);
+    }
+    r
+}
+
+// Calls a function by name, or through a pointer in `callee`.
+fn gen_call(
+    name: String,
+    callee: Option<usize>,
+    args: Vec<Node>,
+    ty: &Type,
+) -> Result<Option<usize>> {
+    // Each argument is saved in the frame once it is computed, so that
+    // only a few registers are in use however many arguments there are.
+    let first = {
+        let mut area = ARG_AREA.lock().unwrap();
+        let first = area.used;
+        area.used += args.len();
+        area.max = area.max.max(area.used);
+        first
+    };
+
+    let mut call_args = vec![];
+    for (i, arg) in args.into_iter().enumerate() {
+        let offset = ARG_AREA.lock().unwrap().base + (first + i + 1) * 8;
+        let is_float = arg.ty.is_float();
+        let val = gen_expr(arg)?;
+        let addr = Some(*NUM_REGS.lock().unwrap());
+        *NUM_REGS.lock().unwrap() += 1;
+        add(IROp::Bprel, addr, Some(offset));
+        add(IROp::Store(8), addr, val);
+        kill(addr);
+        kill(val);
+        call_args.push(CallArg { offset, is_float });
+    }
+    ARG_AREA.lock().unwrap().used = first;
+
+    let r = Some(*NUM_REGS.lock().unwrap());
+    *NUM_REGS.lock().unwrap() += 1;
+    add(IROp::Call(name, call_args, ty.is_float()), r, callee);
+    Ok(r)
 }
 
 fn to_assign_op(op: &TokenType) -> IROp {
@@ -294,57 +473,65 @@ fn to_assign_op(op: &TokenType) -> IROp {
         BitandEQ => IROp::AND,
         XorEQ => IROp::XOR,
         BitorEQ => IROp::OR,
-        e => panic!("unexpected op: {:?}", e),
+        // Called only for the operators above.
+        op => unreachable!("{:?} is not a compound assignment", op),
     }
 }
 
-fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
-    let src = gen_expr(rhs);
-    let dst = gen_lval(lhs);
+fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Node, rhs: Node) -> Result<Option<usize>> {
+    let common = match op {
+        TokenType::ShlEQ | TokenType::ShrEQ => ty.promote(),
+        _ => Type::arith_conv(ty, &rhs.ty),
+    };
+    let src = gen_expr(rhs)?;
+    let dst = gen_lval(lhs)?;
     let val = Some(*NUM_REGS.lock().unwrap());
     *NUM_REGS.lock().unwrap() += 1;
 
     load(ty, val, dst);
-    add(to_assign_op(op), val, src);
+    gen_conv(val, ty, &common);
+    if common.is_float() {
+        add(float_op(to_assign_op(op), common.size), val, src);
+    } else {
+        add(signed_op(to_assign_op(op), common.is_unsigned), val, src);
+        wrap(val, &common);
+    }
     kill(src);
+    gen_conv(val, &common, ty);
     store(ty, dst, val);
     kill(dst);
-    val
+    Ok(val)
 }
 
-fn gen_expr(node: Box<Node>) -> Option<usize> {
-    let node = *node;
-    match node.op {
+fn gen_expr(node: Node) -> Result<Option<usize>> {
+    Ok(match node.op {
         NodeType::Num(val) => {
             let r = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
             add(IROp::Imm, r, Some(val as usize));
             r
         }
+        NodeType::FNum(val) => {
+            let r = Some(*NUM_REGS.lock().unwrap());
+            *NUM_REGS.lock().unwrap() += 1;
+            add(IROp::Imm, r, Some(float_bits(val, &node.ty)));
+            r
+        }
         NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
-            let r = gen_lval(Box::new(node.clone()));
+            let r = gen_lval(node.clone())?;
             load(&node.ty, r, r);
             r
         }
-        NodeType::Call(name, args) => {
-            let mut args_ir: [usize; 6] = [0; 6];
-            for i in 0..args.len() {
-                args_ir[i] = gen_expr(Box::new(args[i].clone())).unwrap();
-            }
-
-            let r = Some(*NUM_REGS.lock().unwrap());
-            *NUM_REGS.lock().unwrap() += 1;
-
-            add(IROp::Call(name, args.len(), args_ir), r, None);
-
-            for arg in args_ir.iter().take(args.len()) {
-                kill(Some(*arg));
-            }
+        NodeType::Call(name, args) => gen_call(name, None, args, &node.ty)?,
+        NodeType::CallPtr(expr, args) => {
+            let callee = gen_expr(*expr)?;
+            let r = gen_call(String::new(), callee, args, &node.ty)?;
+            kill(callee);
             r
         }
-        NodeType::Addr(expr) => gen_lval(expr),
+        NodeType::Addr(expr) => gen_lval(*expr)?,
         NodeType::Deref(expr) => {
-            let r = gen_expr(expr);
+            let r = gen_expr(*expr)?;
             load(&node.ty, r, r);
             r
         }
@@ -357,32 +544,53 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             *NUM_REGS.lock().unwrap() += 1;
             *RETURN_REG.lock().unwrap() = r;
 
-            gen_stmt(*body);
+            gen_stmt(*body)?;
             label(Some(*RETURN_LABEL.lock().unwrap()));
 
             *RETURN_LABEL.lock().unwrap() = orig_label;
             *RETURN_REG.lock().unwrap() = orig_reg;
             Some(r)
         }
+        NodeType::Cast(expr) => {
+            let from = expr.ty.clone();
+            let r = gen_expr(*expr)?;
+            gen_conv(r, &from, &node.ty);
+            r
+        }
         NodeType::BinOp(op, lhs, rhs) => {
             use self::TokenType::*;
+            // Type of the operation, which differs from the type of the
+            // result for comparisons.
+            let ty = match op {
+                EQ | NE | LE | LeftAngleBracket | RightAngleBracket => {
+                    Type::arith_conv(&lhs.ty, &rhs.ty)
+                }
+                _ => *node.ty.clone(),
+            };
+            let arith = |op: IROp| {
+                if ty.is_float() {
+                    float_op(op, ty.size)
+                } else {
+                    signed_op(op, ty.is_unsigned)
+                }
+            };
             match op {
                 Equal => {
-                    let rhs = gen_expr(rhs);
-                    let lhs = gen_lval(lhs);
+                    let rhs = gen_expr(*rhs)?;
+                    let lhs = gen_lval(*lhs)?;
                     store(&node.ty, lhs, rhs);
                     kill(lhs);
                     rhs
                 }
-                Plus => gen_binop(IROp::Add, lhs, rhs),
-                Minus => gen_binop(IROp::Sub, lhs, rhs),
+                Plus => wrap(gen_binop(arith(IROp::Add), *lhs, *rhs)?, &ty),
+                Minus => wrap(gen_binop(arith(IROp::Sub), *lhs, *rhs)?, &ty),
                 Logand => {
                     let x = Some(*NLABEL.lock().unwrap());
                     *NLABEL.lock().unwrap() += 1;
 
-                    let r1 = gen_expr(lhs);
+                    let r1 = gen_expr(*lhs)?;
                     add(IROp::Unless, r1, x);
-                    let r2 = gen_expr(rhs);
+                    let r2 = gen_expr(*rhs)?;
                     add(IROp::Mov, r1, r2);
                     kill(r2);
                     add(IROp::Unless, r1, x);
@@ -396,13 +604,13 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                     let y = Some(*NLABEL.lock().unwrap());
                     *NLABEL.lock().unwrap() += 1;
 
-                    let r1 = gen_expr(lhs);
+                    let r1 = gen_expr(*lhs)?;
                     add(IROp::Unless, r1, x);
                     add(IROp::Imm, r1, Some(1));
                     jmp(y);
                     label(x);
 
-                    let r2 = gen_expr(rhs);
+                    let r2 = gen_expr(*rhs)?;
                     add(IROp::Mov, r1, r2);
                     kill(r2);
                     add(IROp::Unless, r1, y);
@@ -411,30 +619,39 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                     r1
                 }
                 MulEQ | DivEQ | ModEQ | AddEQ | SubEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ
-                | BitorEQ => gen_assign_op(&op, &node.ty, lhs, rhs),
-                EQ => gen_binop(IROp::EQ, lhs, rhs),
-                NE => gen_binop(IROp::NE, lhs, rhs),
-                LE => gen_binop(IROp::LE, lhs, rhs),
-                And => gen_binop(IROp::AND, lhs, rhs),
-                VerticalBar => gen_binop(IROp::OR, lhs, rhs),
-                Hat => gen_binop(IROp::XOR, lhs, rhs),
-                SHL => gen_binop(IROp::SHL, lhs, rhs),
-                SHR => gen_binop(IROp::SHR, lhs, rhs),
-                Mod => gen_binop(IROp::Mod, lhs, rhs),
+                | BitorEQ => gen_assign_op(&op, &node.ty, *lhs, *rhs)?,
+                EQ => gen_binop(arith(IROp::EQ), *lhs, *rhs)?,
+                NE => gen_binop(arith(IROp::NE), *lhs, *rhs)?,
+                LE => gen_binop(arith(IROp::LE), *lhs, *rhs)?,
+                And => gen_binop(IROp::AND, *lhs, *rhs)?,
+                VerticalBar => gen_binop(IROp::OR, *lhs, *rhs)?,
+                Hat => gen_binop(IROp::XOR, *lhs, *rhs)?,
+                SHL => wrap(gen_binop(IROp::SHL, *lhs, *rhs)?, &ty),
+                SHR => gen_binop(signed_op(IROp::SHR, ty.is_unsigned), *lhs, *rhs)?,
+                Mod => gen_binop(arith(IROp::Mod), *lhs, *rhs)?,
+                Mul => wrap(gen_binop(arith(IROp::Mul), *lhs, *rhs)?, &ty),
+                Div => gen_binop(arith(IROp::Div), *lhs, *rhs)?,
+                // `a > b` is parsed as `b < a`.
+                LeftAngleBracket => gen_binop(arith(IROp::LT), *lhs, *rhs)?,
                 Comma => {
-                    kill(gen_expr(lhs));
-                    gen_expr(rhs)
+                    kill(gen_expr(*lhs)?);
+                    gen_expr(*rhs)?
                 }
-                _ => gen_binop(IROp::from(op), lhs, rhs),
+                // The parser makes binary nodes of the operators above only.
+                op => unreachable!("binary operator {:?}", op),
             }
         }
         NodeType::Neg(expr) => {
-            let r = gen_expr(expr);
-            add(IROp::Neg, r, None);
-            r
+            let r = gen_expr(*expr)?;
+            if node.ty.is_float() {
+                add(IROp::FNeg(node.ty.size as u8), r, None);
+            } else {
+                add(IROp::Neg, r, None);
+            }
+            wrap(r, &node.ty)
         }
-        NodeType::PostInc(expr) => Some(gen_post_inc(&node.ty, expr, 1) as usize),
-        NodeType::PostDec(expr) => Some(gen_post_inc(&node.ty, expr, -1) as usize),
+        NodeType::PostInc(expr) => Some(gen_post_inc(&node.ty, *expr, 1)? as usize),
+        NodeType::PostDec(expr) => Some(gen_post_inc(&node.ty, *expr, -1)? as usize),
         NodeType::Ternary(cond, then, els) => {
             //      cond then els  then
             // return 1 ? 3 : 5; => 3
@@ -442,23 +659,23 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             *NLABEL.lock().unwrap() += 1;
             let y = Some(*NLABEL.lock().unwrap());
             *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
+            let r = gen_expr(*cond)?;
 
             add(IROp::Unless, r, x);
-            let r2 = gen_expr(then);
+            let r2 = gen_expr(*then)?;
             add(IROp::Mov, r, r2);
             kill(r2);
             jmp(y);
 
             label(x);
-            let r3 = gen_expr(els);
+            let r3 = gen_expr(*els)?;
             add(IROp::Mov, r, r3);
             kill(r3);
             label(y);
             r
         }
         NodeType::Exclamation(expr) => {
-            let lhs = gen_expr(expr);
+            let lhs = gen_expr(*expr)?;
             let rhs = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
             add(IROp::Imm, rhs, Some(0));
@@ -466,16 +683,70 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             kill(rhs);
             lhs
         }
-        e => unreachable!("{:?}", e),
+        // Sema replaces identifiers, literals and sizeof, and the parser
+        // puts no statement in an expression.
+        e => unreachable!("expression {:?}", e),
+    })
+}
+
+// Returns whether code defines labels which are not made anew each time
+// it is generated, that is, ones older than `first_new` or named ones.
+fn has_fixed_labels(ir: &[IR], first_new: usize) -> bool {
+    let named = NAMED_LABELS.lock().unwrap();
+    ir.iter().any(|ir| match (&ir.op, ir.lhs) {
+        (IROp::Label, Some(x)) => x < first_new || named.values().any(|&y| y == x),
+        _ => false,
+    })
+}
+
+// Jumps to the label of the case whose value is in `r`, or to `default`.
+// `cases` are sorted by value. Dense cases are looked up in a jump table,
+// and sparse ones by binary search down to short compare chains, which
+// may in turn find dense runs of cases.
+fn gen_switch(r: Option<usize>, cases: &[(i64, usize)], default: usize) {
+    if is_dense(cases) {
+        let min = cases[0].0;
+        let len = (cases[cases.len() - 1].0 - min + 1) as usize;
+        let mut labels = vec![default; len];
+        for &(val, x) in cases {
+            labels[(val - min) as usize] = x;
+        }
+        add(IROp::JmpTable(min, labels), r, Some(default));
+        return;
     }
+
+    if cases.len() <= COMPARE_CHAIN_MAX_CASES {
+        for &(val, x) in cases {
+            add(IROp::IfEq(val), r, Some(x));
+        }
+        jmp(Some(default));
+        return;
+    }
+
+    let mid = cases.len() / 2;
+    let (val, x) = cases[mid];
+    let lower = new_label();
+    add(IROp::IfEq(val), r, Some(x));
+    add(IROp::IfLt(val), r, Some(lower));
+    gen_switch(r, &cases[mid + 1..], default);
+    label(Some(lower));
+    gen_switch(r, &cases[..mid], default);
+}
+
+fn is_dense(cases: &[(i64, usize)]) -> bool {
+    if cases.len() < JMP_TABLE_MIN_CASES {
+        return false;
+    }
+    let range = cases[cases.len() - 1].0 as i128 - cases[0].0 as i128 + 1;
+    range <= 3 * cases.len() as i128
 }
 
-fn gen_stmt(node: Node) {
+fn gen_stmt(node: Node) -> Result<()> {
     match node.op {
         NodeType::Null => (),
         NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
             if let Some(init) = init_may {
-                let rhs = gen_expr(init);
+                let rhs = gen_expr(*init)?;
                 let lhs = Some(*NUM_REGS.lock().unwrap());
                 *NUM_REGS.lock().unwrap() += 1;
                 add(IROp::Bprel, lhs, Some(offset));
@@ -490,23 +761,23 @@ fn gen_stmt(node: Node) {
                 *NLABEL.lock().unwrap() += 1;
                 let y = Some(*NLABEL.lock().unwrap());
                 *NLABEL.lock().unwrap() += 1;
-                let r = gen_expr(cond.clone());
+                let r = gen_expr(*cond.clone())?;
                 add(IROp::Unless, r, x);
                 kill(r);
-                gen_stmt(*then.clone());
+                gen_stmt(*then.clone())?;
                 jmp(y);
                 label(x);
-                gen_stmt(*els);
+                gen_stmt(*els)?;
                 label(y);
-                return;
+                return Ok(());
             }
 
             let x = Some(*NLABEL.lock().unwrap());
             *NLABEL.lock().unwrap() += 1;
-            let r = gen_expr(cond);
+            let r = gen_expr(*cond)?;
             add(IROp::Unless, r, x);
             kill(r);
-            gen_stmt(*then);
+            gen_stmt(*then)?;
             label(x);
         }
         NodeType::For(init, cond, inc, body) => {
@@ -517,22 +788,42 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+
+            // `#pragma oxide unroll(n)` repeats the body n times in each
+            // turn of the loop, with the condition checked before each copy.
+            let unroll = node.pragmas.iter().find_map(|p| match p {
+                Pragma::Unroll(n) => Some(*n),
+                _ => None,
+            });
 
-            gen_stmt(*init);
+            gen_stmt(*init)?;
             label(x);
-            if !cond.is_null() {
-                let r2 = gen_expr(cond);
-                add(IROp::Unless, r2, y);
-                kill(r2);
-            }
-            gen_stmt(*body);
-            if !inc.is_null() {
-                gen_stmt(*inc);
+            for _ in 0..unroll.unwrap_or(1) {
+                let first_label = *NLABEL.lock().unwrap();
+                *CONTINUE_LABEL.lock().unwrap() = new_label();
+                let start = CODE.lock().unwrap().len();
+                if !cond.is_null() {
+                    let r2 = gen_expr((*cond).clone())?;
+                    add(IROp::Unless, r2, y);
+                    kill(r2);
+                }
+                gen_stmt((*body).clone())?;
+                label(Some(*CONTINUE_LABEL.lock().unwrap()));
+                if !inc.is_null() {
+                    gen_stmt((*inc).clone())?;
+                }
+                // A body defining labels of goto or of an enclosing switch
+                // is not repeated, since they can be defined once only.
+                if has_fixed_labels(&CODE.lock().unwrap()[start..], first_label) {
+                    break;
+                }
             }
             jmp(x);
             label(y);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
         }
         NodeType::DoWhile(body, cond) => {
             let x = Some(*NLABEL.lock().unwrap());
@@ -540,70 +831,154 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+            *CONTINUE_LABEL.lock().unwrap() = new_label();
             label(x);
-            gen_stmt(*body);
-            let r = gen_expr(cond);
+            gen_stmt(*body)?;
+            label(Some(*CONTINUE_LABEL.lock().unwrap()));
+            let r = gen_expr(*cond)?;
             add(IROp::If, r, x);
             kill(r);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
+        }
+        NodeType::Switch(cond, body, vals, has_default) => {
+            let orig = *BREAK_LABEL.lock().unwrap();
+            let break_label = new_label();
+            *BREAK_LABEL.lock().unwrap() = break_label;
+            let mut cases: Vec<(i64, usize)> = vals.into_iter().map(|v| (v, new_label())).collect();
+            let default = if has_default { new_label() } else { break_label };
+            cases.sort();
+
+            // Case values have been converted to the promoted type of the
+            // condition, which its value is held as already.
+            let r = gen_expr(*cond)?;
+            gen_switch(r, &cases, default);
+            kill(r);
+
+            SWITCHES.lock().unwrap().push(SwitchLabels { cases, default });
+            let ret = gen_stmt(*body);
+            SWITCHES.lock().unwrap().pop();
+            ret?;
+            label(Some(break_label));
+            *BREAK_LABEL.lock().unwrap() = orig;
+        }
+        NodeType::Case(val, stmt) => {
+            let val = match val.op {
+                NodeType::Num(val) => val,
+                // Sema requires an integer constant.
+                _ => unreachable!("case value {:?}", val.op),
+            };
+            let x = SWITCHES.lock().unwrap().last().and_then(|sw| {
+                sw.cases.iter().find(|(v, _)| *v == val).map(|(_, x)| *x)
+            });
+            label(x);
+            gen_stmt(*stmt)?;
+        }
+        NodeType::Default(stmt) => {
+            let x = SWITCHES.lock().unwrap().last().map(|sw| sw.default);
+            label(x);
+            gen_stmt(*stmt)?;
         }
         NodeType::Break => {
             let break_label = *BREAK_LABEL.lock().unwrap();
             if break_label == 0 {
-                panic!("stray 'break' statement");
+                let msg = "'break' statement not in loop or switch statement";
+                return Err(node.error(Code::Statement, msg));
             }
             jmp(Some(break_label));
         }
+        NodeType::Continue => {
+            let continue_label = *CONTINUE_LABEL.lock().unwrap();
+            if continue_label == 0 {
+                let msg = "'continue' statement not in loop statement";
+                return Err(node.error(Code::Statement, msg));
+            }
+            jmp(Some(continue_label));
+        }
+        NodeType::Goto(name) => jmp(Some(named_label(name))),
+        NodeType::Label(name, stmt) => {
+            label(Some(named_label(name)));
+            gen_stmt(*stmt)?;
+        }
         NodeType::Return(expr) => {
-            let r = gen_expr(expr);
+            let is_float = expr.ty.is_float();
+            let r = gen_expr(*expr)?;
 
             // Statement expression (GNU extension)
             if *RETURN_LABEL.lock().unwrap() != 0 {
                 add(IROp::Mov, Some(*RETURN_REG.lock().unwrap()), r);
                 kill(r);
                 jmp(Some(*RETURN_LABEL.lock().unwrap()));
-                return;
+                return Ok(());
             }
 
-            add(IROp::Return, r, None);
+            add(IROp::Return(is_float), r, None);
             kill(r);
         }
         NodeType::ExprStmt(expr) => {
-            let r = gen_expr(expr);
+            let r = gen_expr(*expr)?;
             kill(r);
         }
         NodeType::VecStmt(stmts) | NodeType::CompStmt(stmts) => {
             for n in stmts {
-                gen_stmt(n);
+                gen_stmt(n)?;
             }
         }
-        e => panic!("unknown node: {:?}", e),
+        // Expressions are wrapped in ExprStmt, and sema turns
+        // declarations other than variable definitions into Null.
+        e => unreachable!("statement {:?}", e),
     }
+    Ok(())
 }
 
-pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
+pub fn gen_ir(nodes: Vec<Node>) -> Result<Vec<Function>> {
     let mut v = vec![];
     for node in nodes {
         match node.op {
             NodeType::Func(name, args, body, stacksize) => {
                 *CODE.lock().unwrap() = vec![];
-                // *NUM_REGS.lock().unwrap() = 0;
-
-                for (i, arg) in args.iter().enumerate() {
+                NAMED_LABELS.lock().unwrap().clear();
+                // Registers are allocated one function at a time.
+                *NUM_REGS.lock().unwrap() = 0;
+                *ARG_AREA.lock().unwrap() = ArgArea {
+                    base: roundup(stacksize, 8),
+                    ..ArgArea::default()
+                };
+
+                // Integer and floating-point arguments are passed in
+                // separate sets of registers, and the rest on the stack.
+                let mut gp = 0;
+                let mut fp = 0;
+                let mut stack = 0;
+                for arg in &args {
                     if let NodeType::Vardef(_, _, Scope::Local(offset)) = arg.op {
-                        store_arg(&arg.ty, Some(offset), Some(i));
+                        let size = arg.ty.size as u8;
+                        if arg.ty.is_float() && fp < FP_ARGS {
+                            add(IROp::StoreFArg(size), Some(offset), Some(fp));
+                            fp += 1;
+                        } else if !arg.ty.is_float() && gp < GP_ARGS {
+                            store_arg(&arg.ty, Some(offset), Some(gp));
+                            gp += 1;
+                        } else {
+                            add(IROp::StoreStackArg(size), Some(offset), Some(stack));
+                            stack += 1;
+                        }
                     } else {
-                        unreachable!();
+                        unreachable!("parameters are local variables");
                     }
                 }
-                gen_stmt(*body);
+                gen_stmt(*body)?;
 
-                v.push(Function::new(name, CODE.lock().unwrap().clone(), stacksize));
+                let ir = CODE.lock().unwrap().clone();
+                let area = ARG_AREA.lock().unwrap();
+                let stacksize = area.base + area.max * 8;
+                v.push(Function::new(name, ir, stacksize, node.pragmas));
             }
-            NodeType::Vardef(_, _, _) => (),
-            _ => panic!("parse error."),
+            // Sema keeps only the function definitions.
+            e => unreachable!("top-level {:?}", e),
         }
     }
-    v
+    Ok(v)
 }
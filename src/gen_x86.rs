@@ -1,9 +1,10 @@
-use crate::gen_ir::{Function, IROp, IR};
+use crate::gen_ir::{Function, IROp, IR, FP_ARGS, GP_ARGS};
 use crate::util::roundup;
 use crate::{Scope, Var, REGS_N};
 
 const REGS: [&str; REGS_N] = ["r10", "r11", "rbx", "r12", "r13", "r14", "r15"];
 const REGS8: [&str; REGS_N] = ["r10b", "r11b", "bl", "r12b", "r13b", "r14b", "r15b"];
+const REGS16: [&str; REGS_N] = ["r10w", "r11w", "bx", "r12w", "r13w", "r14w", "r15w"];
 const REGS32: [&str; REGS_N] = ["r10d", "r11d", "ebx", "r12d", "r13d", "r14d", "r15d"];
 
 // AVX512 registers
@@ -20,9 +21,7 @@ use std::sync::Mutex;
 // > This pass generates x86-64 assembly from IR.
 
 const ARGREGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-#[allow(dead_code)]
 const ARGREGS8: [&str; 6] = ["dil", "sil", "dl", "cl", "r8b", "r9b"];
-#[allow(dead_code)]
 const ARGREGS32: [&str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
 
 lazy_static! {
@@ -69,6 +68,65 @@ macro_rules! emit{
     ($fmt:expr, $($arg:tt)*) => (print!(concat!("\t", $fmt, "\n"), $($arg)*));
 }
 
+// Floating-point values are kept in general-purpose registers as bit
+// patterns, and are moved to xmm registers to operate on them.
+fn fsuffix(size: u8) -> &'static str {
+    match size {
+        4 => "ss",
+        _ => "sd",
+    }
+}
+
+fn to_xmm(xmm: usize, r: usize, size: u8) {
+    match size {
+        4 => emit!("movd xmm{}, {}", xmm, REGS32[r]),
+        _ => emit!("movq xmm{}, {}", xmm, REGS[r]),
+    }
+}
+
+fn from_xmm(r: usize, xmm: usize, size: u8) {
+    match size {
+        4 => emit!("movd {}, xmm{}", REGS32[r], xmm),
+        _ => emit!("movq {}, xmm{}", REGS[r], xmm),
+    }
+}
+
+fn emit_fop(ir: IR, insn: &'static str, size: u8) {
+    let lhs = ir.lhs.unwrap();
+    let rhs = ir.rhs.unwrap();
+    to_xmm(0, lhs, size);
+    to_xmm(1, rhs, size);
+    emit!("{}{} xmm0, xmm1", insn, fsuffix(size));
+    from_xmm(lhs, 0, size);
+}
+
+// `ucomis` sets the flags as an unsigned comparison does, or sets all of
+// ZF, PF and CF if an operand is NaN. So `a < b` is tested as `b > a`, and
+// equality also checks PF.
+fn emit_fcmp(ir: IR, insn: &'static str, size: u8) {
+    let lhs = ir.lhs.unwrap();
+    let rhs = ir.rhs.unwrap();
+    to_xmm(0, lhs, size);
+    to_xmm(1, rhs, size);
+    match insn {
+        "seta" | "setae" => emit!("ucomi{} xmm1, xmm0", fsuffix(size)),
+        _ => emit!("ucomi{} xmm0, xmm1", fsuffix(size)),
+    }
+    emit!("{} {}", insn, REGS8[lhs]);
+    match insn {
+        "sete" => {
+            emit!("setnp cl");
+            emit!("and {}, cl", REGS8[lhs]);
+        }
+        "setne" => {
+            emit!("setp cl");
+            emit!("or {}, cl", REGS8[lhs]);
+        }
+        _ => (),
+    }
+    emit!("movzx {}, {}", REGS[lhs], REGS8[lhs]);
+}
+
 fn emit_cmp(ir: IR, insn: &'static str) {
     let lhs = ir.lhs.unwrap();
     let rhs = ir.rhs.unwrap();
@@ -77,6 +135,16 @@ fn emit_cmp(ir: IR, insn: &'static str) {
     emit!("movzx {}, {}", REGS[lhs], REGS8[lhs]);
 }
 
+// cmp takes an immediate of at most 32 bits, which is sign-extended.
+fn emit_cmp_imm(r: usize, imm: i64) {
+    if imm == imm as i32 as i64 {
+        emit!("cmp {}, {}", REGS[r], imm);
+    } else {
+        emit!("mov rax, {}", imm);
+        emit!("cmp {}, rax", REGS[r]);
+    }
+}
+
 #[allow(dead_code)]
 fn reg(r: usize, size: u8) -> &'static str {
     match size {
@@ -126,24 +194,61 @@ fn gen(f: Function) {
         let lhs = ir.lhs.unwrap_or(0);
         let rhs = ir.rhs.unwrap_or(0);
         match ir.op {
-            Imm => emit!("mov {}, {}", REGS[lhs], rhs as i32),
+            Imm => emit!("mov {}, {}", REGS[lhs], rhs as i64),
             Mov => emit!("mov {}, {}", REGS[lhs], REGS[rhs]),
-            Return => {
+            Return(is_float) => {
+                // A floating-point value is returned in xmm0.
                 emit!("mov rax, {}", REGS[lhs]);
+                if is_float {
+                    emit!("movq xmm0, rax");
+                }
                 emit!("jmp {}", ret);
             }
-            Call(name, nargs, args) => {
-                for i in 0..nargs {
-                    emit!("mov {}, {}", ARGREGS[i], REGS[args[i]]);
+            Call(name, args, returns_float) => {
+                let mut gp = 0;
+                let mut fp = 0;
+                let mut stack = vec![];
+                for arg in &args {
+                    if arg.is_float && fp < FP_ARGS {
+                        emit!("movq xmm{}, [rbp-{}]", fp, arg.offset);
+                        fp += 1;
+                    } else if !arg.is_float && gp < GP_ARGS {
+                        emit!("mov {}, [rbp-{}]", ARGREGS[gp], arg.offset);
+                        gp += 1;
+                    } else {
+                        stack.push(arg.offset);
+                    }
                 }
                 emit!("push r10");
                 emit!("push r11");
-                emit!("mov rax, 0");
-                emit!("call {}", name);
+
+                // The rest are pushed from the last one, and the stack
+                // stays aligned to 16 bytes at the call.
+                let pad = stack.len() % 2 * 8;
+                if pad != 0 {
+                    emit!("sub rsp, {}", pad);
+                }
+                for offset in stack.iter().rev() {
+                    emit!("push qword [rbp-{}]", offset);
+                }
+                // The number of xmm registers used, for variadic functions
+                emit!("mov rax, {}", fp);
+                if ir.rhs.is_some() {
+                    emit!("call {}", REGS[rhs]);
+                } else {
+                    emit!("call {}", name);
+                }
+                if !stack.is_empty() {
+                    emit!("add rsp, {}", stack.len() * 8 + pad);
+                }
                 emit!("pop r11");
                 emit!("pop r10");
 
-                emit!("mov {}, rax", REGS[lhs]);
+                if returns_float {
+                    emit!("movq {}, xmm0", REGS[lhs]);
+                } else {
+                    emit!("mov {}, rax", REGS[lhs]);
+                }
             }
             Label => println!(".L{}:", lhs),
             LabelAddr(name) => emit!("lea {}, {}", REGS[lhs], name),
@@ -152,6 +257,38 @@ fn gen(f: Function) {
             NE => emit_cmp(ir, "setne"),
             LT => emit_cmp(ir, "setl"),
             LE => emit_cmp(ir, "setle"),
+            ULT => emit_cmp(ir, "setb"),
+            ULE => emit_cmp(ir, "setbe"),
+            FEQ(size) => emit_fcmp(ir, "sete", size),
+            FNE(size) => emit_fcmp(ir, "setne", size),
+            FLT(size) => emit_fcmp(ir, "seta", size),
+            FLE(size) => emit_fcmp(ir, "setae", size),
+            FAdd(size) => emit_fop(ir, "add", size),
+            FSub(size) => emit_fop(ir, "sub", size),
+            FMul(size) => emit_fop(ir, "mul", size),
+            FDiv(size) => emit_fop(ir, "div", size),
+            FNeg(size) => emit!("btc {}, {}", REGS[lhs], size * 8 - 1),
+            IntToFloat(size) => {
+                emit!("cvtsi2{} xmm0, {}", fsuffix(size), REGS[lhs]);
+                from_xmm(lhs, 0, size);
+            }
+            FloatToInt(size) => {
+                to_xmm(0, lhs, size);
+                emit!("cvtt{}2si {}, xmm0", fsuffix(size), REGS[lhs]);
+            }
+            IntToInt(size, is_unsigned) => match size {
+                1 => emit!("movzx {}, {}", REGS[lhs], REGS8[lhs]),
+                2 => emit!("movzx {}, {}", REGS[lhs], REGS16[lhs]),
+                4 if is_unsigned => emit!("mov {}, {}", REGS32[lhs], REGS32[lhs]),
+                4 => emit!("movsxd {}, {}", REGS[lhs], REGS32[lhs]),
+                _ => panic!("Unknown data size: {}", size),
+            },
+            FloatToFloat(size) => {
+                let from = if size == 4 { 8 } else { 4 };
+                to_xmm(0, lhs, from);
+                emit!("cvt{}2{} xmm0, xmm0", fsuffix(from), fsuffix(size));
+                from_xmm(lhs, 0, size);
+            }
             AND => emit!("and {}, {}", REGS[lhs], REGS[rhs]),
             OR => emit!("or {}, {}", REGS[lhs], REGS[rhs]),
             XOR => emit!("xor {}, {}", REGS[lhs], REGS[rhs]),
@@ -163,6 +300,10 @@ fn gen(f: Function) {
                 emit!("mov cl, {}", REGS8[rhs]);
                 emit!("shr {}, cl", REGS[lhs]);
             }
+            SAR => {
+                emit!("mov cl, {}", REGS8[rhs]);
+                emit!("sar {}, cl", REGS[lhs]);
+            }
             Mod => {
                 /* Same meaning(?).
                  * emit!("mov rdx, 0");
@@ -173,6 +314,12 @@ fn gen(f: Function) {
                 emit!("idiv {}", REGS[rhs]);
                 emit!("mov {}, rdx", REGS[lhs]);
             }
+            UMod => {
+                emit!("mov rax, {}", REGS[lhs]);
+                emit!("xor edx, edx");
+                emit!("div {}", REGS[rhs]);
+                emit!("mov {}, rdx", REGS[lhs]);
+            }
             Jmp => emit!("jmp .L{}", lhs),
             If => {
                 emit!("cmp {}, 0", REGS[lhs]);
@@ -182,9 +329,39 @@ fn gen(f: Function) {
                 emit!("cmp {}, 0", REGS[lhs]);
                 emit!("je .L{}", rhs);
             }
+            IfEq(imm) => {
+                emit_cmp_imm(lhs, imm);
+                emit!("je .L{}", rhs);
+            }
+            IfLt(imm) => {
+                emit_cmp_imm(lhs, imm);
+                emit!("jl .L{}", rhs);
+            }
+            JmpTable(min, labels) => {
+                // The table holds 32-bit offsets of the labels from
+                // itself, and sits in the code after the jump.
+                let table = format!(".Ltable{}", *LABEL.lock().unwrap());
+                *LABEL.lock().unwrap() += 1;
+                emit!("mov rax, {}", REGS[lhs]);
+                if min != 0 {
+                    emit!("mov rcx, {}", min);
+                    emit!("sub rax, rcx");
+                }
+                emit!("cmp rax, {}", labels.len() - 1);
+                emit!("ja .L{}", rhs);
+                emit!("lea rcx, [rel {}]", table);
+                emit!("movsxd rax, dword [rcx+rax*4]");
+                emit!("add rax, rcx");
+                emit!("jmp rax");
+                println!("{}:", table);
+                for x in labels {
+                    emit!("dd .L{} - {}", x, table);
+                }
+            }
             Load(size) => {
                 match size {
                     1 => emit!("movzx {}, byte [{}]", REGS[lhs], REGS[rhs]),
+                    2 => emit!("movzx {}, word [{}]", REGS[lhs], REGS[rhs]),
                     4 => emit!("movsxd {}, dword [{}]", REGS[lhs], REGS[rhs]),
                     8 => emit!("mov {}, [{}]", REGS[lhs], REGS[rhs]),
                     _ => panic!("Unknown data size: {}", size),
@@ -193,6 +370,7 @@ fn gen(f: Function) {
             Store(size) => {
                 match size {
                     1 => emit!("mov byte [{}], {}", REGS[lhs], REGS8[rhs]),
+                    2 => emit!("mov word [{}], {}", REGS[lhs], REGS16[rhs]),
                     4 => emit!("mov dword [{}], {}", REGS[lhs], REGS32[rhs]),
                     8 => emit!("mov [{}], {}", REGS[lhs], REGS[rhs]),
                     _ => panic!("Unknown data size: {}", size),
@@ -200,9 +378,21 @@ fn gen(f: Function) {
             }
             StoreArg(size) => {
                 match size {
-                    1 => emit!("mov byte [rbp+{}], {}", lhs, REGS8[rhs]),
-                    4 => emit!("mov dword [rbp+{}], {}", lhs, REGS32[rhs]),
-                    8 => emit!("mov qword [rbp+{}], {}", lhs, REGS[rhs]),
+                    1 => emit!("mov byte [rbp-{}], {}", lhs, ARGREGS8[rhs]),
+                    4 => emit!("mov dword [rbp-{}], {}", lhs, ARGREGS32[rhs]),
+                    8 => emit!("mov qword [rbp-{}], {}", lhs, ARGREGS[rhs]),
+                    _ => panic!("Unknown data size: {}", size),
+                }
+            }
+            StoreFArg(size) => emit!("mov{} [rbp-{}], xmm{}", fsuffix(size), lhs, rhs),
+            StoreStackArg(size) => {
+                // Above the saved rbp and the return address
+                emit!("mov rax, [rbp+{}]", 16 + rhs * 8);
+                match size {
+                    1 => emit!("mov byte [rbp-{}], al", lhs),
+                    2 => emit!("mov word [rbp-{}], ax", lhs),
+                    4 => emit!("mov dword [rbp-{}], eax", lhs),
+                    8 => emit!("mov qword [rbp-{}], rax", lhs),
                     _ => panic!("Unknown data size: {}", size),
                 }
             }
@@ -210,7 +400,7 @@ fn gen(f: Function) {
             AddImm => emit!("add {}, {}", REGS[lhs], rhs),
             Sub => emit!("sub {}, {}", REGS[lhs], REGS[rhs]),
             SubImm => emit!("sub {}, {}", REGS[lhs], rhs),
-            Bprel => emit!("lea {}, [rbp+{}]", REGS[lhs], rhs),
+            Bprel => emit!("lea {}, [rbp-{}]", REGS[lhs], rhs),
             Mul => {
                 emit!("mov rax, {}", REGS[rhs]);
                 emit!("mul {}", REGS[lhs]);
@@ -223,6 +413,12 @@ fn gen(f: Function) {
                 emit!("idiv {}", REGS[rhs]);
                 emit!("mov {}, rax", REGS[lhs]);
             }
+            UDiv => {
+                emit!("mov rax, {}", REGS[lhs]);
+                emit!("xor edx, edx");
+                emit!("div {}", REGS[rhs]);
+                emit!("mov {}, rax", REGS[lhs]);
+            }
             Nop | Kill => (),
             AVX512Add => emit!("vaddpd {}, {}, {}", ZMM_REGS[lhs], ZMM_REGS[lhs], ZMM_REGS[rhs]),
             AVX512Sub => emit!("vsubpd {}, {}, {}", ZMM_REGS[lhs], ZMM_REGS[lhs], ZMM_REGS[rhs]),
@@ -313,7 +509,8 @@ pub fn gen_x86(globals: Vec<Var>, fns: Vec<Function>) {
                 println!("    dq 0");
             } else {
                 // Handle string literals or other initialized data
-                println!("    db {}", data);
+                let bytes: Vec<String> = data.iter().map(|b| b.to_string()).collect();
+                println!("    db {}", bytes.join(", "));
             }
         }
         println!();
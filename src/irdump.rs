@@ -18,7 +18,7 @@ impl IRInfo {
         match *op {
             Add => IRInfo::new("ADD", IRType::RegReg),
             AddImm => IRInfo::new("ADD", IRType::RegImm),
-            Call(_, _, _) => IRInfo::new("CALL", IRType::Call),
+            Call(..) => IRInfo::new("CALL", IRType::Call),
             Div => IRInfo::new("DIV", IRType::RegReg),
             Imm => IRInfo::new("MOV", IRType::RegImm),
             Jmp => IRInfo::new("JMP", IRType::Jmp),
@@ -29,26 +29,49 @@ impl IRInfo {
             NE => IRInfo::new("NE", IRType::RegReg),
             LE => IRInfo::new("LE", IRType::RegReg),
             LT => IRInfo::new("LT", IRType::RegReg),
+            ULE => IRInfo::new("ULE", IRType::RegReg),
+            ULT => IRInfo::new("ULT", IRType::RegReg),
             AND => IRInfo::new("AND", IRType::RegReg),
             OR => IRInfo::new("OR", IRType::RegReg),
             XOR => IRInfo::new("XOR", IRType::RegReg),
             SHL => IRInfo::new("SHL", IRType::RegReg),
             SHR => IRInfo::new("SHR", IRType::RegReg),
+            SAR => IRInfo::new("SAR", IRType::RegReg),
             Mod => IRInfo::new("MOD", IRType::RegReg),
+            UDiv => IRInfo::new("UDIV", IRType::RegReg),
+            UMod => IRInfo::new("UMOD", IRType::RegReg),
+            FAdd(_) => IRInfo::new("FADD", IRType::RegReg),
+            FSub(_) => IRInfo::new("FSUB", IRType::RegReg),
+            FMul(_) => IRInfo::new("FMUL", IRType::RegReg),
+            FDiv(_) => IRInfo::new("FDIV", IRType::RegReg),
+            FEQ(_) => IRInfo::new("FEQ", IRType::RegReg),
+            FNE(_) => IRInfo::new("FNE", IRType::RegReg),
+            FLE(_) => IRInfo::new("FLE", IRType::RegReg),
+            FLT(_) => IRInfo::new("FLT", IRType::RegReg),
+            FNeg(_) => IRInfo::new("FNEG", IRType::Reg),
+            IntToFloat(_) => IRInfo::new("I2F", IRType::Reg),
+            FloatToInt(_) => IRInfo::new("F2I", IRType::Reg),
+            FloatToFloat(_) => IRInfo::new("F2F", IRType::Reg),
+            IntToInt(..) => IRInfo::new("I2I", IRType::Reg),
             Neg => IRInfo::new("NEG", IRType::Reg),
             Load(_) => IRInfo::new("LOAD", IRType::Mem),
             Mov => IRInfo::new("MOV", IRType::RegReg),
             Mul => IRInfo::new("MUL", IRType::RegReg),
             MulImm => IRInfo::new("MUL", IRType::RegImm),
             Nop => IRInfo::new("NOP", IRType::Noarg),
-            Return => IRInfo::new("RET", IRType::Reg),
+            Return(_) => IRInfo::new("RET", IRType::Reg),
             Store(_) => IRInfo::new("STORE", IRType::Mem),
             StoreArg(_) => IRInfo::new("STORE_ARG", IRType::StoreArg),
+            StoreFArg(_) => IRInfo::new("STORE_FARG", IRType::StoreArg),
+            StoreStackArg(_) => IRInfo::new("STORE_STACK_ARG", IRType::StoreArg),
             Sub => IRInfo::new("SUB", IRType::RegReg),
             SubImm => IRInfo::new("SUB", IRType::RegImm),
             Bprel => IRInfo::new("BPREL", IRType::RegImm),
             If => IRInfo::new("IF", IRType::RegLabel),
             Unless => IRInfo::new("UNLESS", IRType::RegLabel),
+            IfEq(_) => IRInfo::new("IF_EQ", IRType::RegLabel),
+            IfLt(_) => IRInfo::new("IF_LT", IRType::RegLabel),
+            JmpTable(..) => IRInfo::new("JMP_TABLE", IRType::RegLabel),
             AVX512Add => IRInfo::new("AVX512_ADD", IRType::RegReg),
             AVX512Sub => IRInfo::new("AVX512_SUB", IRType::RegReg),
             AVX512Mul => IRInfo::new("AVX512_MUL", IRType::RegReg),
@@ -120,21 +143,37 @@ impl fmt::Display for IR {
                 IROp::Load(ref size) | IROp::Store(ref size) => {
                     write!(f, "  {}{} r{}, {}", info.name, size, lhs, self.rhs.unwrap())
                 }
-                IROp::StoreArg(ref size) => {
+                IROp::StoreArg(ref size)
+                | IROp::StoreFArg(ref size)
+                | IROp::StoreStackArg(ref size) => {
                     write!(f, "  {}{} {}, {}", info.name, size, lhs, self.rhs.unwrap())
                 }
-                _ => unreachable!(),
+                // The AVX512 loads and stores have no size.
+                _ => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
+            },
+            RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i64),
+            RegLabel => match self.op {
+                IROp::IfEq(imm) | IROp::IfLt(imm) => {
+                    write!(f, "  {} r{}, {}, .L{}", info.name, lhs, imm, self.rhs.unwrap())
+                }
+                IROp::JmpTable(min, ref labels) => {
+                    let labels: Vec<String> = labels.iter().map(|x| format!(".L{}", x)).collect();
+                    let (name, rhs) = (info.name, self.rhs.unwrap());
+                    write!(f, "  {} r{}-{}, [{}], .L{}", name, lhs, min, labels.join(", "), rhs)
+                }
+                _ => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
             },
-            RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i32),
-            RegLabel => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
             Call => match self.op {
-                IROp::Call(ref name, nargs, args) => {
-                    let mut sb: String = format!("  r{} = {}(", lhs, name);
-                    for (i, arg) in args.iter().enumerate().take(nargs) {
+                IROp::Call(ref name, ref args, _) => {
+                    let mut sb: String = match self.rhs {
+                        Some(rhs) => format!("  r{} = *r{}(", lhs, rhs),
+                        None => format!("  r{} = {}(", lhs, name),
+                    };
+                    for (i, arg) in args.iter().enumerate() {
                         if i != 0 {
                             sb.push_str(", ");
                         }
-                        sb.push_str(&format!("r{}", *arg));
+                        sb.push_str(&format!("[{}]", arg.offset));
                     }
                     sb.push(')');
                     write!(f, "{}", sb)
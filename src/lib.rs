@@ -1,4 +1,6 @@
 #![feature(portable_simd)]
+mod cache;
+pub mod diagnostic;
 pub mod gen_ir;
 pub mod gen_x86;
 pub mod irdump;
@@ -6,6 +8,7 @@ pub mod parse;
 pub mod preprocess;
 pub mod regalloc;
 pub mod sema;
+pub mod source;
 pub mod token;
 pub mod vectorize;
 mod util;
@@ -30,18 +33,25 @@ macro_rules! matches(
 // Token type
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    Num(i32),            // Number literal
-    Str(String, usize),  // String literal. (str, len)
+    Num(i64, IntType),   // Number literal
+    FNum(f64, FloatType), // Floating-point literal
+    Str(Vec<u32>, Encoding), // String literal. (code units, encoding)
+    HeaderName(String),  // <...> in #include
     CharLiteral(String), // Char literal.
     Ident(String),       // Identifier
     Param(usize),        // Function-like macro parameter
     Arrow,               // ->
+    Ellipsis,            // ...
     Extern,              // "extern"
     Typedef,             // "typedef"
     Int,                 // "int"
     Char,                // "char"
+    Float,               // "float"
+    Double,              // "double"
     Void,                // "void"
     Struct,              // "struct"
+    Union,               // "union"
+    Enum,                // "enum"
     Plus,                // +
     Minus,               // -
     Mul,                 // *
@@ -53,14 +63,21 @@ pub enum TokenType {
     Question,            // ?
     VerticalBar,         // |
     Hat,                 // ^
+    Tilde,               // ~
     Colon,               // :
     HashMark,            // #
+    HashHash,            // ##
     If,                  // "if"
     Else,                // "else"
     For,                 // "for"
     Do,                  // "do"
     While,               // "while"
     Break,               // "break"
+    Switch,              // "switch"
+    Case,                // "case"
+    Default,             // "default"
+    Continue,            // "continue"
+    Goto,                // "goto"
     EQ,                  // ==
     NE,                  // !=
     LE,                  // <=
@@ -96,6 +113,37 @@ pub enum TokenType {
     Sizeof,              // "sizeof"
     Alignof,             // "_Alignof"
     NewLine,             // preprocessor-only token
+    Pragma,              // #pragma left for later passes
+    Eof,                 // End of input, added by the parser
+    Invalid(&'static str), // Lexical error (message)
+}
+
+// Type of an integer constant. `long long` is the same as `long`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IntType {
+    Int,
+    UInt,
+    Long,
+    ULong,
+    UChar,  // u8'x'
+    UShort, // u'x'
+}
+
+// Encoding prefix of a string or character literal
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Plain, // "..."
+    UTF8,  // u8"..."
+    UTF16, // u"..."
+    UTF32, // U"..."
+    Wide,  // L"...", which is UTF-32 as wchar_t is int
+}
+
+// Type of a floating constant. `long double` is the same as `double`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FloatType {
+    Float,
+    Double,
 }
 
 // Character Kind
@@ -134,6 +182,7 @@ impl TokenType {
             '?' => Some(Question),
             '|' => Some(VerticalBar),
             '^' => Some(Hat),
+            '~' => Some(Tilde),
             '%' => Some(Mod),
             ':' => Some(Colon),
             '#' => Some(HashMark),
@@ -147,12 +196,17 @@ impl TokenType {
 pub enum Ctype {
     #[default]
     Int,
+    Long,
+    Short,
     Char,
+    Float,
+    Double,
     Void,
     Ptr(Box<Type>),           // ptr of
     Ary(Box<Type>, usize),    // ary of, len
     Struct(Vec<parse::Node>), // members
-    Func(Box<Type>),
+    Union(Vec<parse::Node>),  // members
+    Func(Box<Type>, Vec<parse::Node>), // returning, parameters
 }
 
 
@@ -161,6 +215,7 @@ pub struct Type {
     pub ty: Ctype,
     pub size: usize,  // sizeof
     pub align: usize, // alignof
+    pub is_unsigned: bool,
 }
 
 impl Default for Type {
@@ -169,6 +224,7 @@ impl Default for Type {
             ty: Ctype::default(),
             size: 4,
             align: 4,
+            is_unsigned: false,
         }
     }
 }
@@ -176,7 +232,8 @@ impl Default for Type {
 #[derive(Debug, Clone)]
 pub enum Scope {
     Local(usize),                // offset
-    Global(String, usize, bool), // data, len, is_extern
+    Global(Vec<u8>, usize, bool), // data, len, is_extern
+    Enum(i64),                   // enumeration constant, value
 }
 
 #[derive(Debug, Clone)]
@@ -191,7 +248,7 @@ impl Var {
         Var { ty, name, scope }
     }
 
-    fn new_global(ty: Box<Type>, name: String, data: String, len: usize, is_extern: bool) -> Self {
+    fn new_global(ty: Box<Type>, name: String, data: Vec<u8>, len: usize, is_extern: bool) -> Self {
         Var::new(ty, name.clone(), Scope::Global(data, len, is_extern))
     }
 }
@@ -1,23 +1,111 @@
 extern crate oxide;
 
+use oxide::diagnostic::{self, Format};
 use oxide::gen_ir::gen_ir;
 use oxide::gen_x86::gen_x86;
 use oxide::irdump::dump_ir;
 use oxide::parse::parse;
-use oxide::preprocess::Preprocessor;
+use oxide::preprocess::{print_tokens, Preprocessor};
 use oxide::regalloc::alloc_regs;
 use oxide::sema::sema;
-use oxide::token::tokenize;
+use oxide::token::{preprocess_file, tokenize, tokenize_include};
 use oxide::vectorize::vectorize;
 
 use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
 use std::process;
 
 fn usage() -> ! {
-    eprintln!("Usage: oxide [-dump-ir1] [-dump-ir2] [-dump-ir3] [-no-vec] <file>");
+    eprintln!(
+        "Usage: oxide [-dump-ir1] [-dump-ir2] [-dump-ir3] [-no-vec] [-E] [-dM] [-I<path>] [-iquote <path>] [-isystem <path>]"
+    );
+    eprintln!("             [-D<name>[=<value>]] [-U<name>] [-include <file>] [-cache-dir <dir>]");
+    eprintln!("             [-M] [-MM] [-MD] [-MMD] [-MF <file>] [-MT <target>]");
+    eprintln!("             [-ferror-limit=<n>] [-fdiagnostics-format=text|json|sarif] <file>");
     process::exit(1)
 }
 
+// Reads the value of an option given as either `-I<value>` or
+// `-I <value>`.
+fn option_arg(args: &[String], i: &mut usize, name: &str) -> Option<String> {
+    let val = args[*i].strip_prefix(name)?;
+    if !val.is_empty() {
+        return Some(val.to_string());
+    }
+    *i += 1;
+    match args.get(*i) {
+        Some(val) => Some(val.clone()),
+        None => usage(),
+    }
+}
+
+// Returns a Makefile rule which makes the object file depend on all files
+// read during preprocessing, the main file first.
+fn dependency_rule(path: &str, ctx: &Preprocessor, targets: &[String], skip_system: bool) -> String {
+    let escape = |s: &str| s.replace('$', "$$").replace(' ', "\\ ").replace('#', "\\#");
+
+    let rule = if targets.is_empty() {
+        format!("{}.o:", escape(&stem(path)))
+    } else {
+        format!("{}:", targets.join(" "))
+    };
+    let mut files = vec![path.to_string()];
+    files.extend(ctx.files(skip_system).into_iter().filter(|f| f != path));
+    let files = files
+        .iter()
+        .filter(|f| *f != "-")
+        .map(|f| escape(f))
+        .collect::<Vec<_>>();
+    format!("{} {}\n", rule, files.join(" \\\n "))
+}
+
+// Returns the file name without directories and extension.
+fn stem(path: &str) -> String {
+    let stem = Path::new(path).file_stem().map(|s| s.to_string_lossy());
+    stem.unwrap_or_default().into_owned()
+}
+
+fn write_file(path: &str, s: &str) {
+    if let Err(e) = fs::write(path, s) {
+        eprintln!("cannot write {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+// Prints the diagnostics reported so far to stderr.
+fn print_diagnostics() {
+    let diags = diagnostic::take_reported();
+    // Text output is left empty for a clean compile, but a JSON array or
+    // SARIF log is always written so that tools can parse it.
+    if diags.is_empty() && diagnostic::format() == Format::Text {
+        return;
+    }
+    eprint!("{}", diagnostic::render(&diags, io::stderr().is_terminal()));
+}
+
+// Returns the result of a stage, or prints its error with the
+// diagnostics before it and exits.
+fn check<T>(result: diagnostic::Result<T>) -> T {
+    match result {
+        Ok(val) => val,
+        Err(e) => {
+            diagnostic::report(e);
+            print_diagnostics();
+            process::exit(1)
+        }
+    }
+}
+
+// Exits if a stage has reported errors it recovered from.
+fn check_reported() {
+    if diagnostic::error_count() > 0 {
+        print_diagnostics();
+        process::exit(1);
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() == 1 {
@@ -28,7 +116,17 @@ fn main() {
     let mut dump_ir2 = false;
     let mut dump_ir3 = false;
     let mut enable_vectorization = true;
+    let mut preprocess_only = false;
+    let mut dump_macros = false;
+    let mut print_deps = false; // -M, -MM
+    let mut write_deps = false; // -MD, -MMD
+    let mut skip_system_deps = false; // -MM, -MMD
+    let mut deps_file = None;
+    let mut deps_targets = vec![];
     let mut path = String::new();
+    let mut ctx = Preprocessor::new();
+    let mut includes = vec![];
+    let mut n_isystem = 0; // -isystem dirs go before the default ones, in order
     
     // Parse command line arguments
     let mut i = 1;
@@ -41,6 +139,51 @@ fn main() {
             dump_ir3 = true;
         } else if args[i] == "-no-vec" {
             enable_vectorization = false;
+        } else if args[i] == "-E" {
+            preprocess_only = true;
+        } else if args[i] == "-dM" {
+            dump_macros = true;
+        } else if args[i] == "-M" || args[i] == "-MM" {
+            print_deps = true;
+            skip_system_deps = args[i] == "-MM";
+        } else if args[i] == "-MD" || args[i] == "-MMD" {
+            write_deps = true;
+            skip_system_deps = args[i] == "-MMD";
+        } else if let Some(file) = option_arg(&args, &mut i, "-MF") {
+            deps_file = Some(file);
+        } else if let Some(target) = option_arg(&args, &mut i, "-MT") {
+            deps_targets.push(target);
+        } else if let Some(dir) = option_arg(&args, &mut i, "-I") {
+            ctx.include_paths.push(dir);
+        } else if let Some(dir) = option_arg(&args, &mut i, "-iquote") {
+            ctx.quote_paths.push(dir);
+        } else if let Some(dir) = option_arg(&args, &mut i, "-isystem") {
+            ctx.system_paths.insert(n_isystem, dir);
+            n_isystem += 1;
+        } else if let Some(def) = option_arg(&args, &mut i, "-D") {
+            check(ctx.define_cmdline(&def));
+        } else if let Some(name) = option_arg(&args, &mut i, "-U") {
+            check(ctx.undef_cmdline(&name));
+        } else if let Some(n) = args[i].strip_prefix("-ferror-limit=") {
+            match n.parse() {
+                Ok(n) => diagnostic::set_error_limit(n),
+                Err(_) => usage(),
+            }
+        } else if let Some(format) = args[i].strip_prefix("-fdiagnostics-format=") {
+            match format {
+                "text" => diagnostic::set_format(Format::Text),
+                "json" => diagnostic::set_format(Format::Json),
+                "sarif" => diagnostic::set_format(Format::Sarif),
+                _ => usage(),
+            }
+        } else if let Some(dir) = option_arg(&args, &mut i, "-cache-dir") {
+            ctx.cache_dir = Some(dir);
+        } else if args[i] == "-include" {
+            i += 1;
+            match args.get(i) {
+                Some(file) => includes.push(file.clone()),
+                None => usage(),
+            }
         } else if path.is_empty() {
             path = args[i].clone();
         } else {
@@ -53,11 +196,47 @@ fn main() {
         usage();
     }
 
+    // Files given by -include are read as if they were included at the
+    // beginning of the main file.
+    if preprocess_only || dump_macros || print_deps {
+        let mut tokens = vec![];
+        for file in &includes {
+            tokens.append(&mut check(ctx.include_cmdline(file)));
+        }
+        tokens.append(&mut check(preprocess_file(path.clone(), None, &mut ctx)));
+        print_diagnostics();
+        if print_deps {
+            let rule = dependency_rule(&path, &ctx, &deps_targets, skip_system_deps);
+            match deps_file {
+                Some(file) => write_file(&file, &rule),
+                None => print!("{}", rule),
+            }
+        } else if dump_macros {
+            ctx.dump_macros();
+        } else {
+            print_tokens(&tokens);
+        }
+        return;
+    }
+
     // Tokenize and parse
-    let tokens = tokenize(path, &mut Preprocessor::new());
-    let nodes = parse(&tokens);
-    let (nodes, globals) = sema(nodes);
-    let mut fns = gen_ir(nodes);
+    let mut tokens = vec![];
+    for file in &includes {
+        tokens.append(&mut check(tokenize_include(file, &mut ctx)));
+    }
+    tokens.append(&mut check(tokenize(path.clone(), &mut ctx)));
+    if write_deps {
+        let rule = dependency_rule(&path, &ctx, &deps_targets, skip_system_deps);
+        let file = deps_file.unwrap_or_else(|| format!("{}.d", stem(&path)));
+        write_file(&file, &rule);
+    }
+    // Sema checks what the parser has recovered, so that errors of both
+    // are reported in one run.
+    let nodes = check(parse(&tokens));
+    let (nodes, globals) = check(sema(nodes));
+    check_reported();
+    let mut fns = check(gen_ir(nodes));
+    print_diagnostics();
 
     if dump_ir1 {
         dump_ir(&fns);
@@ -69,13 +248,10 @@ fn main() {
         dump_ir(&fns);
     }
     
-    // Apply vectorization if enabled
-    if enable_vectorization {
-        vectorize(&mut fns);
-        
-        if dump_ir3 {
-            dump_ir(&fns);
-        }
+    // Apply vectorization where it is enabled
+    vectorize(&mut fns, enable_vectorization);
+    if dump_ir3 {
+        dump_ir(&fns);
     }
 
     gen_x86(globals, fns);
@@ -1,6 +1,8 @@
-use crate::token::Token;
+use crate::diagnostic::{self, Code, Diagnostic, DiagnosticPragma, Result, Severity};
+use crate::source::{ExpansionId, Span};
+use crate::token::{tokenize_str, Token};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::{Ctype, Encoding, FloatType, IntType, Scope, TokenType, Type};
 
 use std::collections::HashMap;
 
@@ -23,16 +25,167 @@ int main() {     ; +-+                        int   []         2
 }                ; +-+                  return        []      +->primary
                                                                  0
 */
-pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
+pub fn parse(tokens: &[Token]) -> Result<Vec<Node>> {
+    // Pragmas are taken out of the token stream and remembered by the
+    // position of the token following them, so that they can be attached
+    // to the statement or function starting there. Diagnostic pragmas
+    // apply to the rest of the file instead.
+    let mut pragmas: HashMap<usize, Vec<(Pragma, Token)>> = HashMap::new();
+    let mut v = vec![];
+    for t in tokens {
+        if t.ty != TokenType::Pragma {
+            v.push(t.clone());
+            continue;
+        }
+        match read_pragma(t) {
+            Some(Pragma::Diagnostic(pragma)) => diagnostic::add_pragma(t.span, pragma),
+            Some(pragma) => pragmas.entry(v.len()).or_default().push((pragma, t.clone())),
+            None => (),
+        }
+    }
+
+    // The end of input is a token so that errors there have a place.
+    let end = v.last().map_or(Span::default(), |t| Span { lo: t.span.hi, ..t.span });
+    v.push(Token::new(TokenType::Eof, end));
+    let tokens = &v;
     let mut parser = Parser::new(tokens);
+    parser.pragmas = pragmas;
 
+    // Syntax errors are reported as they are found, and parsing goes on
+    // from the next statement or declaration, so that a run reports all
+    // of them. The nodes returned are good enough for sema to check.
     let mut v = vec![];
-    while tokens.len() != parser.pos {
-        if let Some(node) = parser.toplevel() {
-            v.push(node);
+    while tokens[parser.pos].ty != TokenType::Eof {
+        let node = parser.toplevel()?;
+        // Enumeration constants declared on the way come first.
+        v.append(&mut parser.enums);
+        v.extend(node);
+    }
+
+    // Pragmas before a `}`, a declaration in a block or the end of input
+    let mut left: Vec<_> = parser.pragmas.into_iter().collect();
+    left.sort_by_key(|(pos, _)| *pos);
+    for (_, pragmas) in left {
+        apply_pragmas(pragmas, None);
+    }
+    Ok(v)
+}
+
+// Functions implemented by the compiler itself.
+pub const BUILTINS: &[&str] = &["__builtin_expect"];
+
+// Pragmas which annotate the following statement or function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pragma {
+    Vectorize(bool),                // #pragma oxide vectorize(on|off), on a function
+    Unroll(usize),                  // #pragma oxide unroll(N), on a for or while loop
+    Diagnostic(DiagnosticPragma),   // #pragma GCC diagnostic push|pop|ignored "-Wname"
+}
+
+type PragmaHandler = fn(&[Token]) -> std::result::Result<Pragma, Option<(Code, String)>>;
+
+// Known pragmas, keyed by their leading identifiers. A handler reads the
+// rest of the pragma and returns Err(None) if it is malformed, or a more
+// specific warning.
+const PRAGMAS: &[(&[&str], PragmaHandler)] = &[
+    (&["oxide", "vectorize"], |args| {
+        match paren_arg(args).ok_or(None)?.as_str() {
+            "on" => Ok(Pragma::Vectorize(true)),
+            "off" => Ok(Pragma::Vectorize(false)),
+            _ => Err(None),
+        }
+    }),
+    (&["oxide", "unroll"], |args| {
+        match paren_arg(args).ok_or(None)?.parse() {
+            Ok(n) if n > 0 => Ok(Pragma::Unroll(n)),
+            _ => Err(None),
+        }
+    }),
+    (&["GCC", "diagnostic", "push"], |args| {
+        args.is_empty().then_some(Pragma::Diagnostic(DiagnosticPragma::Push)).ok_or(None)
+    }),
+    (&["GCC", "diagnostic", "pop"], |args| {
+        args.is_empty().then_some(Pragma::Diagnostic(DiagnosticPragma::Pop)).ok_or(None)
+    }),
+    (&["GCC", "diagnostic", "ignored"], |args| match args {
+        [t] if matches!(t.ty, TokenType::Str(_, _)) => {
+            let flag = t.str_value().ok_or(None)?;
+            match Code::from_flag(&flag) {
+                Some(code) => Ok(Pragma::Diagnostic(DiagnosticPragma::Ignored(code))),
+                None => {
+                    let msg = format!("unknown warning option '{}' ignored", flag);
+                    Err(Some((Code::UnknownWarning, msg)))
+                }
+            }
+        }
+        _ => Err(None),
+    }),
+];
+
+// Returns the text between the parentheses of `(arg)`.
+fn paren_arg(args: &[Token]) -> Option<String> {
+    match args {
+        [l, arg, r] if l.ty == TokenType::LeftParen && r.ty == TokenType::RightParen => {
+            Some(arg.tokstr())
+        }
+        _ => None,
+    }
+}
+
+// Interprets a pragma token. Unknown or malformed pragmas are reported
+// and dropped.
+fn read_pragma(t: &Token) -> Option<Pragma> {
+    let tokens = tokenize_str(t.tokstr(), "<scratch space>");
+    let tokens: Vec<Token> = tokens
+        .into_iter()
+        .filter(|t| t.ty != TokenType::NewLine)
+        .collect();
+
+    for (names, handler) in PRAGMAS {
+        let matched = names.len() <= tokens.len()
+            && names.iter().zip(&tokens).all(|(name, t)| t.is_ident(name));
+        if !matched {
+            continue;
+        }
+        return match handler(&tokens[names.len()..]) {
+            Ok(pragma) => Some(pragma),
+            Err(warning) => {
+                let (code, msg) = warning.unwrap_or_else(|| {
+                    let msg = format!("malformed '#pragma {}' ignored", names.join(" "));
+                    (Code::UnknownPragma, msg)
+                });
+                diagnostic::report(t.warning(code, msg));
+                None
+            }
+        };
+    }
+    let msg = format!("unknown pragma '{}' ignored", t.tokstr());
+    diagnostic::report(t.warning(Code::UnknownPragma, msg));
+    None
+}
+
+// Attaches pragmas to the node following them, and warns about the ones
+// which do not apply to it or have no node to apply to.
+fn apply_pragmas(pragmas: Vec<(Pragma, Token)>, mut node: Option<&mut Node>) {
+    for (pragma, t) in pragmas {
+        let (applies, what) = match (&pragma, node.as_deref()) {
+            (Pragma::Vectorize(_), node) => {
+                (node.is_some_and(|n| matches!(n.op, NodeType::Func(..))), "a function")
+            }
+            (Pragma::Unroll(_), node) => {
+                (node.is_some_and(|n| matches!(n.op, NodeType::For(..))), "a for or while loop")
+            }
+            // parse() hands these to the diagnostic module.
+            (Pragma::Diagnostic(_), _) => unreachable!("diagnostic pragma {:?}", pragma),
+        };
+        match node {
+            Some(ref mut node) if applies => node.pragmas.push(pragma),
+            _ => {
+                let msg = format!("'#pragma {}' must be followed by {}; ignored", t.tokstr(), what);
+                diagnostic::report(t.warning(Code::IgnoredPragma, msg));
+            }
         }
     }
-    v
 }
 
 #[derive(Debug, Clone)]
@@ -60,42 +213,59 @@ macro_rules! new_expr(
 
 #[derive(Debug, Clone)]
 pub enum NodeType {
-    Num(i32),                                        // Number literal
-    Str(String, usize),                              // String literal, (data, len)
+    Num(i64),                                        // Number literal
+    FNum(f64),                                       // Floating-point literal
+    Str(Vec<u8>, usize),                             // String literal, (data, len)
     Ident(String),                                   // Identifier
     Decl(String),                                    // declaration
+    EnumConst(String, Box<Node>),                    // Enumeration constant, (name, value)
     Vardef(String, Option<Box<Node>>, Scope),        // Variable definition, name = init
     Lvar(Scope),                                     // Variable reference
-    Gvar(String, String, usize),                     // Variable reference, (name, data, len)
+    Gvar(String, Vec<u8>, usize),                    // Variable reference, (name, data, len)
     BinOp(TokenType, Box<Node>, Box<Node>),          // left-hand, right-hand
     If(Box<Node>, Box<Node>, Option<Box<Node>>),     // "if" ( cond ) then "else" els
     Ternary(Box<Node>, Box<Node>, Box<Node>),        // cond ? then : els
     For(Box<Node>, Box<Node>, Box<Node>, Box<Node>), // "for" ( init; cond; inc ) body
     Break,
+    Continue,
+    Goto(String),                  // "goto" label
+    Label(String, Box<Node>),      // label: stmt
     DoWhile(Box<Node>, Box<Node>), // do { body } while(cond)
+    Switch(Box<Node>, Box<Node>, Vec<i64>, bool), // "switch" ( cond ) body, (case values, has default)
+    Case(Box<Node>, Box<Node>),    // "case" value: stmt
+    Default(Box<Node>),            // "default": stmt
     Addr(Box<Node>),               // address-of operator("&"), expr
     Deref(Box<Node>),              // pointer dereference ("*"), expr
     Dot(Box<Node>, String, usize), // Struct member accessm, (expr, name, offset)
     Exclamation(Box<Node>),        // !, expr
     Neg(Box<Node>),                // -
+    Cast(Box<Node>),               // Conversion of expr to the node's type
     PostInc(Box<Node>),            // post ++
     PostDec(Box<Node>),            // post --
     Return(Box<Node>),             // "return", stmt
     Sizeof(Box<Node>),             // "sizeof", expr
     Alignof(Box<Node>),            // "_Alignof", expr
     Call(String, Vec<Node>),       // Function call(name, args)
+    CallPtr(Box<Node>, Vec<Node>), // Call through a function pointer(expr, args)
     Func(String, Vec<Node>, Box<Node>, usize), // Function definition(name, args, body, stacksize)
     CompStmt(Vec<Node>),           // Compound statement
     VecStmt(Vec<Node>),            // For the purpose of assign a value when initializing an array.
     ExprStmt(Box<Node>),           // Expression statement
     StmtExpr(Box<Node>),           // Statement expression (GNU extn.)
+    Error,                         // Statement with a syntax error
     Null,
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
-    pub op: NodeType,  // Node type
-    pub ty: Box<Type>, // C type
+    pub op: NodeType,         // Node type
+    pub ty: Box<Type>,        // C type
+    pub pragmas: Vec<Pragma>, // Annotations from preceding pragmas
+
+    // For error reporting: the token the node is made from, such as the
+    // operator of a binary expression.
+    pub span: Span,
+    pub expansion: Option<ExpansionId>,
 }
 
 impl Node {
@@ -103,17 +273,31 @@ impl Node {
         Self {
             op,
             ty: Box::new(Type::default()),
+            pragmas: vec![],
+            span: Span::default(),
+            expansion: None,
         }
     }
 
-    pub fn new_int(val: i32) -> Self {
+    // Sets the location of the node to that of a token.
+    fn at(mut self, t: &Token) -> Self {
+        self.span = t.span;
+        self.expansion = t.expansion;
+        self
+    }
+
+    pub fn error(&self, code: Code, msg: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(code, msg).at(self.span, self.expansion)
+    }
+
+    pub fn new_int(val: i64) -> Self {
         Node::new(NodeType::Num(val))
     }
 
-    pub fn scale_ptr(node: Box<Node>, ty: &Type) -> Self {
+    pub fn scale_ptr(node: Node, ty: &Type) -> Self {
         match ty.ty {
             Ctype::Ptr(ref ptr_to) => {
-                Node::new_binop(TokenType::Mul, *node, Node::new_int(ptr_to.size as i32))
+                Node::new_binop(TokenType::Mul, node, Node::new_int(ptr_to.size as i64))
             }
             _ => panic!("expect ptr type"),
         }
@@ -123,14 +307,54 @@ impl Node {
         Node::new(NodeType::BinOp(ty, Box::new(lhs), Box::new(rhs)))
     }
 
-    pub fn new_num(val: i32) -> Self {
-        Node::new(NodeType::Num(val))
+    pub fn new_num(val: i64, ty: IntType) -> Self {
+        let mut node = Node::new(NodeType::Num(val));
+        node.ty = Box::new(Type::from(ty));
+        node
+    }
+
+    pub fn new_float(val: f64, ty: FloatType) -> Self {
+        let mut node = Node::new(NodeType::FNum(val));
+        node.ty = Box::new(Type::from(ty));
+        node
     }
 
     pub fn is_null(&self) -> bool {
-        match self.op {
-            NodeType::Null => true,
-            _ => false,
+        matches!(self.op, NodeType::Null)
+    }
+}
+
+impl From<IntType> for Type {
+    fn from(ty: IntType) -> Self {
+        match ty {
+            IntType::Int => Type::int_ty(),
+            IntType::UInt => Type::int_ty().unsigned(),
+            IntType::Long => Type::long_ty(),
+            IntType::ULong => Type::long_ty().unsigned(),
+            IntType::UChar => Type::char_ty().unsigned(),
+            IntType::UShort => Type::short_ty().unsigned(),
+        }
+    }
+}
+
+// Element type of a string literal. char16_t and char32_t are unsigned,
+// and wchar_t is int.
+impl From<Encoding> for Type {
+    fn from(enc: Encoding) -> Self {
+        match enc {
+            Encoding::Plain | Encoding::UTF8 => Type::char_ty(),
+            Encoding::UTF16 => Type::short_ty().unsigned(),
+            Encoding::UTF32 => Type::int_ty().unsigned(),
+            Encoding::Wide => Type::int_ty(),
+        }
+    }
+}
+
+impl From<FloatType> for Type {
+    fn from(ty: FloatType) -> Self {
+        match ty {
+            FloatType::Float => Type::float_ty(),
+            FloatType::Double => Type::double_ty(),
         }
     }
 }
@@ -141,6 +365,7 @@ impl Type {
             ty,
             size,
             align: size,
+            is_unsigned: false,
         }
     }
 
@@ -152,10 +377,77 @@ impl Type {
         Type::new(Ctype::Char, 1)
     }
 
+    pub fn short_ty() -> Self {
+        Type::new(Ctype::Short, 2)
+    }
+
     pub fn int_ty() -> Self {
         Type::new(Ctype::Int, 4)
     }
 
+    pub fn long_ty() -> Self {
+        Type::new(Ctype::Long, 8)
+    }
+
+    pub fn float_ty() -> Self {
+        Type::new(Ctype::Float, 4)
+    }
+
+    pub fn double_ty() -> Self {
+        Type::new(Ctype::Double, 8)
+    }
+
+    pub fn unsigned(mut self) -> Self {
+        self.is_unsigned = true;
+        self
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self.ty, Ctype::Int | Ctype::Long | Ctype::Char | Ctype::Short)
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self.ty, Ctype::Float | Ctype::Double)
+    }
+
+    pub fn is_arith(&self) -> bool {
+        self.is_integer() || self.is_float()
+    }
+
+    // Quoted from the C standard
+    // > the usual arithmetic conversions
+    //
+    // If either operand is floating-point, the result is double if either
+    // is double and float otherwise. Integer operands are promoted to at
+    // least int. The result has the size of the larger operand, and is
+    // unsigned if an operand of that size is unsigned.
+    pub fn arith_conv(lhs: &Type, rhs: &Type) -> Type {
+        if !lhs.is_arith() || !rhs.is_arith() {
+            return lhs.clone();
+        }
+        if lhs.is_float() || rhs.is_float() {
+            if matches!(lhs.ty, Ctype::Double) || matches!(rhs.ty, Ctype::Double) {
+                return Type::double_ty();
+            }
+            return Type::float_ty();
+        }
+        let (lhs, rhs) = (lhs.promote(), rhs.promote());
+        match lhs.size.cmp(&rhs.size) {
+            std::cmp::Ordering::Less => rhs,
+            std::cmp::Ordering::Greater => lhs,
+            std::cmp::Ordering::Equal if rhs.is_unsigned => rhs,
+            std::cmp::Ordering::Equal => lhs,
+        }
+    }
+
+    // Integer types smaller than int are converted to int.
+    pub fn promote(&self) -> Type {
+        match self.ty {
+            Ctype::Char | Ctype::Short => Type::int_ty(),
+            _ => self.clone(),
+        }
+    }
+
     pub fn ptr_to(base: Box<Type>) -> Self {
         Type::new(Ctype::Ptr(base), 8)
     }
@@ -173,6 +465,11 @@ pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
     pos: usize,
     env: Env,
+    pragmas: HashMap<usize, Vec<(Pragma, Token)>>, // Keyed by token position
+
+    // Enumeration constants declared by type specifiers read so far, to
+    // be put before the statement or declaration they are part of.
+    enums: Vec<Node>,
 }
 
 impl<'a> Parser<'a> {
@@ -181,6 +478,8 @@ impl<'a> Parser<'a> {
             tokens,
             pos: 0,
             env: Env::new(None),
+            pragmas: HashMap::new(),
+            enums: vec![],
         }
     }
 
@@ -214,12 +513,47 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn expect(&mut self, ty: TokenType) {
+    fn expect(&mut self, ty: TokenType) -> Result<()> {
         let t = &self.tokens[self.pos];
         if t.ty != ty {
-            t.bad_token(&format!("{:?} expected", ty));
+            let e = self.missing(&ty);
+            // A `;` missing at the end of a line is likely the only mistake
+            // there, so parsing goes on as if it were written.
+            if ty == TokenType::Semicolon && t.bol && !e.fixits.is_empty() {
+                return diagnostic::report_error(e);
+            }
+            return Err(e);
         }
         self.pos += 1;
+        Ok(())
+    }
+
+    // Returns an error for a missing token, with a fix-it inserting it
+    // after the previous token if it is a punctuator. Like clang, a
+    // missing `;` is reported there rather than at the next token, which
+    // is often on the next line.
+    fn missing(&self, ty: &TokenType) -> Diagnostic {
+        let msg = format!("expected {}", spelling(ty));
+        let prev = match self.pos {
+            0 => None,
+            pos => Some(&self.tokens[pos - 1]).filter(|t| t.expansion.is_none()),
+        };
+        match (punctuator(ty), prev) {
+            (Some(s), Some(prev)) => {
+                let end = Span { lo: prev.span.hi, ..prev.span };
+                let e = match ty {
+                    TokenType::Semicolon => Diagnostic::error(Code::Syntax, msg).at(end, None),
+                    _ => self.error(&msg),
+                };
+                e.fix(end, s)
+            }
+            _ => self.error(&msg),
+        }
+    }
+
+    // Returns a syntax error at the current token.
+    fn error(&self, msg: &str) -> Diagnostic {
+        self.tokens[self.pos].error(Code::Syntax, msg)
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
@@ -236,7 +570,14 @@ impl<'a> Parser<'a> {
         if let TokenType::Ident(ref name) = t.ty {
             return self.find_typedef(name).is_some();
         }
-        t.ty == Int || t.ty == Char || t.ty == Void || t.ty == Struct
+        t.ty == Int
+            || t.ty == Char
+            || t.ty == Float
+            || t.ty == Double
+            || t.ty == Void
+            || t.ty == Struct
+            || t.ty == Union
+            || t.ty == Enum
     }
 
     fn set_offset(members: &mut Vec<Node>) -> (usize, usize) {
@@ -253,36 +594,82 @@ impl<'a> Parser<'a> {
                     align = t.align;
                 }
             } else {
-                panic!();
+                unreachable!();
             }
         }
         (off, align)
     }
 
+    // Members of a union all start at offset 0.
+    fn set_union_offset(members: &mut Vec<Node>) -> (usize, usize) {
+        let mut size = 0;
+        let mut align = 0;
+        for node in members {
+            if let NodeType::Vardef(_, _, Scope::Local(offset)) = &mut node.op {
+                *offset = 0;
+                size = size.max(node.ty.size);
+                align = align.max(node.ty.align);
+            } else {
+                unreachable!();
+            }
+        }
+        (size, align)
+    }
+
     fn add_member(ty: &mut Type, mut members: Vec<Node>) {
-        let (off, align) = Self::set_offset(&mut members);
-        if let Ctype::Struct(ref mut members2) = ty.ty {
+        let (off, align) = match ty.ty {
+            Ctype::Union(_) => Self::set_union_offset(&mut members),
+            _ => Self::set_offset(&mut members),
+        };
+        if let Ctype::Struct(ref mut members2) | Ctype::Union(ref mut members2) = ty.ty {
             *members2 = members;
         }
         ty.size = roundup(off, align);
+        ty.align = align;
+    }
+
+    // Reads a member declaration of a struct or union. A struct or union
+    // without a tag or a name is an anonymous member, whose members are
+    // found as members of the enclosing one. Returns None for a
+    // declaration which declares no member, such as of an enum.
+    fn member(&mut self) -> Result<Option<Node>> {
+        let start = self.pos;
+        let t = &self.tokens[start];
+        let mut ty = self.base_type()?;
+        if !self.consume(TokenType::Semicolon) {
+            let node = self.declarator(&mut ty, false)?;
+            self.expect(TokenType::Semicolon)?;
+            return Ok(Some(node));
+        }
+
+        let is_aggregate = t.ty == TokenType::Struct || t.ty == TokenType::Union;
+        if !is_aggregate || self.tokens[start + 1].ty != TokenType::LeftBrace {
+            return Ok(None);
+        }
+        let mut node = Node::new(NodeType::Vardef(String::new(), None, Scope::Local(0))).at(t);
+        node.ty = Box::new(ty);
+        Ok(Some(node))
     }
 
-    fn decl_specifiers(&mut self) -> Option<Type> {
+    fn decl_specifiers(&mut self) -> Result<Option<Type>> {
         let t = &self.tokens[self.pos];
         self.pos += 1;
         match t.ty {
             TokenType::Ident(ref name) => {
                 if let Some(ty) = self.find_typedef(name) {
-                    Some(ty.clone())
+                    Ok(Some(ty.clone()))
                 } else {
                     self.pos -= 1;
-                    None
+                    Ok(None)
                 }
             }
-            TokenType::Int => Some(Type::int_ty()),
-            TokenType::Char => Some(Type::char_ty()),
-            TokenType::Void => Some(Type::void_ty()),
-            TokenType::Struct => {
+            TokenType::Int => Ok(Some(Type::int_ty())),
+            TokenType::Char => Ok(Some(Type::char_ty())),
+            TokenType::Float => Ok(Some(Type::float_ty())),
+            TokenType::Double => Ok(Some(Type::double_ty())),
+            TokenType::Void => Ok(Some(Type::void_ty())),
+            TokenType::Struct | TokenType::Union => {
+                let is_union = t.ty == TokenType::Union;
                 let mut tag_may: Option<String> = None;
                 let t = &self.tokens[self.pos];
                 if let TokenType::Ident(ref name) = t.ty {
@@ -293,7 +680,7 @@ impl<'a> Parser<'a> {
                 let mut members = vec![];
                 if self.consume(TokenType::LeftBrace) {
                     while !self.consume(TokenType::RightBrace) {
-                        members.push(self.declaration())
+                        members.extend(self.member()?);
                     }
                 }
 
@@ -303,7 +690,16 @@ impl<'a> Parser<'a> {
                         ty_may = self.find_tag(tag);
                     }
                 }
-                let mut ty = ty_may.unwrap_or(Type::new(Ctype::Struct(vec![]), 10));
+                let same_kind = |ty: &Type| match ty.ty {
+                    Ctype::Struct(_) => !is_union,
+                    Ctype::Union(_) => is_union,
+                    _ => false,
+                };
+                if ty_may.as_ref().is_some_and(|ty| !same_kind(ty)) {
+                    return Err(self.tag_mismatch(t));
+                }
+                let empty = if is_union { Ctype::Union(vec![]) } else { Ctype::Struct(vec![]) };
+                let mut ty = ty_may.unwrap_or(Type::new(empty, 10));
 
                 if !members.is_empty() {
                     Self::add_member(&mut ty, members);
@@ -311,260 +707,407 @@ impl<'a> Parser<'a> {
                         self.env.tags.insert(tag, ty.clone());
                     }
                 }
-                Some(ty.clone())
+                Ok(Some(ty.clone()))
+            }
+            TokenType::Enum => self.enum_specifier().map(Some),
+            _ => {
+                self.pos -= 1;
+                Err(t.error(Code::Syntax, "expected a type"))
+            }
+        }
+    }
+
+    // Reads an enum specifier after `enum`. Enumeration constants are int,
+    // and so are enum types. The constants are left in `self.enums` for
+    // sema, which evaluates their values.
+    fn enum_specifier(&mut self) -> Result<Type> {
+        let t = &self.tokens[self.pos];
+        let tag = match t.ty {
+            TokenType::Ident(ref name) => {
+                self.pos += 1;
+                Some(name.clone())
+            }
+            _ => None,
+        };
+
+        if !self.consume(TokenType::LeftBrace) {
+            let tag = match tag {
+                Some(tag) => tag,
+                None => return Err(self.error("expected identifier or '{'")),
+            };
+            return match self.find_tag(&tag) {
+                Some(ty) if matches!(ty.ty, Ctype::Struct(_) | Ctype::Union(_)) => {
+                    Err(self.tag_mismatch(t))
+                }
+                Some(ty) => Ok(ty),
+                None => Err(t.error(Code::Syntax, format!("use of undeclared enum '{}'", tag))),
+            };
+        }
+
+        // A constant without a value is one more than the previous one.
+        let mut prev: Option<Node> = None;
+        loop {
+            let t = &self.tokens[self.pos];
+            let name = self.ident()?;
+            let val = if self.consume(TokenType::Equal) {
+                self.conditional()?
+            } else if let Some(prev) = prev {
+                Node::new_binop(TokenType::Plus, prev, Node::new_int(1)).at(t)
+            } else {
+                Node::new_int(0).at(t)
+            };
+            self.enums.push(Node::new(NodeType::EnumConst(name.clone(), Box::new(val))).at(t));
+            prev = Some(Node::new(NodeType::Ident(name)).at(t));
+
+            if self.consume(TokenType::RightBrace) {
+                break;
+            }
+            self.expect(TokenType::Comma)?;
+            if self.consume(TokenType::RightBrace) {
+                break;
             }
-            _ => t.bad_token("typename expected"),
         }
+
+        let ty = Type::int_ty();
+        if let Some(tag) = tag {
+            self.env.tags.insert(tag, ty.clone());
+        }
+        Ok(ty)
+    }
+
+    fn tag_mismatch(&self, t: &Token) -> Diagnostic {
+        let msg = format!(
+            "use of '{}' with tag type that does not match previous declaration",
+            t.tokstr()
+        );
+        t.error(Code::Syntax, msg)
     }
 
-    fn ident(&mut self) -> String {
+    // Puts the enumeration constants declared by `node` before it.
+    fn with_enums(&mut self, node: Node) -> Node {
+        if self.enums.is_empty() {
+            return node;
+        }
+        let mut stmts = std::mem::take(&mut self.enums);
+        stmts.push(node);
+        Node::new(NodeType::VecStmt(stmts))
+    }
+
+    fn ident(&mut self) -> Result<String> {
         let t = &self.tokens[self.pos];
         if let TokenType::Ident(ref name) = t.ty {
             self.pos += 1;
-            name.clone()
+            Ok(name.clone())
         } else {
-            t.bad_token("variable name expected");
+            Err(self.error("expected identifier"))
         }
     }
 
-    fn primary(&mut self) -> Node {
+    fn primary(&mut self) -> Result<Node> {
         let t = &self.tokens[self.pos];
         self.pos += 1;
-        match t.ty {
-            TokenType::Num(val) => Node::new_num(val),
-            TokenType::Str(ref str, len) => {
-                let mut node = Node::new(NodeType::Str(str.clone(), len));
-                node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), len));
+        Ok(match t.ty {
+            TokenType::Num(val, ty) => Node::new_num(val, ty).at(t),
+            TokenType::FNum(val, ty) => Node::new_float(val, ty).at(t),
+            TokenType::Str(ref units, enc) => {
+                // Code units are stored little-endian, followed by a
+                // terminating zero unit.
+                let elem = Type::from(enc);
+                let len = units.len() + 1;
+                let mut data = vec![];
+                for u in units.iter().chain(&[0]) {
+                    data.extend_from_slice(&u.to_le_bytes()[..elem.size]);
+                }
+                let mut node = Node::new(NodeType::Str(data, len)).at(t);
+                node.ty = Box::new(Type::ary_of(Box::new(elem), len));
                 node
             }
             TokenType::Ident(ref name) => {
                 if !self.consume(TokenType::LeftParen) {
-                    return Node::new(NodeType::Ident(name.clone()));
+                    return Ok(Node::new(NodeType::Ident(name.clone())).at(t));
                 }
 
-                let mut args = vec![];
-                if self.consume(TokenType::RightParen) {
-                    return Node::new(NodeType::Call(name.clone(), args));
-                }
+                let mut args = self.call_args()?;
 
-                args.push(self.assign());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.assign());
+                // `__builtin_expect(x, c)` is `x` with a hint for branch
+                // prediction, which we don't use.
+                if name == "__builtin_expect" {
+                    if args.len() != 2 {
+                        let msg = "__builtin_expect takes 2 arguments";
+                        return Err(t.error(Code::Syntax, msg));
+                    }
+                    return Ok(args.remove(0));
                 }
-                self.expect(TokenType::RightParen);
-                Node::new(NodeType::Call(name.clone(), args))
+                Node::new(NodeType::Call(name.clone(), args)).at(t)
             }
             TokenType::LeftParen => {
                 if self.consume(TokenType::LeftBrace) {
-                    let stmt = Box::new(self.compound_stmt());
-                    self.expect(TokenType::RightParen);
-                    return Node::new(NodeType::StmtExpr(stmt));
+                    let stmt = Box::new(self.compound_stmt()?);
+                    self.expect(TokenType::RightParen)?;
+                    return Ok(Node::new(NodeType::StmtExpr(stmt)).at(t));
                 }
-                let node = self.expr();
-                self.expect(TokenType::RightParen);
+                let node = self.expr()?;
+                self.expect(TokenType::RightParen)?;
                 node
             }
-            _ => t.bad_token("number expected"),
+            _ => {
+                self.pos -= 1;
+                return Err(t.error(Code::Syntax, "expected expression"));
+            }
+        })
+    }
+
+    // Reads arguments of a function call after `(`.
+    fn call_args(&mut self) -> Result<Vec<Node>> {
+        let mut args = vec![];
+        if self.consume(TokenType::RightParen) {
+            return Ok(args);
         }
+
+        args.push(self.assign()?);
+        while self.consume(TokenType::Comma) {
+            args.push(self.assign()?);
+        }
+        self.expect(TokenType::RightParen)?;
+        Ok(args)
     }
 
-    fn postfix(&mut self) -> Node {
-        let mut lhs = self.primary();
+    fn postfix(&mut self) -> Result<Node> {
+        let mut lhs = self.primary()?;
 
         loop {
+            let t = &self.tokens[self.pos];
+            // A call of a function named by an identifier is read by
+            // primary(), so this is a call through a pointer.
+            if self.consume(TokenType::LeftParen) {
+                lhs = Node::new(NodeType::CallPtr(Box::new(lhs), self.call_args()?)).at(t);
+                continue;
+            }
+
             if self.consume(TokenType::Inc) {
-                lhs = new_expr!(NodeType::PostInc, lhs);
+                lhs = new_expr!(NodeType::PostInc, lhs).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Dec) {
-                lhs = new_expr!(NodeType::PostDec, lhs);
+                lhs = new_expr!(NodeType::PostDec, lhs).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Dot) {
                 // TODO: Use new_expr!
-                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident(), 0));
+                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident()?, 0)).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Arrow) {
                 lhs = Node::new(NodeType::Dot(
-                    Box::new(new_expr!(NodeType::Deref, lhs)),
-                    self.ident(),
+                    Box::new(new_expr!(NodeType::Deref, lhs).at(t)),
+                    self.ident()?,
                     0,
-                ));
+                ))
+                .at(t);
                 continue;
             }
 
             if self.consume(TokenType::LeftBracket) {
                 lhs = new_expr!(
                     NodeType::Deref,
-                    Node::new_binop(TokenType::Plus, lhs, self.assign())
-                );
-                self.expect(TokenType::RightBracket);
+                    Node::new_binop(TokenType::Plus, lhs, self.assign()?).at(t)
+                )
+                .at(t);
+                self.expect(TokenType::RightBracket)?;
                 continue;
             }
-            return lhs;
+            return Ok(lhs);
         }
     }
 
-    fn unary(&mut self) -> Node {
+    fn unary(&mut self) -> Result<Node> {
+        let t = &self.tokens[self.pos];
         if self.consume(TokenType::Minus) {
-            return new_expr!(NodeType::Neg, self.unary());
+            return Ok(new_expr!(NodeType::Neg, self.unary()?).at(t));
         }
         if self.consume(TokenType::Mul) {
-            return new_expr!(NodeType::Deref, self.unary());
+            return Ok(new_expr!(NodeType::Deref, self.unary()?).at(t));
         }
         if self.consume(TokenType::And) {
-            return new_expr!(NodeType::Addr, self.unary());
+            return Ok(new_expr!(NodeType::Addr, self.unary()?).at(t));
         }
         if self.consume(TokenType::Exclamation) {
-            return new_expr!(NodeType::Exclamation, self.unary());
+            return Ok(new_expr!(NodeType::Exclamation, self.unary()?).at(t));
         }
         if self.consume(TokenType::Sizeof) {
-            return new_expr!(NodeType::Sizeof, self.unary());
+            if let Some(ty) = self.paren_type_name()? {
+                return Ok(Node::new_int(ty.size as i64).at(t));
+            }
+            return Ok(new_expr!(NodeType::Sizeof, self.unary()?).at(t));
         }
         if self.consume(TokenType::Alignof) {
-            return new_expr!(NodeType::Alignof, self.unary());
+            if let Some(ty) = self.paren_type_name()? {
+                return Ok(Node::new_int(ty.align as i64).at(t));
+            }
+            return Ok(new_expr!(NodeType::Alignof, self.unary()?).at(t));
+        }
+        if let Some(ty) = self.paren_type_name()? {
+            let mut node = new_expr!(NodeType::Cast, self.unary()?).at(t);
+            node.ty = Box::new(ty);
+            return Ok(node);
         }
 
         if self.consume(TokenType::Inc) {
-            return Node::new_binop(TokenType::AddEQ, self.unary(), Node::new_num(1));
+            return Ok(Node::new_binop(TokenType::AddEQ, self.unary()?, Node::new_int(1)).at(t));
         }
         if self.consume(TokenType::Dec) {
-            return Node::new_binop(TokenType::SubEQ, self.unary(), Node::new_num(1));
+            return Ok(Node::new_binop(TokenType::SubEQ, self.unary()?, Node::new_int(1)).at(t));
         }
 
         self.postfix()
     }
 
-    fn mul(&mut self) -> Node {
-        let mut lhs = self.unary();
+    fn mul(&mut self) -> Result<Node> {
+        let mut lhs = self.unary()?;
 
         loop {
+            let t = &self.tokens[self.pos];
             if self.consume(TokenType::Mul) {
-                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Div) {
-                lhs = Node::new_binop(TokenType::Div, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Div, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Mod) {
-                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary());
+                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn add(&mut self) -> Node {
-        let mut lhs = self.mul();
+    fn add(&mut self) -> Result<Node> {
+        let mut lhs = self.mul()?;
 
         loop {
+            let t = &self.tokens[self.pos];
             if self.consume(TokenType::Plus) {
-                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul());
+                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul()?).at(t);
             } else if self.consume(TokenType::Minus) {
-                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul());
+                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn shift(&mut self) -> Node {
-        let mut lhs = self.add();
+    fn shift(&mut self) -> Result<Node> {
+        let mut lhs = self.add()?;
         loop {
+            let t = &self.tokens[self.pos];
             if self.consume(TokenType::SHL) {
-                lhs = Node::new_binop(TokenType::SHL, lhs, self.add());
+                lhs = Node::new_binop(TokenType::SHL, lhs, self.add()?).at(t);
             } else if self.consume(TokenType::SHR) {
-                lhs = Node::new_binop(TokenType::SHR, lhs, self.add());
+                lhs = Node::new_binop(TokenType::SHR, lhs, self.add()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn relational(&mut self) -> Node {
-        let mut lhs = self.shift();
+    fn relational(&mut self) -> Result<Node> {
+        let mut lhs = self.shift()?;
         loop {
+            let t = &self.tokens[self.pos];
             if self.consume(TokenType::LeftAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift());
+                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::RightAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift()?, lhs).at(t);
             } else if self.consume(TokenType::LE) {
-                lhs = Node::new_binop(TokenType::LE, lhs, self.shift())
+                lhs = Node::new_binop(TokenType::LE, lhs, self.shift()?).at(t)
             } else if self.consume(TokenType::GE) {
-                lhs = Node::new_binop(TokenType::LE, self.shift(), lhs);
+                lhs = Node::new_binop(TokenType::LE, self.shift()?, lhs).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn equality(&mut self) -> Node {
-        let mut lhs = self.relational();
+    fn equality(&mut self) -> Result<Node> {
+        let mut lhs = self.relational()?;
         loop {
+            let t = &self.tokens[self.pos];
             if self.consume(TokenType::EQ) {
-                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational());
+                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational()?).at(t);
             } else if self.consume(TokenType::NE) {
-                lhs = Node::new_binop(TokenType::NE, lhs, self.relational());
+                lhs = Node::new_binop(TokenType::NE, lhs, self.relational()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn bit_and(&mut self) -> Node {
-        let mut lhs = self.equality();
+    fn bit_and(&mut self) -> Result<Node> {
+        let mut lhs = self.equality()?;
+        let mut t = &self.tokens[self.pos];
         while self.consume(TokenType::And) {
-            lhs = Node::new_binop(TokenType::And, lhs, self.equality());
+            lhs = Node::new_binop(TokenType::And, lhs, self.equality()?).at(t);
+            t = &self.tokens[self.pos];
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn bit_xor(&mut self) -> Node {
-        let mut lhs = self.bit_and();
+    fn bit_xor(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_and()?;
+        let mut t = &self.tokens[self.pos];
         while self.consume(TokenType::Hat) {
-            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and());
+            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and()?).at(t);
+            t = &self.tokens[self.pos];
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn bit_or(&mut self) -> Node {
-        let mut lhs = self.bit_xor();
+    fn bit_or(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_xor()?;
+        let mut t = &self.tokens[self.pos];
         while self.consume(TokenType::VerticalBar) {
-            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor());
+            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor()?).at(t);
+            t = &self.tokens[self.pos];
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn logand(&mut self) -> Node {
-        let mut lhs = self.bit_or();
+    fn logand(&mut self) -> Result<Node> {
+        let mut lhs = self.bit_or()?;
+        let mut t = &self.tokens[self.pos];
         while self.consume(TokenType::Logand) {
-            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand());
+            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand()?).at(t);
+            t = &self.tokens[self.pos];
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn logor(&mut self) -> Node {
-        let mut lhs = self.logand();
+    fn logor(&mut self) -> Result<Node> {
+        let mut lhs = self.logand()?;
+        let mut t = &self.tokens[self.pos];
         while self.consume(TokenType::Logor) {
-            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand());
+            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand()?).at(t);
+            t = &self.tokens[self.pos];
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn conditional(&mut self) -> Node {
-        let cond = self.logor();
+    fn conditional(&mut self) -> Result<Node> {
+        let cond = self.logor()?;
+        let t = &self.tokens[self.pos];
         if !self.consume(TokenType::Question) {
-            return cond;
+            return Ok(cond);
         }
-        let then = self.expr();
-        self.expect(TokenType::Colon);
-        let els = self.conditional();
-        Node::new(NodeType::Ternary(
+        let then = self.expr()?;
+        self.expect(TokenType::Colon)?;
+        let els = self.conditional()?;
+        Ok(Node::new(NodeType::Ternary(
             Box::new(cond),
             Box::new(then),
             Box::new(els),
         ))
+        .at(t))
     }
 
     fn assign_op(ty: &TokenType) -> Option<&TokenType> {
@@ -576,37 +1119,27 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn assign(&mut self) -> Node {
-        let lhs = self.conditional();
-        if let Some(op) = Self::assign_op(&self.tokens[self.pos].ty) {
+    fn assign(&mut self) -> Result<Node> {
+        let lhs = self.conditional()?;
+        let t = &self.tokens[self.pos];
+        if let Some(op) = Self::assign_op(&t.ty) {
             self.pos += 1;
-            Node::new_binop(op.clone(), lhs, self.assign())
+            Ok(Node::new_binop(op.clone(), lhs, self.assign()?).at(t))
         } else {
-            lhs
+            Ok(lhs)
         }
     }
 
-    fn expr(&mut self) -> Node {
-        let lhs = self.assign();
-        if !self.consume(TokenType::Comma) {
-            return lhs;
-        }
-        Node::new_binop(TokenType::Comma, lhs, self.expr())
-    }
-
-    fn ctype(&mut self) -> Type {
+    fn expr(&mut self) -> Result<Node> {
+        let lhs = self.assign()?;
         let t = &self.tokens[self.pos];
-        if let Some(mut ty) = self.decl_specifiers() {
-            while self.consume(TokenType::Mul) {
-                ty = Type::ptr_to(Box::new(ty));
-            }
-            ty
-        } else {
-            t.bad_token("typename expected");
+        if !self.consume(TokenType::Comma) {
+            return Ok(lhs);
         }
+        Ok(Node::new_binop(TokenType::Comma, lhs, self.expr()?).at(t))
     }
 
-    fn read_array(&mut self, mut ty: Box<Type>) -> Type {
+    fn read_array(&mut self, mut ty: Box<Type>) -> Result<Type> {
         let mut v: Vec<usize> = vec![];
         while self.consume(TokenType::LeftBracket) {
             if self.consume(TokenType::RightBracket) {
@@ -614,12 +1147,12 @@ impl<'a> Parser<'a> {
                 continue;
             }
 
-            let len = self.expr();
+            let len = self.expr()?;
             if let NodeType::Num(n) = len.op {
                 v.push(n as usize);
-                self.expect(TokenType::RightBracket);
+                self.expect(TokenType::RightBracket)?;
             } else {
-                panic!("number expected");
+                return Err(len.error(Code::Syntax, "array size must be an integer constant"));
             }
         }
 
@@ -627,17 +1160,17 @@ impl<'a> Parser<'a> {
         for val in v {
             ty = Box::new(Type::ary_of(ty, val));
         }
-        *ty
+        Ok(*ty)
     }
 
-    fn array_init_rval(&mut self, ident: Node) -> Node {
+    fn array_init_rval(&mut self, ident: Node) -> Result<Node> {
         let mut init = vec![];
         let mut i = 0;
         loop {
-            let val = self.primary();
+            let val = self.primary()?;
             let node = new_expr!(
                 NodeType::Deref,
-                Node::new_binop(TokenType::Plus, ident.clone(), Node::new(NodeType::Num(i)))
+                Node::new_binop(TokenType::Plus, ident.clone(), Node::new_int(i))
             );
             init.push(Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
                 TokenType::Equal,
@@ -649,98 +1182,216 @@ impl<'a> Parser<'a> {
             }
             i += 1;
         }
-        self.expect(TokenType::RightBrace);
-        Node::new(NodeType::VecStmt(init))
+        self.expect(TokenType::RightBrace)?;
+        Ok(Node::new(NodeType::VecStmt(init)))
     }
 
-    fn update_ptr_to(&mut self, src: &mut Box<Type>, dst: Box<Type>) {
+    // Replaces the placeholder at the bottom of `src` with `dst`, the type
+    // a parenthesized declarator applies to. For example, `int (*x)[3]`
+    // reads `*x` as a pointer to the placeholder, which becomes `int[3]`.
+    fn update_ptr_to(src: &mut Box<Type>, dst: Box<Type>) {
         match src.ty {
-            Ctype::Ptr(ref mut ptr_to) => self.update_ptr_to(ptr_to, dst),
+            Ctype::Ptr(ref mut ptr_to) => Self::update_ptr_to(ptr_to, dst),
+            Ctype::Func(ref mut returning, _) => Self::update_ptr_to(returning, dst),
+            Ctype::Ary(ref mut ary_of, len) => {
+                Self::update_ptr_to(ary_of, dst);
+                // The size depends on the element type.
+                **src = Type::ary_of(ary_of.clone(), len);
+            }
             _ => *src = dst,
         }
     }
 
-    fn direct_decl(&mut self, ty: Box<Type>) -> Node {
+    // Reads the part of a declarator after the name: array dimensions or
+    // a parameter list.
+    fn type_suffix(&mut self, ty: Box<Type>) -> Result<Type> {
+        if self.consume(TokenType::LeftParen) {
+            return self.param_list(ty);
+        }
+        self.read_array(ty)
+    }
+
+    // Reads a parameter list after `(`. `(void)` declares no parameters,
+    // and so does `()` as in C23. Arguments matching `...` are passed as
+    // they are.
+    fn param_list(&mut self, returning: Box<Type>) -> Result<Type> {
+        let mut params = vec![];
+        if self.tokens[self.pos].ty == TokenType::Void
+            && self.tokens[self.pos + 1].ty == TokenType::RightParen
+        {
+            self.pos += 1;
+        }
+        if !self.consume(TokenType::RightParen) {
+            loop {
+                if self.consume(TokenType::Ellipsis) {
+                    self.expect(TokenType::RightParen)?;
+                    break;
+                }
+                params.push(self.param_declaration()?);
+                if !self.consume(TokenType::Comma) {
+                    self.expect(TokenType::RightParen)?;
+                    break;
+                }
+            }
+        }
+        Ok(Type::new(Ctype::Func(returning, params), 0))
+    }
+
+    fn direct_decl(&mut self, ty: Box<Type>, is_abstract: bool) -> Result<Node> {
         let t = &self.tokens[self.pos];
         let mut placeholder = Box::new(Type::default());
         let mut node;
 
+        // In an abstract declarator, `(` may begin a parameter list, as in
+        // `int (*)(int)`.
+        let is_params = is_abstract && t.ty == TokenType::LeftParen && {
+            let next = &self.tokens[self.pos + 1];
+            next.ty == TokenType::RightParen || self.is_typename(next)
+        };
+
         if let TokenType::Ident(_) = t.ty {
-            node = Node::new(NodeType::Vardef(self.ident(), None, Scope::Local(0)));
-        } else if self.consume(TokenType::LeftParen) {
-            node = self.declarator(&mut placeholder);
-            self.expect(TokenType::RightParen);
+            node = Node::new(NodeType::Vardef(self.ident()?, None, Scope::Local(0))).at(t);
+        } else if t.ty == TokenType::LeftParen && !is_params {
+            self.pos += 1;
+            node = self.declarator(&mut placeholder, is_abstract)?;
+            self.expect(TokenType::RightParen)?;
+        } else if is_abstract {
+            node = Node::new(NodeType::Vardef(String::new(), None, Scope::Local(0))).at(t);
         } else {
-            t.bad_token("bad direct-declarator");
+            return Err(t.error(Code::Syntax, "expected identifier or '('"));
         }
 
         // Read the second half of type name (e.g. `[3][5]`).
-        let ty = self.read_array(ty);
-        self.update_ptr_to(&mut node.ty, Box::new(ty));
-
-        // Read an initializer.
-        let init: Option<Box<Node>>;
-        if self.consume(TokenType::Equal) {
-            // Assign a value when initializing an array.
-            if let TokenType::Ident(ref name) = t.ty {
-                if self.consume(TokenType::LeftBrace) {
-                    let mut stmts = vec![];
-                    let mut ary_declaration =
-                        Node::new(NodeType::Vardef(name.clone(), None, Scope::Local(0)));
-                    ary_declaration.ty = node.ty;
-                    stmts.push(ary_declaration);
-                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name.clone())));
-                    stmts.push(init_ary);
-                    return Node::new(NodeType::VecStmt(stmts));
-                }
-            }
+        let ty = self.type_suffix(ty)?;
+        Self::update_ptr_to(&mut node.ty, Box::new(ty));
+        Ok(node)
+    }
+
+    // Reads a declarator. The name may be left out if `is_abstract`, as in
+    // a parameter of a prototype, and is empty then.
+    fn declarator(&mut self, ty: &mut Type, is_abstract: bool) -> Result<Node> {
+        while self.consume(TokenType::Mul) {
+            *ty = Type::ptr_to(Box::new(ty.clone()));
+        }
+        self.direct_decl(Box::new(ty.clone()), is_abstract)
+    }
 
-            init = Some(Box::new(self.assign()));
-            match node.op {
-                NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
+    // Reads an initializer of a local variable, if any.
+    fn initializer(&mut self, mut node: Node) -> Result<Node> {
+        if !self.consume(TokenType::Equal) {
+            return Ok(node);
+        }
+
+        // Assign a value when initializing an array.
+        if self.consume(TokenType::LeftBrace) {
+            let name = match node.op {
+                NodeType::Vardef(ref name, _, _) => name.clone(),
                 _ => unreachable!(),
+            };
+            let mut ident = Node::new(NodeType::Ident(name));
+            ident.span = node.span;
+            ident.expansion = node.expansion;
+            let init_ary = self.array_init_rval(ident)?;
+            return Ok(Node::new(NodeType::VecStmt(vec![node, init_ary])));
+        }
+
+        let init = Some(Box::new(self.assign()?));
+        match node.op {
+            NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
+            _ => unreachable!(),
+        }
+        Ok(node)
+    }
+
+    // Reads declaration specifiers, which must be present.
+    fn base_type(&mut self) -> Result<Type> {
+        let t = &self.tokens[self.pos];
+        match self.decl_specifiers()? {
+            Some(ty) => Ok(ty),
+            None => Err(t.error(Code::Syntax, format!("unknown type name '{}'", t.tokstr()))),
+        }
+    }
+
+    // Reads a type name, as in a cast or `sizeof(int)`.
+    fn type_name(&mut self) -> Result<Type> {
+        let mut ty = self.base_type()?;
+        let node = self.declarator(&mut ty, true)?;
+        if let NodeType::Vardef(ref name, _, _) = node.op {
+            if !name.is_empty() {
+                return Err(node.error(Code::Syntax, "expected ')'"));
             }
         }
-        node
+        Ok(*node.ty)
     }
 
-    fn declarator(&mut self, ty: &mut Type) -> Node {
-        while self.consume(TokenType::Mul) {
-            *ty = Type::ptr_to(Box::new(ty.clone()));
+    // Reads a parenthesized type name if the next tokens are one.
+    fn paren_type_name(&mut self) -> Result<Option<Type>> {
+        let t = &self.tokens[self.pos];
+        if t.ty != TokenType::LeftParen || !self.is_typename(&self.tokens[self.pos + 1]) {
+            return Ok(None);
         }
-        self.direct_decl(Box::new(ty.clone()))
+        self.pos += 1;
+        let ty = self.type_name()?;
+        self.expect(TokenType::RightParen)?;
+        Ok(Some(ty))
     }
 
-    fn declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let node = self.declarator(&mut ty);
-        self.expect(TokenType::Semicolon);
-        node
+    fn declaration(&mut self) -> Result<Node> {
+        let mut ty = self.base_type()?;
+        // Only declares a tag or enumeration constants, e.g. `enum { A };`
+        if self.consume(TokenType::Semicolon) {
+            return Ok(Node::new(NodeType::Null));
+        }
+        let node = self.declarator(&mut ty, false)?;
+        let node = self.initializer(node)?;
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn param_declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let mut node = self.declarator(&mut ty);
-        if let Ctype::Ary(ary_of, _) = node.ty.ty {
-            node.ty = Box::new(Type::ptr_to(ary_of));
+    fn param_declaration(&mut self) -> Result<Node> {
+        let mut ty = self.base_type()?;
+        let mut node = self.declarator(&mut ty, true)?;
+        // Parameters of array and function types are pointers.
+        match node.ty.ty {
+            Ctype::Ary(ref ary_of, _) => node.ty = Box::new(Type::ptr_to(ary_of.clone())),
+            Ctype::Func(_, _) => node.ty = Box::new(Type::ptr_to(node.ty.clone())),
+            _ => (),
         }
-        node
+        Ok(node)
     }
 
-    fn expr_stmt(&mut self) -> Node {
-        let expr = self.expr();
+    fn expr_stmt(&mut self) -> Result<Node> {
+        let expr = self.expr()?;
         let node = new_expr!(NodeType::ExprStmt, expr);
-        self.expect(TokenType::Semicolon);
-        node
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn stmt(&mut self) -> Node {
+    fn stmt(&mut self) -> Result<Node> {
+        let pragmas = self.pragmas.remove(&self.pos);
+        let start = self.pos;
+        let mut node = match self.stmt2() {
+            Ok(node) => self.with_enums(node),
+            Err(e) => {
+                self.enums.clear();
+                self.recover(e, start, false)?;
+                Node::new(NodeType::Error).at(&self.tokens[start])
+            }
+        };
+        // A statement with a syntax error has been reported already.
+        if !matches!(node.op, NodeType::Error) {
+            apply_pragmas(pragmas.unwrap_or_default(), Some(&mut node));
+        }
+        Ok(node)
+    }
+
+    fn stmt2(&mut self) -> Result<Node> {
         let t = &self.tokens[self.pos];
         self.pos += 1;
 
-        match t.ty {
+        let node = match t.ty {
             TokenType::Typedef => {
-                let node = self.declaration();
+                let node = self.declaration()?;
                 if let NodeType::Vardef(name, _, _) = node.op {
                     self.env.typedefs.insert(name, *node.ty);
                     Node::new(NodeType::Null)
@@ -750,165 +1401,300 @@ impl<'a> Parser<'a> {
             }
             TokenType::If => {
                 let mut els = None;
-                self.expect(TokenType::LeftParen);
-                let cond = self.expr();
-                self.expect(TokenType::RightParen);
-                let then = self.stmt();
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                let then = self.stmt()?;
                 if self.consume(TokenType::Else) {
-                    els = Some(Box::new(self.stmt()));
+                    els = Some(Box::new(self.stmt()?));
                 }
                 Node::new(NodeType::If(Box::new(cond), Box::new(then), els))
             }
             TokenType::For => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
 
                 let init: Box<Node> = if self.is_typename(&self.tokens[self.pos]) {
-                    Box::new(self.declaration())
+                    let init = self.declaration()?;
+                    Box::new(self.with_enums(init))
                 } else if self.consume(TokenType::Semicolon) {
                     Box::new(Node::new(NodeType::Null))
                 } else {
-                    Box::new(self.expr_stmt())
+                    Box::new(self.expr_stmt()?)
                 };
 
                 let cond;
                 if !self.consume(TokenType::Semicolon) {
-                    cond = Box::new(self.expr());
-                    self.expect(TokenType::Semicolon);
+                    cond = Box::new(self.expr()?);
+                    self.expect(TokenType::Semicolon)?;
                 } else {
                     cond = Box::new(Node::new(NodeType::Null))
                 }
 
                 let inc;
                 if !self.consume(TokenType::RightParen) {
-                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()));
-                    self.expect(TokenType::RightParen);
+                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()?));
+                    self.expect(TokenType::RightParen)?;
                 } else {
                     inc = Box::new(Node::new(NodeType::Null))
                 }
 
-                let body = Box::new(self.stmt());
+                let body = Box::new(self.stmt()?);
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::While => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
                 let init = Box::new(Node::new(NodeType::Null));
                 let inc = Box::new(Node::new(NodeType::Null));
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                let body = Box::new(self.stmt());
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                let body = Box::new(self.stmt()?);
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::Do => {
-                let body = Box::new(self.stmt());
-                self.expect(TokenType::While);
-                self.expect(TokenType::LeftParen);
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                self.expect(TokenType::Semicolon);
+                let body = Box::new(self.stmt()?);
+                self.expect(TokenType::While)?;
+                self.expect(TokenType::LeftParen)?;
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::DoWhile(body, cond))
             }
+            TokenType::Switch => {
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                let body = self.stmt()?;
+                Node::new(NodeType::Switch(Box::new(cond), Box::new(body), vec![], false))
+            }
+            TokenType::Case => {
+                let val = self.conditional()?;
+                self.expect(TokenType::Colon)?;
+                let stmt = self.labeled_stmt()?;
+                Node::new(NodeType::Case(Box::new(val), Box::new(stmt)))
+            }
+            TokenType::Default => {
+                self.expect(TokenType::Colon)?;
+                Node::new(NodeType::Default(Box::new(self.labeled_stmt()?)))
+            }
             TokenType::Break => {
-                self.expect(TokenType::Semicolon);
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::Break)
             }
+            TokenType::Continue => {
+                self.expect(TokenType::Semicolon)?;
+                Node::new(NodeType::Continue)
+            }
+            TokenType::Goto => {
+                // Errors about the label point to its name.
+                let label = &self.tokens[self.pos];
+                let name = self.ident()?;
+                self.expect(TokenType::Semicolon)?;
+                return Ok(Node::new(NodeType::Goto(name)).at(label));
+            }
+            TokenType::Ident(ref name) if self.tokens[self.pos].ty == TokenType::Colon => {
+                self.pos += 1;
+                Node::new(NodeType::Label(name.clone(), Box::new(self.labeled_stmt()?)))
+            }
             TokenType::Return => {
-                let expr = self.expr();
-                self.expect(TokenType::Semicolon);
+                let expr = self.expr()?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::Return(Box::new(expr)))
             }
-            TokenType::LeftBrace => {
-                let mut stmts = vec![];
-                while !self.consume(TokenType::RightBrace) {
-                    stmts.push(self.stmt());
-                }
-                Node::new(NodeType::CompStmt(stmts))
-            }
+            TokenType::LeftBrace => Node::new(NodeType::CompStmt(self.block_items(t)?)),
             TokenType::Semicolon => Node::new(NodeType::Null),
             _ => {
                 self.pos -= 1;
                 if self.is_typename(&self.tokens[self.pos]) {
                     return self.declaration();
                 }
-                self.expr_stmt()
+                return self.expr_stmt();
             }
+        };
+        Ok(node.at(t))
+    }
+
+    // Reads the statement following a label. As in C23, a label may end
+    // a block.
+    fn labeled_stmt(&mut self) -> Result<Node> {
+        if self.tokens[self.pos].ty == TokenType::RightBrace {
+            return Ok(Node::new(NodeType::Null));
         }
+        self.stmt()
     }
 
-    fn compound_stmt(&mut self) -> Node {
+    // Reads statements up to the `}` matching `brace`.
+    fn block_items(&mut self, brace: &Token) -> Result<Vec<Node>> {
         let mut stmts = vec![];
+        while !self.consume(TokenType::RightBrace) {
+            if self.tokens[self.pos].ty == TokenType::Eof {
+                let e = self.error("expected '}'");
+                return Err(e.label(brace.span, "to match this '{'"));
+            }
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
 
+    fn compound_stmt(&mut self) -> Result<Node> {
         let new_env = Env::new(Some(Box::new(self.env.clone())));
         self.env = new_env;
-        while !self.consume(TokenType::RightBrace) {
-            stmts.push(self.stmt());
-        }
+        let stmts = self.block_items(&self.tokens[self.pos - 1])?;
         let next = self.env.next.clone();
         self.env = *next.unwrap();
-        Node::new(NodeType::CompStmt(stmts))
+        Ok(Node::new(NodeType::CompStmt(stmts)))
     }
 
-    fn toplevel(&mut self) -> Option<Node> {
-        let is_typedef = self.consume(TokenType::Typedef);
-        let is_extern = self.consume(TokenType::Extern);
-
-        let mut ty = self.ctype();
-        let t = &self.tokens[self.pos];
-        let name: String;
-        if let TokenType::Ident(ref name2) = t.ty {
-            name = name2.clone();
-        } else {
-            t.bad_token("function or variable name expected");
+    // Reports a syntax error and skips to where parsing can go on. Returns
+    // the error instead if there is nothing left to skip to, or if it stops
+    // the run.
+    fn recover(&mut self, e: Diagnostic, start: usize, toplevel: bool) -> Result<()> {
+        if e.severity == Severity::Fatal || self.tokens[self.pos].ty == TokenType::Eof {
+            return Err(e);
         }
-        self.pos += 1;
+        diagnostic::report_error(e)?;
+        self.synchronize(start, toplevel);
+        Ok(())
+    }
 
-        // Function
-        if self.consume(TokenType::LeftParen) {
-            let mut args = vec![];
-            if !self.consume(TokenType::RightParen) {
-                args.push(self.param_declaration());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.param_declaration());
+    // Skips tokens after a syntax error in a statement starting at `start`
+    // up to just past the next `;`, or to the `}` closing the enclosing
+    // block. Braces on the way are skipped as a whole, and the statement
+    // ends at the `}` of a group of them. At the top level, a declaration
+    // starting a line ends the skip as well.
+    fn synchronize(&mut self, start: usize, toplevel: bool) {
+        if self.pos == start {
+            self.pos += 1;
+        }
+        let mut depth = 0;
+        loop {
+            let t = &self.tokens[self.pos];
+            match t.ty {
+                TokenType::Eof => return,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 0 => {
+                    if toplevel {
+                        self.pos += 1;
+                    }
+                    return;
                 }
-                self.expect(TokenType::RightParen);
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return;
+                    }
+                }
+                TokenType::Semicolon if depth == 0 => {
+                    self.pos += 1;
+                    return;
+                }
+                _ if toplevel && depth == 0 && t.bol && self.is_decl_start(t) => return,
+                _ => (),
             }
+            self.pos += 1;
+        }
+    }
 
-            if self.consume(TokenType::Semicolon) {
-                let mut node = Node::new(NodeType::Decl(name));
-                node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-                return Some(node);
-            }
+    fn is_decl_start(&self, t: &Token) -> bool {
+        self.is_typename(t) || t.ty == TokenType::Typedef || t.ty == TokenType::Extern
+    }
 
-            let t = &self.tokens[self.pos];
-            self.expect(TokenType::LeftBrace);
-            if is_typedef {
-                t.bad_token("typedef {} has function definition");
+    fn toplevel(&mut self) -> Result<Option<Node>> {
+        let pragmas = self.pragmas.remove(&self.pos);
+        let start = self.pos;
+        let mut node = match self.toplevel2() {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                apply_pragmas(pragmas.unwrap_or_default(), None);
+                return Ok(None);
+            }
+            Err(e) => {
+                self.enums.clear();
+                // Declarations in the scopes left are dropped.
+                while let Some(next) = self.env.next.take() {
+                    self.env = *next;
+                }
+                self.recover(e, start, true)?;
+                return Ok(None);
             }
-            let body = self.compound_stmt();
+        };
+        apply_pragmas(pragmas.unwrap_or_default(), Some(&mut node));
+        Ok(Some(node))
+    }
 
-            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0));
-            node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-            return Some(node);
+    fn toplevel2(&mut self) -> Result<Option<Node>> {
+        let is_typedef = self.consume(TokenType::Typedef);
+        let is_extern = self.consume(TokenType::Extern);
+
+        let mut ty = self.base_type()?;
+        if !is_typedef && self.consume(TokenType::Semicolon) {
+            return Ok(None);
         }
+        let mut node = self.declarator(&mut ty, false)?;
+        let name = match node.op {
+            NodeType::Vardef(ref name, _, _) => name.clone(),
+            _ => unreachable!(),
+        };
 
-        ty = self.read_array(Box::new(ty));
-        self.expect(TokenType::Semicolon);
+        // Function
+        if let Ctype::Func(_, ref params) = node.ty.ty {
+            let params = params.clone();
+            let brace = &self.tokens[self.pos];
+            if self.consume(TokenType::LeftBrace) {
+                if is_typedef {
+                    let msg = format!("typedef '{}' has a function definition", name);
+                    return Err(brace.error(Code::Syntax, msg).label(node.span, ""));
+                }
+                let body = self.compound_stmt()?;
+                let op = NodeType::Func(name, params, Box::new(body), 0);
+                return Ok(Some(Node { op, ..node }));
+            }
+        }
+        self.expect(TokenType::Semicolon)?;
 
         if is_typedef {
-            self.env.typedefs.insert(name.clone(), ty.clone());
-            return None;
+            self.env.typedefs.insert(name, *node.ty);
+            return Ok(None);
+        }
+
+        if let Ctype::Func(_, _) = node.ty.ty {
+            node.op = NodeType::Decl(name);
+            return Ok(Some(node));
         }
 
         // Global variable
-        let mut node = Node::new(NodeType::Vardef(
-            name,
-            None,
-            if is_extern {
-                Scope::Global(String::new(), 0, true)
-            } else {
-                Scope::Global(String::new(), ty.size, false)
-            },
-        ));
-        node.ty = Box::new(ty);
-        Some(node)
+        let scope = if is_extern {
+            Scope::Global(vec![], 0, true)
+        } else {
+            Scope::Global(vec![], node.ty.size, false)
+        };
+        node.op = NodeType::Vardef(name, None, scope);
+        Ok(Some(node))
+    }
+}
+
+// Returns how a punctuator is written.
+fn punctuator(ty: &TokenType) -> Option<&'static str> {
+    use self::TokenType::*;
+    Some(match ty {
+        Semicolon => ";",
+        Comma => ",",
+        Colon => ":",
+        LeftParen => "(",
+        RightParen => ")",
+        LeftBracket => "[",
+        RightBracket => "]",
+        LeftBrace => "{",
+        RightBrace => "}",
+        _ => return None,
+    })
+}
+
+// Returns how a token of the given type is written, for messages.
+fn spelling(ty: &TokenType) -> String {
+    match (punctuator(ty), ty) {
+        (Some(s), _) => format!("'{}'", s),
+        (None, TokenType::While) => "'while'".to_string(),
+        (None, _) => format!("{:?}", ty),
     }
 }
@@ -1,22 +1,40 @@
 // C preprocessor
 
-use crate::token::{tokenize, Token};
-use crate::TokenType;
+use crate::cache::{self, Dep, Entry, Key};
+use crate::diagnostic::{self, Code, Diagnostic, Result};
+use crate::parse::BUILTINS;
+use crate::source::{FileId, Span, SOURCE_MAP};
+use crate::token::{preprocess_file, tokenize_str, Token};
+use crate::{IntType, TokenType, Type};
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Vec<Token> {
-    ctx.preprocess_impl(tokens)
+pub fn preprocess(tokens: Vec<Token>, path: Rc<String>, ctx: &mut Preprocessor) -> Result<Vec<Token>> {
+    if !ctx.files.contains(&*path) {
+        ctx.files.push(path.to_string());
+    }
+    ctx.trace.push(path.to_string());
+    ctx.preprocess_impl(tokens, path)
 }
 
 #[derive(Clone)]
 #[derive(Default)]
 pub struct Env {
+    path: Rc<String>,
     input: Vec<Token>,
     output: Vec<Token>,
     pos: usize,
+    pending: Vec<Token>, // macro expansions to be rescanned, in reverse order
+    conds: Vec<CondIncl>,
+    line_file: Option<FileId>, // set by #line
+    last: Span,                // span of the last token read, for errors at the end
     next: Option<Box<Env>>,
 }
 
@@ -31,26 +49,42 @@ impl Env {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// An `#if`, `#ifdef` or `#ifndef` group being processed.
+#[derive(Debug, Clone)]
+struct CondIncl {
+    ctx: CondCtx,
+    included: bool, // true once one of its branches has been taken
+    tok: Token,
+}
+
 #[derive(Debug, Clone)]
-enum MacroType {
+pub(crate) enum MacroType {
     Objlike,
-    Funclike(Vec<String>),
+    Funclike(Vec<String>, bool), // params, is_variadic
+    Builtin,                     // __LINE__ and such, computed on use
 }
 
 #[derive(Debug, Clone)]
-struct Macro {
-    ty: MacroType,
+pub(crate) struct Macro {
+    pub ty: MacroType,
     pub tokens: Vec<Token>,
 }
 
 impl Macro {
-    fn new(ty: MacroType) -> Self {
+    pub fn new(ty: MacroType) -> Self {
         Macro { ty, tokens: vec![] }
     }
 
     fn replace_params(mut self) -> Self {
         match self.ty {
-            MacroType::Funclike(ref params) => {
+            MacroType::Funclike(ref params, _) => {
                 let mut map = HashMap::new();
                 for (i, item) in params.iter().enumerate() {
                     let name = item.clone();
@@ -63,12 +97,7 @@ impl Macro {
                         TokenType::Ident(ref name) => {
                             if let Some(n) = map.get(name) {
                                 if let Some(elem) = self.tokens.get_mut(i) {
-                                    *elem = Token::new(
-                                        TokenType::Param(*n),
-                                        0,
-                                        t.filename.clone(),
-                                        t.buf.clone(),
-                                    );
+                                    elem.ty = TokenType::Param(*n);
                                 }
                             } else {
                                 continue;
@@ -82,14 +111,14 @@ impl Macro {
                 self.tokens = self
                     .tokens
                     .into_iter()
-                    .scan(false, |is_prev_hashmark, mut t| {
-                        if *is_prev_hashmark {
-                            match t.ty {
-                                TokenType::Param(_) => t.stringize = true,
-                                _ => *is_prev_hashmark = false,
+                    .scan(None, |prev_hashmark: &mut Option<bool>, mut t| {
+                        match (prev_hashmark.take(), &t.ty) {
+                            (Some(has_space), TokenType::Param(_)) => {
+                                t.stringize = true;
+                                t.has_space = has_space;
                             }
-                        } else {
-                            *is_prev_hashmark = t.ty == TokenType::HashMark;
+                            (_, TokenType::HashMark) => *prev_hashmark = Some(t.has_space),
+                            _ => (),
                         }
                         Some(t)
                     })
@@ -120,7 +149,21 @@ impl Macro {
 
 pub struct Preprocessor {
     macros: HashMap<String, Macro>,
+    once: HashSet<PathBuf>, // files with #pragma once
+    files: Vec<String>,     // files read, in order
+    system_files: HashSet<String>,
+    counter: usize,         // next value of __COUNTER__
+    date: String,           // __DATE__ and __TIME__
+    time: String,
+    trace: Vec<String>,     // every file read, for cache entries
+    volatile: bool,         // output depends on more than the macros
     pub env: Box<Env>,
+    pub cache_dir: Option<String>, // -cache-dir
+
+    // Include search list
+    pub quote_paths: Vec<String>,  // -iquote, for "..." only
+    pub include_paths: Vec<String>, // -I
+    pub system_paths: Vec<String>,
 }
 
 impl Default for Preprocessor {
@@ -131,44 +174,203 @@ impl Default for Preprocessor {
 
 impl Preprocessor {
     pub fn new() -> Self {
-        Preprocessor {
+        let (date, time) = date_time();
+        let mut ctx = Preprocessor {
             macros: HashMap::new(),
+            once: HashSet::new(),
+            files: vec![],
+            system_files: HashSet::new(),
+            counter: 0,
+            date,
+            time,
+            trace: vec![],
+            volatile: false,
             env: Box::new(Env::new(vec![], None)),
+            cache_dir: None,
+            quote_paths: vec![],
+            include_paths: vec![],
+            system_paths: vec!["/usr/local/include".into(), "/usr/include".into()],
+        };
+        ctx.define_predefined_macros();
+        ctx
+    }
+
+    // Defines an object-like macro as `#define name body` would.
+    fn define_macro(&mut self, name: &str, body: &str) {
+        let mut m = Macro::new(MacroType::Objlike);
+        m.tokens = tokenize_str(body.into(), "<built-in>");
+        self.macros.insert(name.into(), m);
+    }
+
+    // Handles `-D name`, `-D name=value` and `-U name` given on the
+    // command line as if they were #define and #undef directives.
+    pub fn define_cmdline(&mut self, def: &str) -> Result<()> {
+        let (name, val) = def.split_once('=').unwrap_or((def, "1"));
+        self.cmdline_directive(format!("#define {} {}\n", name, val))
+    }
+
+    pub fn undef_cmdline(&mut self, name: &str) -> Result<()> {
+        self.cmdline_directive(format!("#undef {}\n", name))
+    }
+
+    // Reads a file given by `-include` as if by `#include "file"` on the
+    // command line. So the file is looked for in the current directory
+    // first, and then where quoted includes are.
+    pub fn include_cmdline(&mut self, file: &str) -> Result<Vec<Token>> {
+        let path = Rc::new("<command line>".to_string());
+        let tokens = tokenize_str(format!("#include \"{}\"\n", file), &path);
+        let tokens = self.preprocess_impl(tokens, path)?;
+        Ok(tokens.into_iter().filter(|t| t.ty != TokenType::NewLine).collect())
+    }
+
+    fn cmdline_directive(&mut self, s: String) -> Result<()> {
+        let path = Rc::new("<command line>".to_string());
+        let tokens = tokenize_str(s, &path);
+        self.preprocess_impl(tokens, path)?;
+        Ok(())
+    }
+
+    // Returns the files read so far in the order they were opened,
+    // optionally leaving out headers found in system directories.
+    pub fn files(&self, skip_system: bool) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|path| !skip_system || !self.system_files.contains(*path))
+            .cloned()
+            .collect()
+    }
+
+    // Prints all macros as #define lines for `-dM`.
+    pub fn dump_macros(&self) {
+        let mut names = self.macros.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            if let Some(s) = self.definition(name) {
+                println!("{}", s);
+            }
         }
     }
 
+    // Returns the #define directive for a macro, or None if it is
+    // built in.
+    fn definition(&self, name: &str) -> Option<String> {
+        let m = &self.macros[name];
+        let mut s = format!("#define {}", name);
+        let params = match m.ty {
+            MacroType::Builtin => return None,
+            MacroType::Objlike => vec![],
+            MacroType::Funclike(ref params, variadic) => {
+                let mut v = params.clone();
+                if variadic {
+                    *v.last_mut().unwrap() = "...".into();
+                }
+                s.push_str(&format!("({})", v.join(", ")));
+                params.clone()
+            }
+        };
+
+        for (i, t) in m.tokens.iter().enumerate() {
+            if i == 0 || t.has_space {
+                s.push(' ');
+            }
+            match t.ty {
+                TokenType::Param(val) if t.stringize => s.push_str(&format!("#{}", params[val])),
+                TokenType::Param(val) => s.push_str(&params[val]),
+                _ => s.push_str(&t.tokstr()),
+            }
+        }
+        Some(s)
+    }
+
+    fn define_predefined_macros(&mut self) {
+        let builtins = [
+            "__FILE__",
+            "__LINE__",
+            "__DATE__",
+            "__TIME__",
+            "__COUNTER__",
+            "__has_include",
+            "__has_builtin",
+        ];
+        for name in builtins {
+            self.macros.insert(name.into(), Macro::new(MacroType::Builtin));
+        }
+
+        let version = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]
+        .iter()
+        .fold(0, |acc, n| acc * 100 + n.parse::<i32>().unwrap());
+        let int_size = Type::int_ty().size;
+        let ptr_size = Type::ptr_to(Box::new(Type::char_ty())).size;
+
+        self.define_macro("__STDC__", "1");
+        self.define_macro("__STDC_VERSION__", "201112L");
+        self.define_macro("__STDC_HOSTED__", "1");
+        self.define_macro("__x86_64__", "1");
+        self.define_macro("__LP64__", "1");
+        self.define_macro("__SIZEOF_INT__", &int_size.to_string());
+        self.define_macro("__SIZEOF_POINTER__", &ptr_size.to_string());
+        self.define_macro("__OXIDE__", &version.to_string());
+    }
+
     fn next(&mut self) -> Option<Token> {
+        if let Some(t) = self.env.pending.pop() {
+            return Some(t);
+        }
         if self.eof() {
             return None;
         }
         let pos = self.env.pos;
-        let t = Some(std::mem::take(&mut self.env.input[pos]));
+        let mut t = std::mem::take(&mut self.env.input[pos]);
         self.env.pos += 1;
-        t
+        if let Some(file) = self.env.line_file {
+            t.span.file = file;
+        }
+        self.env.last = t.span;
+        Some(t)
+    }
+
+    // Returns the next token, or an error at the end of the input.
+    fn next_or(&mut self, code: Code, msg: &str) -> Result<Token> {
+        match self.next() {
+            Some(t) => Ok(t),
+            None => Err(Diagnostic::error(code, msg).at(self.env.last, None)),
+        }
     }
 
     fn eof(&self) -> bool {
-        self.env.pos == self.env.input.len()
+        self.env.pending.is_empty() && self.env.pos == self.env.input.len()
+    }
+
+    // Pushes tokens back to the input so that they are read again.
+    fn unget(&mut self, tokens: Vec<Token>) {
+        self.env.pending.extend(tokens.into_iter().rev());
     }
 
-    fn get(&mut self, ty: TokenType, msg: &str) -> Token {
-        let t = self.next().expect(msg);
+    fn get(&mut self, ty: TokenType, code: Code, msg: &str) -> Result<Token> {
+        let t = self.next_or(code, msg)?;
         if t.ty != ty {
-            t.bad_token(msg);
+            return Err(t.error(code, msg));
         }
-        t
+        Ok(t)
     }
 
-    fn ident(&mut self, msg: &str) -> String {
-        let t = self.next().expect(msg);
+    fn ident(&mut self, code: Code, msg: &str) -> Result<String> {
+        let t = self.next_or(code, msg)?;
         match t.ty {
-            TokenType::Ident(s) | TokenType::Str(s, _) => s,
-            _ => t.bad_token(msg),
+            TokenType::Ident(ref s) => Ok(s.clone()),
+            _ => Err(t.error(code, msg)),
         }
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.env.input.get(self.env.pos)
+        match self.env.pending.last() {
+            Some(t) => Some(t),
+            None => self.env.input.get(self.env.pos),
+        }
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
@@ -179,7 +381,7 @@ impl Preprocessor {
         } else {
             return false;
         }
-        self.env.pos += 1;
+        self.next();
         true
     }
 
@@ -194,16 +396,25 @@ impl Preprocessor {
         v
     }
 
-    fn read_one_arg(&mut self) -> Vec<Token> {
+    fn expect_eol(&mut self) -> Result<()> {
+        match self.next() {
+            Some(t) if t.ty != TokenType::NewLine => {
+                Err(t.error(Code::Directive, "extra token at end of directive"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Reads a macro argument. If `rest` is true, the argument extends to
+    // the closing parenthesis including commas, as for `__VA_ARGS__`.
+    fn read_one_arg(&mut self, rest: bool, start: &Token) -> Result<Vec<Token>> {
         let mut v = vec![];
-        let msg = "unclosed macro argument";
-        let start = self.peek().expect(msg).clone();
         let mut level = 0;
 
-        while !self.eof() {
-            let t = self.peek().expect(msg).clone();
-            if level == 0 && (t.ty == TokenType::RightParen || t.ty == TokenType::Comma) {
-                return v;
+        while let Some(t) = self.peek().cloned() {
+            if level == 0 && (t.ty == TokenType::RightParen || (t.ty == TokenType::Comma && !rest))
+            {
+                return Ok(v);
             }
 
             self.next();
@@ -214,169 +425,1366 @@ impl Preprocessor {
             }
             v.push(t);
         }
-        start.bad_token(msg);
+        Err(start.error(Code::Macro, "unterminated macro invocation"))
     }
 
-    fn read_args(&mut self) -> Vec<Vec<Token>> {
+    // Reads macro arguments up to and including the closing parenthesis,
+    // which is returned as well.
+    fn read_args(
+        &mut self,
+        nparams: usize,
+        variadic: bool,
+        start: &Token,
+    ) -> Result<(Vec<Vec<Token>>, Token)> {
         let mut v = vec![];
-        if self.consume(TokenType::RightParen) {
-            return v;
+        if self.peek().is_some_and(|t| t.ty == TokenType::RightParen) {
+            return Ok((v, self.next().unwrap()));
         }
-        v.push(self.read_one_arg());
-        while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            v.push(self.read_one_arg());
+        loop {
+            let rest = variadic && v.len() + 1 == nparams;
+            v.push(self.read_one_arg(rest, start)?);
+            if self.peek().is_some_and(|t| t.ty == TokenType::RightParen) {
+                return Ok((v, self.next().unwrap()));
+            }
+            self.get(TokenType::Comma, Code::Macro, "comma expected")?;
         }
-        v
     }
 
-    fn stringize(tokens: &[Token], filename: Rc<String>, buf: Rc<Vec<char>>) -> Token {
-        let mut sb = String::new();
-        for (i, t) in tokens.iter().enumerate() {
-            if i != 0 {
+    fn stringize(tokens: &[Token]) -> Token {
+        let mut sb = String::from("\"");
+        let mut space = false;
+        for t in tokens {
+            if t.ty == TokenType::NewLine {
+                space = true;
+                continue;
+            }
+            if sb.len() > 1 && (space || t.has_space) {
                 sb.push(' ');
             }
-            sb.push_str(&t.tokstr());
+            space = false;
+            for c in t.tokstr().chars() {
+                let in_literal = matches!(t.ty, TokenType::Str(_, _) | TokenType::Num(_, _));
+                if in_literal && (c == '"' || c == '\\') {
+                    sb.push('\\');
+                }
+                sb.push(c);
+            }
         }
-
-        let len = sb.len();
-        Token::new(TokenType::Str(sb, len), 0, filename, buf)
+        sb.push('"');
+        tokenize_str(sb, "<scratch space>").remove(0)
     }
 
-    fn add_special_macro(&mut self, t: &Token) -> bool {
-        if t.is_ident("__LINE__") {
-            self.env.output.push(Token::new(
-                TokenType::Num(t.get_line_number() as i32),
-                0,
-                t.filename.clone(),
-                t.buf.clone(),
-            ));
-            true
-        } else {
-            false
+    // Concatenates two tokens with the `##` operator and lexes the result
+    // again, which must be a single valid token.
+    fn paste(lhs: &Token, rhs: &Token) -> Result<Token> {
+        let buf = format!("{}{}", lhs.tokstr(), rhs.tokstr());
+        let mut tokens = tokenize_str(buf.clone(), "<scratch space>");
+        if tokens.len() != 1 || matches!(tokens[0].ty, TokenType::Invalid(_)) {
+            let msg = format!("pasting forms '{}', an invalid token", buf);
+            return Err(lhs.error(Code::Macro, msg).label(rhs.span, ""));
         }
+        let mut t = tokens.remove(0);
+        t.bol = false;
+        t.has_space = lhs.has_space;
+        Ok(t)
     }
 
-    fn apply_objlike(&mut self, tokens: Vec<Token>) {
-        for t in tokens {
-            if self.add_special_macro(&t) {
+    // Substitutes macro arguments into a replacement list and evaluates
+    // the `#` and `##` operators in it. `args` are the arguments as
+    // written and `expanded` the same arguments fully macro-expanded;
+    // the latter are used except for operands of `#` and `##`. `va` is
+    // the index of `__VA_ARGS__` in `args` if the macro is variadic.
+    fn subst(
+        tokens: Vec<Token>,
+        args: &[Vec<Token>],
+        expanded: &[Vec<Token>],
+        va: Option<usize>,
+    ) -> Result<Vec<Token>> {
+        let mut v: Vec<Token> = vec![];
+        // True if the last operand pushed to `v` was an empty argument.
+        let mut placemarker = false;
+        let mut iter = tokens.into_iter().peekable();
+
+        while let Some(t) = iter.next() {
+            if t.ty != TokenType::HashHash {
+                let pasted = iter.peek().is_some_and(|t| t.ty == TokenType::HashHash);
+                let expanded = if pasted { args } else { expanded };
+                let operand = Self::operand(t, &mut iter, args, expanded, va)?;
+                placemarker = operand.is_empty();
+                v.extend(operand);
                 continue;
-            } else {
-                self.env.output.push(t);
             }
-        }
-    }
 
-    fn apply_funclike(&mut self, tokens: Vec<Token>, params: &[String], start: &Token) {
-        self.get(TokenType::LeftParen, "comma expected");
-        let args = self.read_args();
-        if params.len() != args.len() {
-            start.bad_token("number of parameter does not match");
-        }
+            let rhs = iter.next().unwrap();
 
-        for t in tokens {
-            if self.add_special_macro(&t) {
+            // GNU extension: `, ## __VA_ARGS__` deletes the comma if the
+            // variable arguments are empty.
+            if let (TokenType::Param(val), Some(va)) = (&rhs.ty, va) {
+                let is_comma = v.last().is_some_and(|t| t.ty == TokenType::Comma);
+                if *val == va && !rhs.stringize && is_comma && !placemarker {
+                    if args[va].is_empty() {
+                        v.pop();
+                    } else {
+                        v.extend(args[va].clone());
+                    }
+                    continue;
+                }
+            }
+
+            let rhs = Self::operand(rhs, &mut iter, args, args, va)?;
+            if rhs.is_empty() {
+                continue;
+            }
+            if placemarker {
+                placemarker = false;
+                v.extend(rhs);
                 continue;
             }
+            let lhs = v.pop().unwrap();
+            v.push(Self::paste(&lhs, &rhs[0])?);
+            v.extend(rhs.into_iter().skip(1));
+        }
+        Ok(v)
+    }
 
-            match t.ty {
-                TokenType::Param(val) => {
-                    if t.stringize {
-                        self.env
-                            .output
-                            .push(Self::stringize(&args[val], t.filename, t.buf));
-                    } else {
-                        self.env.output.append(&mut args[val].clone());
+    // Returns the tokens an operand of a replacement list is replaced
+    // with. `__VA_OPT__(...)` reads its contents from `iter`.
+    fn operand(
+        t: Token,
+        iter: &mut impl Iterator<Item = Token>,
+        args: &[Vec<Token>],
+        expanded: &[Vec<Token>],
+        va: Option<usize>,
+    ) -> Result<Vec<Token>> {
+        Ok(match t.ty {
+            TokenType::Param(val) if t.stringize => {
+                let mut t2 = Self::stringize(&args[val]);
+                t2.has_space = t.has_space;
+                vec![t2]
+            }
+            TokenType::Param(val) => {
+                let mut v = expanded[val].clone();
+                if let Some(t2) = v.first_mut() {
+                    t2.has_space = t.has_space;
+                }
+                v
+            }
+            TokenType::Ident(ref name) if name == "__VA_OPT__" && va.is_some() => {
+                if iter.next().map(|t| t.ty) != Some(TokenType::LeftParen) {
+                    return Err(t.error(Code::Macro, "'(' expected after __VA_OPT__"));
+                }
+                let mut body = vec![];
+                let mut level = 0;
+                loop {
+                    let t2 = match iter.next() {
+                        Some(t2) => t2,
+                        None => return Err(t.error(Code::Macro, "unterminated __VA_OPT__")),
+                    };
+                    if t2.ty == TokenType::RightParen && level == 0 {
+                        break;
                     }
+                    if t2.ty == TokenType::LeftParen {
+                        level += 1;
+                    } else if t2.ty == TokenType::RightParen {
+                        level -= 1;
+                    }
+                    body.push(t2);
                 }
-                _ => self.env.output.push(t),
+
+                if args[va.unwrap()].is_empty() {
+                    vec![]
+                } else {
+                    Self::subst(body, args, expanded, va)?
+                }
+            }
+            _ => vec![t],
+        })
+    }
+
+    // Returns the value of a builtin macro, which depends on where it
+    // is used.
+    fn builtin_macro(&mut self, name: &str, t: &Token) -> Result<Token> {
+        let loc = t.loc();
+        let s = match name {
+            "__FILE__" => {
+                let filename = loc.filename().replace('\\', "\\\\").replace('"', "\\\"");
+                format!("\"{}\"", filename)
+            }
+            "__LINE__" => loc.get_line_number().to_string(),
+            "__DATE__" | "__TIME__" => {
+                self.volatile = true;
+                let s = if name == "__DATE__" { &self.date } else { &self.time };
+                format!("\"{}\"", s)
             }
+            "__COUNTER__" => {
+                self.counter += 1;
+                (self.counter - 1).to_string()
+            }
+            _ => return Err(t.error(Code::Macro, format!("'{}' can only be used in #if", name))),
+        };
+        let mut t2 = tokenize_str(s, "<scratch space>").remove(0);
+        t2.bol = t.bol;
+        t2.has_space = t.has_space;
+        let mut map = SOURCE_MAP.lock().unwrap();
+        t2.expansion = Some(map.add_expansion(name.into(), t.span, t.expansion));
+        Ok(t2)
+    }
+
+    // Returns the replacement of a function-like macro invocation and
+    // the hide set of its tokens before the macro name is added.
+    fn apply_funclike(
+        &mut self,
+        tokens: Vec<Token>,
+        params: &[String],
+        variadic: bool,
+        start: &Token,
+    ) -> Result<(Vec<Token>, HashSet<String>)> {
+        self.get(TokenType::LeftParen, Code::Macro, "'(' expected")?;
+        let (mut args, rparen) = self.read_args(params.len(), variadic, start)?;
+
+        // `F()` passes one empty argument, and the variable arguments
+        // may be omitted entirely.
+        if args.is_empty() && !params.is_empty() {
+            args.push(vec![]);
+        }
+        if variadic && args.len() + 1 == params.len() {
+            args.push(vec![]);
+        }
+        if params.len() != args.len() {
+            let msg = format!(
+                "macro '{}' takes {} argument{}, but {} given",
+                start.tokstr(),
+                params.len(),
+                if params.len() == 1 { "" } else { "s" },
+                args.len()
+            );
+            return Err(start.error(Code::Macro, msg).label(rparen.span, ""));
         }
+
+        let va = if variadic {
+            Some(params.len() - 1)
+        } else {
+            None
+        };
+        let expanded = args
+            .iter()
+            .map(|arg| self.expand(arg.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let hideset = start
+            .hideset
+            .intersection(&rparen.hideset)
+            .cloned()
+            .collect();
+        Ok((Self::subst(tokens, &args, &expanded, va)?, hideset))
     }
 
-    fn apply(&mut self, m: Macro, start: &Token) {
-        match m.ty {
-            MacroType::Objlike => self.apply_objlike(m.tokens),
-            MacroType::Funclike(ref params) => self.apply_funclike(m.tokens, params, start),
+    // A function-like macro name is an invocation only if the next token,
+    // possibly on a following line, is '('.
+    fn next_is_paren(&mut self) -> bool {
+        let mut newlines = vec![];
+        while let Some(t) = self.peek() {
+            if t.ty == TokenType::LeftParen {
+                return true;
+            }
+            if t.ty != TokenType::NewLine {
+                break;
+            }
+            newlines.push(self.next().unwrap());
         }
+        self.unget(newlines);
+        false
     }
 
-    fn funclike_macro(&mut self, name: String) {
+    fn funclike_macro(&mut self, name: String) -> Result<()> {
         let mut params = vec![];
-        params.push(self.ident("parameter name expected"));
+        let mut variadic = false;
         while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            params.push(self.ident("parameter name expected"));
+            if !params.is_empty() {
+                self.get(TokenType::Comma, Code::Macro, "comma expected")?;
+            }
+            if self.consume(TokenType::Ellipsis) {
+                params.push("__VA_ARGS__".into());
+                variadic = true;
+                self.get(TokenType::RightParen, Code::Macro, "')' expected")?;
+                break;
+            }
+            params.push(self.ident(Code::Macro, "parameter name expected")?);
         }
 
-        let mut m = Macro::new(MacroType::Funclike(params));
-        m.tokens = self.read_until_eol();
+        let mut m = Macro::new(MacroType::Funclike(params, variadic));
+        m.tokens = self.read_replacement_list()?;
         m = m.replace_params();
         self.macros.insert(name, m);
+        Ok(())
     }
 
-    fn objlike_macro(&mut self, name: String) {
+    fn objlike_macro(&mut self, name: String) -> Result<()> {
         let mut m = Macro::new(MacroType::Objlike);
-        m.tokens = self.read_until_eol();
+        m.tokens = self.read_replacement_list()?;
         self.macros.insert(name, m);
+        Ok(())
     }
 
-    fn define(&mut self) {
-        let name = self.ident("macro name expected");
-        if self.consume(TokenType::LeftParen) {
+    fn read_replacement_list(&mut self) -> Result<Vec<Token>> {
+        let tokens = self.read_until_eol();
+        for t in [tokens.first(), tokens.last()].into_iter().flatten() {
+            if t.ty == TokenType::HashHash {
+                let msg = "'##' cannot appear at either end of macro expansion";
+                return Err(t.error(Code::Macro, msg));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn define(&mut self) -> Result<()> {
+        let t = self.next_or(Code::Macro, "macro name expected")?;
+        let name = match t.ty {
+            TokenType::Ident(ref name) => name.clone(),
+            _ => return Err(t.error(Code::Macro, "macro name must be an identifier")),
+        };
+
+        // `#define F(x)` is function-like, `#define X (x)` is not. The
+        // token is read with `next` rather than peeked so that both spans
+        // are in the same file after a #line.
+        let t2 = self.next();
+        let paren = t2.as_ref().is_some_and(|t2| {
+            t2.ty == TokenType::LeftParen && t2.span.file == t.span.file && t2.span.lo == t.span.hi
+        });
+        if paren {
             return self.funclike_macro(name);
         }
-        self.objlike_macro(name);
+        self.unget(t2.into_iter().collect());
+        self.objlike_macro(name)
+    }
+
+    // Macro-expands a token sequence which is not part of the input,
+    // such as the expression of an `#if` directive.
+    fn expand(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>> {
+        let next = mem::take(&mut self.env);
+        self.env = Box::new(Env::new(tokens, Some(next)));
+        while let Some(t) = self.next() {
+            self.expand_macro(t)?;
+        }
+        let output = mem::take(&mut self.env.output);
+        self.env = self.env.next.take().unwrap();
+        Ok(output)
+    }
+
+    // Expands a macro by Prosser's algorithm. The replacement is pushed
+    // back to the input to be rescanned, and each of its tokens remembers
+    // in its hide set the macros it came from so that they are not
+    // expanded again.
+    fn expand_macro(&mut self, t: Token) -> Result<()> {
+        if t.is_ident("_Pragma") {
+            return self.pragma_operator(t);
+        }
+
+        let (name, m) = match t.ty {
+            TokenType::Ident(ref name) if !t.hideset.contains(name) => {
+                match self.macros.get(name) {
+                    Some(m) => (name.clone(), m.clone()),
+                    None => {
+                        self.env.output.push(t);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => {
+                self.env.output.push(t);
+                return Ok(());
+            }
+        };
+
+        let (tokens, mut hideset) = match m.ty {
+            MacroType::Builtin => {
+                let t2 = self.builtin_macro(&name, &t)?;
+                self.env.output.push(t2);
+                return Ok(());
+            }
+            MacroType::Objlike => (Self::subst(m.tokens, &[], &[], None)?, t.hideset.clone()),
+            MacroType::Funclike(ref params, variadic) => {
+                if !self.next_is_paren() {
+                    self.env.output.push(t);
+                    return Ok(());
+                }
+                self.apply_funclike(m.tokens, params, variadic, &t)?
+            }
+        };
+        let mut map = SOURCE_MAP.lock().unwrap();
+        let expansion = map.add_expansion(name.clone(), t.span, t.expansion);
+        drop(map);
+        hideset.insert(name);
+
+        let mut tokens = tokens
+            .into_iter()
+            .map(|mut t| {
+                t.hideset.extend(hideset.iter().cloned());
+                t.expansion = Some(expansion);
+                t.bol = false;
+                t
+            })
+            .collect::<Vec<_>>();
+        if let Some(t2) = tokens.first_mut() {
+            t2.has_space = t.has_space;
+        }
+        self.unget(tokens);
+        Ok(())
+    }
+
+    // `_Pragma("...")` is the same as `#pragma ...`.
+    fn pragma_operator(&mut self, t: Token) -> Result<()> {
+        self.get(TokenType::LeftParen, Code::Macro, "'(' expected after '_Pragma'")?;
+        let s = self.next_or(Code::Macro, "string literal expected")?;
+        if !matches!(s.ty, TokenType::Str(_, _)) {
+            return Err(s.error(Code::Macro, "string literal expected"));
+        }
+        self.get(TokenType::RightParen, Code::Macro, "')' expected")?;
+
+        let literal = s.tokstr();
+        let literal = literal.strip_prefix('L').unwrap_or(&literal);
+        let text = literal[1..literal.len() - 1]
+            .replace("\\\"", "\"")
+            .replace("\\\\", "\\");
+        let mut tokens = tokenize_str(text, "<scratch space>");
+        let mut map = SOURCE_MAP.lock().unwrap();
+        let expansion = map.add_expansion("_Pragma".into(), t.span, t.expansion);
+        drop(map);
+        for t in &mut tokens {
+            t.expansion = Some(expansion);
+        }
+        self.pragma(&t, tokens);
+        Ok(())
+    }
+
+    // Replaces `defined(X)` and `defined X` with 1 or 0.
+    fn read_defined(&self, tokens: Vec<Token>) -> Result<Vec<Token>> {
+        let mut v = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            let t = &tokens[i];
+            if !t.is_ident("defined") {
+                v.push(t.clone());
+                i += 1;
+                continue;
+            }
+
+            let has_paren = tokens
+                .get(i + 1)
+                .is_some_and(|t| t.ty == TokenType::LeftParen);
+            i += if has_paren { 2 } else { 1 };
+            let name = match tokens.get(i).map(|t| &t.ty) {
+                Some(TokenType::Ident(name)) => name,
+                _ => return Err(t.error(Code::IfExpr, "macro name must be an identifier")),
+            };
+            i += 1;
+            if has_paren {
+                if tokens.get(i).map(|t| &t.ty) != Some(&TokenType::RightParen) {
+                    return Err(t.error(Code::IfExpr, "')' expected after 'defined'"));
+                }
+                i += 1;
+            }
+
+            let val = self.macros.contains_key(name) as i64;
+            v.push(Token::new(TokenType::Num(val, IntType::Int), t.span));
+        }
+        Ok(v)
+    }
+
+    // Replaces `__has_include("x")`, `__has_include(<x>)` and
+    // `__has_builtin(x)` with 1 or 0.
+    fn read_has_feature(&self, tokens: Vec<Token>) -> Result<Vec<Token>> {
+        let mut v = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            let t = &tokens[i];
+            if !t.is_ident("__has_include") && !t.is_ident("__has_builtin") {
+                v.push(t.clone());
+                i += 1;
+                continue;
+            }
+
+            if tokens.get(i + 1).map(|t| &t.ty) != Some(&TokenType::LeftParen) {
+                let msg = format!("'(' expected after '{}'", t.tokstr());
+                return Err(t.error(Code::IfExpr, msg));
+            }
+            let len = match tokens[i + 2..]
+                .iter()
+                .position(|t| t.ty == TokenType::RightParen)
+            {
+                Some(len) => len,
+                None => {
+                    let msg = format!("')' expected after '{}'", t.tokstr());
+                    return Err(t.error(Code::IfExpr, msg));
+                }
+            };
+            let args = &tokens[i + 2..i + 2 + len];
+            i += len + 3;
+
+            let val = if t.is_ident("__has_builtin") {
+                match args {
+                    [arg] if matches!(arg.ty, TokenType::Ident(_)) => {
+                        BUILTINS.contains(&&*arg.tokstr())
+                    }
+                    _ => return Err(t.error(Code::IfExpr, "builtin name expected")),
+                }
+            } else {
+                let (name, quoted) = match args {
+                    [arg] if matches!(arg.ty, TokenType::Str(_, _)) => {
+                        let s = arg.tokstr();
+                        (s[1..s.len() - 1].to_string(), true)
+                    }
+                    [lt, name @ .., gt]
+                        if lt.ty == TokenType::LeftAngleBracket
+                            && gt.ty == TokenType::RightAngleBracket =>
+                    {
+                        let mut s = String::new();
+                        for (j, t) in name.iter().enumerate() {
+                            if j > 0 && t.has_space {
+                                s.push(' ');
+                            }
+                            s.push_str(&t.tokstr());
+                        }
+                        (s, false)
+                    }
+                    _ => {
+                        let msg = "expected \"FILENAME\" or <FILENAME>";
+                        return Err(t.error(Code::IfExpr, msg));
+                    }
+                };
+                self.search_include(&name, quoted).is_some()
+            };
+
+            let mut t2 = t.clone();
+            t2.ty = TokenType::Num(val as i64, IntType::Int);
+            v.push(t2);
+        }
+        Ok(v)
+    }
+
+    fn read_const_expr(&mut self, start: &Token) -> Result<bool> {
+        let tokens = self.read_until_eol();
+        let tokens = self.read_defined(tokens)?;
+        // The result of __has_include is not covered by the token cache.
+        if tokens.iter().any(|t| t.is_ident("__has_include")) {
+            self.volatile = true;
+        }
+        let tokens = self.read_has_feature(tokens)?;
+        let tokens = self.expand(tokens)?;
+        if tokens.is_empty() {
+            let msg = format!("#{} with no expression", start.tokstr());
+            return Err(start.error(Code::IfExpr, msg));
+        }
+        Ok(ConstExpr::new(&tokens).eval()? != 0)
+    }
+
+    fn push_cond(&mut self, tok: Token, included: bool) {
+        self.env.conds.push(CondIncl {
+            ctx: CondCtx::Then,
+            included,
+            tok,
+        });
+        if !included {
+            self.skip_cond_incl();
+        }
+    }
+
+    fn is_directive(&self, pos: usize, names: &[&str]) -> bool {
+        match (self.env.input.get(pos), self.env.input.get(pos + 1)) {
+            (Some(hash), Some(name)) if hash.ty == TokenType::HashMark && hash.bol => {
+                names.contains(&&*name.tokstr())
+            }
+            _ => false,
+        }
+    }
+
+    // Skips tokens until the next `#elif`, `#else` or `#endif` of the
+    // current group. Nested groups are skipped entirely, and tokens in
+    // skipped groups are never looked at otherwise, so they may contain
+    // lexical errors.
+    fn skip_cond_incl(&mut self) {
+        let mut depth = 0;
+        while !self.eof() {
+            let pos = self.env.pos;
+            if self.is_directive(pos, &["if", "ifdef", "ifndef"]) {
+                depth += 1;
+            } else if self.is_directive(pos, &["elif", "else"]) && depth == 0 {
+                return;
+            } else if self.is_directive(pos, &["endif"]) {
+                if depth == 0 {
+                    return;
+                }
+                depth -= 1;
+            }
+            self.env.pos += 1;
+        }
     }
 
-    fn include(&mut self) {
-        let path = self.ident("string expected");
-        let t = self.next().expect("newline expected");
-        if t.ty != TokenType::NewLine {
-            t.bad_token("newline expected");
+    // Reads the operand of `#include`. It is either a "..." or <...>
+    // header name, or tokens which expand to one. Returns the name, true
+    // for the "..." form, and the token to report errors at.
+    fn read_header_name(&mut self, start: &Token) -> Result<(String, bool, Token)> {
+        let tokens = self.read_until_eol();
+        let tokens = match tokens.first().map(|t| &t.ty) {
+            Some(TokenType::Str(_, _)) | Some(TokenType::HeaderName(_)) => tokens,
+            _ => self.expand(tokens)?,
+        };
+        let msg = "#include expects \"FILENAME\" or <FILENAME>";
+        let t = match tokens.first() {
+            Some(t) => t.clone(),
+            None => return Err(start.error(Code::Directive, msg)),
+        };
+
+        Ok(match t.ty {
+            // Escape sequences are not processed in header names.
+            TokenType::Str(_, _) if tokens.len() == 1 => {
+                let s = t.tokstr();
+                (s[1..s.len() - 1].to_string(), true, t)
+            }
+            TokenType::HeaderName(ref name) if tokens.len() == 1 => (name.clone(), false, t),
+            TokenType::LeftAngleBracket => {
+                match tokens.iter().position(|t| t.ty == TokenType::RightAngleBracket) {
+                    Some(end) if end == tokens.len() - 1 => {
+                        let name = tokens[1..end].iter().map(|t| t.tokstr()).collect();
+                        (name, false, t)
+                    }
+                    _ => return Err(t.error(Code::Directive, msg)),
+                }
+            }
+            _ => return Err(t.error(Code::Directive, msg)),
+        })
+    }
+
+    // Returns the path of an included file and whether it is found in a
+    // system directory. "..." is searched for in the directory of the
+    // current file first.
+    fn search_include(&self, name: &str, quoted: bool) -> Option<(String, bool)> {
+        if Path::new(name).is_absolute() {
+            return Path::new(name).is_file().then(|| (name.to_string(), false));
+        }
+
+        let mut dirs = vec![];
+        if quoted {
+            let dir = Path::new(&*self.env.path).parent().unwrap_or(Path::new(""));
+            dirs.push((dir.to_path_buf(), false));
+            dirs.extend(self.quote_paths.iter().map(|dir| (PathBuf::from(dir), false)));
+        }
+        dirs.extend(self.include_paths.iter().map(|dir| (PathBuf::from(dir), false)));
+        dirs.extend(self.system_paths.iter().map(|dir| (PathBuf::from(dir), true)));
+
+        dirs.into_iter()
+            .map(|(dir, system)| (dir.join(name), system))
+            .find(|(path, _)| path.is_file())
+            .map(|(path, system)| (path.to_string_lossy().into_owned(), system))
+    }
+
+    fn include(&mut self, start: &Token) -> Result<()> {
+        let (name, quoted, t) = self.read_header_name(start)?;
+        let path = match self.search_include(&name, quoted) {
+            Some((path, system)) => {
+                if system {
+                    self.system_files.insert(path.clone());
+                }
+                path
+            }
+            None => {
+                let msg = format!("'{}' file not found", name);
+                return Err(t.error(Code::FileNotFound, msg));
+            }
+        };
+        if fs::canonicalize(&path).is_ok_and(|path| self.once.contains(&path)) {
+            return Ok(());
+        }
+        if self.cache_dir.is_some() {
+            return self.include_cached(path, start.span);
         }
-        let mut v = tokenize(path, self);
+        let mut v = preprocess_file(path, Some(start.span), self)?;
         self.env.output.append(&mut v);
+        Ok(())
+    }
+
+    // Returns the key of the cache entry for a header included in the
+    // current state, or None if the header cannot be cached.
+    fn cache_key(&self, path: &str) -> Option<Key> {
+        let mut key = Key::default();
+        key.str(path);
+        key.str(&fs::canonicalize(path).ok()?.to_string_lossy());
+        let (secs, nanos) = cache::mtime(path)?;
+        key.u64(secs);
+        key.u64(nanos as u64);
+        key.str(&env::current_dir().ok()?.to_string_lossy());
+        for dirs in [&self.quote_paths, &self.include_paths, &self.system_paths] {
+            key.u64(dirs.len() as u64);
+            for dir in dirs {
+                key.str(dir);
+            }
+        }
+
+        let mut once = self.once.iter().collect::<Vec<_>>();
+        once.sort();
+        key.u64(once.len() as u64);
+        for path in once {
+            key.str(&path.to_string_lossy());
+        }
+        let mut names = self.macros.keys().collect::<Vec<_>>();
+        names.sort();
+        key.u64(names.len() as u64);
+        for name in names {
+            key.str(name);
+            let def = self.definition(name);
+            key.bool(def.is_some());
+            key.str(&def.unwrap_or_default());
+        }
+        Some(key)
+    }
+
+    // Includes a header using the token cache. A header is saved unless
+    // its result depends on something other than the files it reads and
+    // the macros, such as __COUNTER__ or __DATE__.
+    fn include_cached(&mut self, path: String, site: Span) -> Result<()> {
+        let dir = self.cache_dir.clone().unwrap();
+        let key = self.cache_key(&path);
+        if let Some(entry) = key.as_ref().and_then(|key| cache::load(&dir, key, site)) {
+            for dep in entry.deps {
+                if dep.system {
+                    self.system_files.insert(dep.path.clone());
+                }
+                if !self.files.contains(&dep.path) {
+                    self.files.push(dep.path.clone());
+                }
+                self.trace.push(dep.path);
+            }
+            for (name, m) in entry.macros {
+                match m {
+                    Some(m) => self.macros.insert(name, m),
+                    None => self.macros.remove(&name),
+                };
+            }
+            self.once.extend(entry.once);
+            self.env.output.extend(entry.tokens);
+            return Ok(());
+        }
+
+        let defs = self
+            .macros
+            .keys()
+            .map(|name| (name.clone(), self.definition(name)))
+            .collect::<HashMap<_, _>>();
+        let once = self.once.clone();
+        let trace = self.trace.len();
+        let counter = self.counter;
+        let volatile = mem::replace(&mut self.volatile, false);
+
+        let tokens = preprocess_file(path, Some(site), self)?;
+
+        if let (Some(key), false) = (key, self.volatile || self.counter != counter) {
+            let deps = self.trace[trace..]
+                .iter()
+                .map(|path| Dep::new(path, self.system_files.contains(path)))
+                .collect::<Option<Vec<_>>>();
+
+            let mut changed = vec![];
+            for name in self.macros.keys() {
+                if defs.get(name) != Some(&self.definition(name)) {
+                    changed.push((name.clone(), Some(self.macros[name].clone())));
+                }
+            }
+            for name in defs.keys() {
+                if !self.macros.contains_key(name) {
+                    changed.push((name.clone(), None));
+                }
+            }
+
+            if let Some(deps) = deps {
+                let entry = Entry {
+                    deps,
+                    tokens: tokens.clone(),
+                    macros: changed,
+                    once: self.once.difference(&once).cloned().collect(),
+                };
+                cache::store(&dir, &key, &entry, site);
+            }
+        }
+        self.volatile |= volatile;
+        self.env.output.extend(tokens);
+        Ok(())
+    }
+
+    // Reads the rest of a directive and returns its spelling.
+    fn read_message(&mut self) -> String {
+        let tokens = self.read_until_eol();
+        let v = tokens.iter().map(|t| t.tokstr()).collect::<Vec<_>>();
+        v.join(" ")
+    }
+
+    // Handles `#line 10 "foo.c"` and the GNU `# 10 "foo.c"` form. The
+    // line following the directive gets the given line number.
+    fn line_directive(&mut self, t: &Token) -> Result<()> {
+        let tokens = self.read_until_eol();
+        let tokens = self.expand(tokens)?;
+        let line = match tokens.first().map(|t| &t.ty) {
+            Some(TokenType::Num(val, _)) if *val > 0 => *val as isize,
+            _ => {
+                let msg = "#line directive requires a positive integer argument";
+                return Err(t.error(Code::Directive, msg));
+            }
+        };
+        let filename = match tokens.get(1) {
+            Some(t2) if matches!(t2.ty, TokenType::Str(_, _)) => Some(t2.str_value().unwrap()),
+            Some(t2) => return Err(t2.error(Code::Directive, "invalid filename")),
+            None => None,
+        };
+
+        let mut map = SOURCE_MAP.lock().unwrap();
+        let f = map.file(t.span.file);
+        let name = filename.unwrap_or_else(|| f.name.clone());
+        let delta = f.line_delta + line - (map.lookup(t.span).line as isize + 1);
+        self.env.line_file = Some(map.add_alias(t.span.file, name, delta));
+        Ok(())
     }
 
-    fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+    // Handles the tokens of `#pragma` or `_Pragma("...")`.
+    fn pragma(&mut self, t: &Token, tokens: Vec<Token>) {
+        if tokens.len() == 1 && tokens[0].is_ident("once") {
+            if let Ok(path) = fs::canonicalize(&*self.env.path) {
+                self.once.insert(path);
+            }
+            return;
+        }
+
+        // Other pragmas are passed to the parser as a single token
+        // spanning the text after `pragma`.
+        let (first, last) = match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return,
+        };
+        let mut t2 = first.clone();
+        t2.ty = TokenType::Pragma;
+        t2.span = first.span.to(last.span);
+        t2.bol = t.bol;
+        self.env.output.push(t2);
+    }
+
+    fn preprocess_impl(&mut self, tokens: Vec<Token>, path: Rc<String>) -> Result<Vec<Token>> {
         self.env = Box::new(Env::new(tokens, Some(self.env.clone())));
+        self.env.path = path;
 
         while !self.eof() {
             let t = self.next().unwrap();
-            let macro_name;
-            if let TokenType::Ident(ref name) = t.ty {
-                macro_name = Some(name.clone());
-            } else {
-                macro_name = None;
+            if t.ty != TokenType::HashMark || !t.bol {
+                self.expand_macro(t)?;
+                continue;
             }
-            if let Some(name) = macro_name {
-                if let Some(m) = self.macros.get(&name).cloned() {
-                    self.apply(m, &t);
-                } else {
-                    self.env.output.push(t);
-                }
+
+            // Null directive
+            if self.consume(TokenType::NewLine) {
                 continue;
             }
 
-            if t.ty != TokenType::HashMark {
-                self.env.output.push(t);
+            let ident = self.next().unwrap();
+            if let TokenType::Num(_, _) = ident.ty {
+                self.unget(vec![ident]);
+                self.line_directive(&t)?;
                 continue;
             }
 
-            let ident = self.ident("identifier expected");
-            if &*ident == "define" {
-                self.define();
-            } else if &*ident == "include" {
-                self.include();
+            let name = ident.tokstr();
+            if name == "define" {
+                self.define()?;
+            } else if name == "undef" {
+                let name = self.ident(Code::Macro, "macro name must be an identifier")?;
+                self.expect_eol()?;
+                self.macros.remove(&name);
+            } else if name == "include" {
+                self.include(&ident)?;
+            } else if name == "if" {
+                let val = self.read_const_expr(&ident)?;
+                self.push_cond(t, val);
+            } else if name == "ifdef" {
+                let name = self.ident(Code::Macro, "macro name must be an identifier")?;
+                self.expect_eol()?;
+                let defined = self.macros.contains_key(&name);
+                self.push_cond(t, defined);
+            } else if name == "ifndef" {
+                let name = self.ident(Code::Macro, "macro name must be an identifier")?;
+                self.expect_eol()?;
+                let defined = self.macros.contains_key(&name);
+                self.push_cond(t, !defined);
+            } else if name == "elif" {
+                match self.env.conds.last() {
+                    Some(cond) if cond.ctx != CondCtx::Else => (),
+                    _ => return Err(t.error(Code::Directive, "#elif without #if")),
+                }
+                let included = self.env.conds.last().unwrap().included;
+                let cond = self.env.conds.len() - 1;
+                self.env.conds[cond].ctx = CondCtx::Elif;
+                if !included && self.read_const_expr(&ident)? {
+                    self.env.conds[cond].included = true;
+                } else {
+                    self.read_until_eol();
+                    self.skip_cond_incl();
+                }
+            } else if name == "else" {
+                let cond = match self.env.conds.last_mut() {
+                    Some(cond) if cond.ctx != CondCtx::Else => cond,
+                    _ => return Err(t.error(Code::Directive, "#else without #if")),
+                };
+                cond.ctx = CondCtx::Else;
+                let included = cond.included;
+                self.expect_eol()?;
+                if included {
+                    self.skip_cond_incl();
+                }
+            } else if name == "endif" {
+                if self.env.conds.pop().is_none() {
+                    return Err(t.error(Code::Directive, "#endif without #if"));
+                }
+                self.expect_eol()?;
+            } else if name == "error" {
+                let msg = self.read_message();
+                return Err(t.error(Code::ErrorDirective, msg));
+            } else if name == "warning" {
+                let msg = self.read_message();
+                diagnostic::report(t.warning(Code::WarningDirective, msg));
+                self.volatile = true; // so that the warning is not lost
+
+            } else if name == "line" {
+                self.line_directive(&ident)?;
+            } else if name == "pragma" {
+                let tokens = self.read_until_eol();
+                self.pragma(&t, tokens);
             } else {
-                t.bad_token("unknown directive");
+                let msg = format!("invalid preprocessing directive #{}", name);
+                return Err(ident.error(Code::Directive, msg));
             }
         }
 
+        if let Some(cond) = self.env.conds.last() {
+            return Err(cond.tok.error(Code::Directive, "unterminated conditional directive"));
+        }
+
         let mut output = vec![];
         mem::swap(&mut self.env.output, &mut output);
         self.env = self.env.next.take().unwrap();
-        output
+        Ok(output)
+    }
+}
+
+// Prints preprocessed tokens as text for `-E`. A token is printed on the
+// line it was written on, or where the macro it came from was used, and
+// a `# line "file"` marker is printed where the file changes.
+pub fn print_tokens(tokens: &[Token]) {
+    let mut out = String::new();
+    let mut file: Option<String> = None;
+    let mut line = 0;
+    let mut prev: Option<&Token> = None;
+
+    for t in tokens {
+        let loc = t.loc();
+        let l = loc.get_line_number();
+        let filename = loc.filename();
+
+        if file.as_ref() != Some(&filename) || l < line || l > line + 8 {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("# {} \"{}\"\n", l, filename));
+            file = Some(filename);
+            line = l;
+            out.push_str(&indentation(&loc));
+        } else if l > line {
+            out.push_str(&"\n".repeat(l - line));
+            line = l;
+            out.push_str(&indentation(&loc));
+        } else if t.has_space || prev.is_some_and(|prev| needs_space(prev, t)) {
+            out.push(' ');
+        }
+
+        // A pragma takes a line by itself.
+        if t.ty == TokenType::Pragma {
+            if !out.trim_end_matches(' ').ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&format!("#pragma {}\n", t.tokstr()));
+            line += 1;
+            prev = None;
+            continue;
+        }
+        out.push_str(&t.tokstr());
+        prev = Some(t);
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    print!("{}", out);
+}
+
+// Returns the spaces before a token at the beginning of a line.
+fn indentation(t: &Token) -> String {
+    let map = SOURCE_MAP.lock().unwrap();
+    let f = map.file(t.span.file);
+    let bol = f.line_start(f.line_index(t.span.lo));
+    let s = &f.src[bol as usize..t.span.lo as usize];
+    if s.chars().all(|c| c.is_whitespace()) {
+        s.to_string()
+    } else {
+        String::new()
+    }
+}
+
+// True if two adjacent tokens would be read as one token when printed
+// without a space in between, such as `+` and `+`.
+fn needs_space(prev: &Token, t: &Token) -> bool {
+    let (a, b) = match (prev.tokstr().chars().last(), t.tokstr().chars().next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return false,
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let is_punct = |c: char| "+-*/%&|^<>=!.#:".contains(c);
+    (is_word(a) && is_word(b)) || (is_punct(a) && is_punct(b))
+}
+
+// Returns the values of __DATE__ and __TIME__, such as "Jan  1 2000" and
+// "12:34:56", in UTC. SOURCE_DATE_EPOCH overrides the current time for
+// reproducible builds.
+fn date_time() -> (String, String) {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH);
+            now.map_or(0, |d| d.as_secs() as i64)
+        });
+    let days = secs.div_euclid(86400);
+    let secs = secs.rem_euclid(86400);
+
+    // Converts days since 1970-01-01 to a civil date.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    let months = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let date = format!("{} {:2} {}", months[month as usize - 1], day, year);
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    (date, time)
+}
+
+// Evaluator for the constant expressions of `#if` and `#elif`.
+// Identifiers remaining after macro expansion evaluate to 0.
+struct ConstExpr<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    unevaluated: usize, // > 0 inside the skipped operand of `&&`, `||` or `?:`
+}
+
+// A value of an `#if` expression. Signed and unsigned integers act as
+// intmax_t and uintmax_t, so only the signedness needs to be kept.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    val: i64,
+    is_unsigned: bool,
+}
+
+impl Value {
+    fn int(val: i64) -> Self {
+        Value {
+            val,
+            is_unsigned: false,
+        }
+    }
+
+    fn with(self, val: i64) -> Self {
+        Value { val, ..self }
+    }
+
+    // Applies the usual arithmetic conversions to the operands of a
+    // binary operator. The values are unchanged, as both types are 64
+    // bits wide; the result is whether the operation is unsigned.
+    fn conv(lhs: Value, rhs: Value) -> bool {
+        lhs.is_unsigned || rhs.is_unsigned
+    }
+}
+
+impl<'a> ConstExpr<'a> {
+    fn new(tokens: &'a [Token]) -> Self {
+        ConstExpr {
+            tokens,
+            pos: 0,
+            unevaluated: 0,
+        }
+    }
+
+    fn eval(mut self) -> Result<i64> {
+        let val = self.conditional()?;
+        if let Some(t) = self.tokens.get(self.pos) {
+            return Err(t.error(Code::IfExpr, "extra token in expression"));
+        }
+        Ok(val.val)
+    }
+
+    fn consume(&mut self, ty: TokenType) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(t) if t.ty == ty => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, ty: TokenType) -> Result<()> {
+        if !self.consume(ty.clone()) {
+            return Err(self.error(&format!("{:?} expected", ty)));
+        }
+        Ok(())
+    }
+
+    fn error(&self, msg: &str) -> Diagnostic {
+        match self.tokens.get(self.pos) {
+            Some(t) => t.error(Code::IfExpr, msg),
+            None => self.tokens[self.pos - 1].error(Code::IfExpr, msg),
+        }
+    }
+
+    fn conditional(&mut self) -> Result<Value> {
+        let cond = self.logor()?;
+        if !self.consume(TokenType::Question) {
+            return Ok(cond);
+        }
+
+        let cond = cond.val;
+        self.unevaluated += (cond == 0) as usize;
+        let then = self.conditional()?;
+        self.unevaluated -= (cond == 0) as usize;
+        self.expect(TokenType::Colon)?;
+        self.unevaluated += (cond != 0) as usize;
+        let els = self.conditional()?;
+        self.unevaluated -= (cond != 0) as usize;
+        let val = if cond != 0 { then.val } else { els.val };
+        Ok(Value {
+            val,
+            is_unsigned: Value::conv(then, els),
+        })
+    }
+
+    fn logor(&mut self) -> Result<Value> {
+        let mut lhs = self.logand()?;
+        while self.consume(TokenType::Logor) {
+            self.unevaluated += (lhs.val != 0) as usize;
+            let rhs = self.logand()?;
+            self.unevaluated -= (lhs.val != 0) as usize;
+            lhs = Value::int((lhs.val != 0 || rhs.val != 0) as i64);
+        }
+        Ok(lhs)
+    }
+
+    fn logand(&mut self) -> Result<Value> {
+        let mut lhs = self.bit_or()?;
+        while self.consume(TokenType::Logand) {
+            self.unevaluated += (lhs.val == 0) as usize;
+            let rhs = self.bit_or()?;
+            self.unevaluated -= (lhs.val == 0) as usize;
+            lhs = Value::int((lhs.val != 0 && rhs.val != 0) as i64);
+        }
+        Ok(lhs)
+    }
+
+    // Applies the usual arithmetic conversions and an operation on the
+    // converted values.
+    fn binop(lhs: Value, rhs: Value, f: impl Fn(i64, i64) -> i64) -> Value {
+        Value {
+            val: f(lhs.val, rhs.val),
+            is_unsigned: Value::conv(lhs, rhs),
+        }
+    }
+
+    fn bit_or(&mut self) -> Result<Value> {
+        let mut lhs = self.bit_xor()?;
+        while self.consume(TokenType::VerticalBar) {
+            lhs = Self::binop(lhs, self.bit_xor()?, |l, r| l | r);
+        }
+        Ok(lhs)
+    }
+
+    fn bit_xor(&mut self) -> Result<Value> {
+        let mut lhs = self.bit_and()?;
+        while self.consume(TokenType::Hat) {
+            lhs = Self::binop(lhs, self.bit_and()?, |l, r| l ^ r);
+        }
+        Ok(lhs)
+    }
+
+    fn bit_and(&mut self) -> Result<Value> {
+        let mut lhs = self.equality()?;
+        while self.consume(TokenType::And) {
+            lhs = Self::binop(lhs, self.equality()?, |l, r| l & r);
+        }
+        Ok(lhs)
+    }
+
+    fn equality(&mut self) -> Result<Value> {
+        let mut lhs = self.relational()?;
+        loop {
+            if self.consume(TokenType::EQ) {
+                lhs = Value::int((lhs.val == self.relational()?.val) as i64);
+            } else if self.consume(TokenType::NE) {
+                lhs = Value::int((lhs.val != self.relational()?.val) as i64);
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    // Compares values after the usual arithmetic conversions, so that
+    // `-1 < 0u` is false.
+    fn compare(lhs: Value, rhs: Value) -> Ordering {
+        if Value::conv(lhs, rhs) {
+            (lhs.val as u64).cmp(&(rhs.val as u64))
+        } else {
+            lhs.val.cmp(&rhs.val)
+        }
+    }
+
+    fn relational(&mut self) -> Result<Value> {
+        let mut lhs = self.shift()?;
+        loop {
+            let op = match self.tokens.get(self.pos).map(|t| &t.ty) {
+                Some(TokenType::LeftAngleBracket) => Ordering::is_lt,
+                Some(TokenType::RightAngleBracket) => Ordering::is_gt,
+                Some(TokenType::LE) => Ordering::is_le,
+                Some(TokenType::GE) => Ordering::is_ge,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.shift()?;
+            lhs = Value::int(op(Self::compare(lhs, rhs)) as i64);
+        }
+    }
+
+    // The result of a shift has the type of the left operand.
+    fn shift(&mut self) -> Result<Value> {
+        let mut lhs = self.add()?;
+        loop {
+            if self.consume(TokenType::SHL) {
+                lhs = lhs.with(lhs.val.wrapping_shl(self.add()?.val as u32));
+            } else if self.consume(TokenType::SHR) {
+                let rhs = self.add()?.val as u32;
+                if lhs.is_unsigned {
+                    lhs = lhs.with((lhs.val as u64).wrapping_shr(rhs) as i64);
+                } else {
+                    lhs = lhs.with(lhs.val.wrapping_shr(rhs));
+                }
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn add(&mut self) -> Result<Value> {
+        let mut lhs = self.mul()?;
+        loop {
+            if self.consume(TokenType::Plus) {
+                lhs = Self::binop(lhs, self.mul()?, i64::wrapping_add);
+            } else if self.consume(TokenType::Minus) {
+                lhs = Self::binop(lhs, self.mul()?, i64::wrapping_sub);
+            } else {
+                return Ok(lhs);
+            }
+        }
+    }
+
+    fn mul(&mut self) -> Result<Value> {
+        let mut lhs = self.unary()?;
+        loop {
+            if self.consume(TokenType::Mul) {
+                lhs = Self::binop(lhs, self.unary()?, i64::wrapping_mul);
+                continue;
+            }
+
+            let is_div = self.consume(TokenType::Div);
+            if !is_div && !self.consume(TokenType::Mod) {
+                return Ok(lhs);
+            }
+            let rhs = self.unary()?;
+            let is_unsigned = Value::conv(lhs, rhs);
+            let (l, r) = (lhs.val, rhs.val);
+            let val = if r == 0 {
+                if self.unevaluated == 0 {
+                    return Err(self.tokens[self.pos - 1].error(Code::IfExpr, "division by zero"));
+                }
+                0
+            } else if is_unsigned && is_div {
+                (l as u64 / r as u64) as i64
+            } else if is_unsigned {
+                (l as u64 % r as u64) as i64
+            } else if is_div {
+                l.wrapping_div(r)
+            } else {
+                l.wrapping_rem(r)
+            };
+            lhs = Value { val, is_unsigned };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value> {
+        if self.consume(TokenType::Plus) {
+            return self.unary();
+        }
+        if self.consume(TokenType::Minus) {
+            let val = self.unary()?;
+            return Ok(val.with(val.val.wrapping_neg()));
+        }
+        if self.consume(TokenType::Exclamation) {
+            return Ok(Value::int((self.unary()?.val == 0) as i64));
+        }
+        if self.consume(TokenType::Tilde) {
+            let val = self.unary()?;
+            return Ok(val.with(!val.val));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Value> {
+        if self.consume(TokenType::LeftParen) {
+            let val = self.conditional()?;
+            self.expect(TokenType::RightParen)?;
+            return Ok(val);
+        }
+
+        let t = match self.tokens.get(self.pos) {
+            Some(t) => t,
+            None => return Err(self.error("expression expected")),
+        };
+        self.pos += 1;
+        match t.ty {
+            // Character constants such as u'x' promote to intmax_t.
+            TokenType::Num(val, ty) => Ok(Value {
+                val,
+                is_unsigned: matches!(ty, IntType::UInt | IntType::ULong),
+            }),
+            TokenType::FNum(_, _) => {
+                Err(t.error(Code::IfExpr, "floating constant in preprocessor expression"))
+            }
+            // Keywords are identifiers to the preprocessor.
+            _ if t
+                .tokstr()
+                .starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+            {
+                Ok(Value::int(0))
+            }
+            _ => Err(t.error(Code::IfExpr, "invalid token in expression")),
+        }
     }
 }
@@ -19,7 +19,7 @@ use std::sync::Mutex;
 
 lazy_static! {
     static ref USED: Mutex<[bool; REGS_N]> = Mutex::new([false; REGS_N]);
-    static ref REG_MAP: Mutex<[Option<usize>; 8192]> = Mutex::new([None; 8192]);
+    static ref REG_MAP: Mutex<Vec<Option<usize>>> = Mutex::new(vec![]);
 }
 
 fn used_get(i: usize) -> bool {
@@ -31,18 +31,18 @@ fn used_set(i: usize, val: bool) {
 }
 
 fn reg_map_get(i: usize) -> Option<usize> {
-    REG_MAP.lock().unwrap().get(i).cloned().unwrap()
+    REG_MAP.lock().unwrap().get(i).cloned().flatten()
 }
 
 fn reg_map_set(i: usize, val: usize) {
-    REG_MAP.lock().unwrap()[i] = Some(val);
+    let mut map = REG_MAP.lock().unwrap();
+    if map.len() <= i {
+        map.resize(i + 1, None);
+    }
+    map[i] = Some(val);
 }
 
 fn alloc(ir_reg: usize) -> usize {
-    if REG_MAP.lock().unwrap().len() <= ir_reg {
-        panic!("program too big");
-    }
-
     if let Some(r) = reg_map_get(ir_reg) {
         assert!(used_get(r));
         return r;
@@ -72,18 +72,10 @@ fn visit(irv: &mut Vec<IR>) {
                 ir.lhs = Some(alloc(ir.lhs.unwrap()));
                 ir.rhs = Some(alloc(ir.rhs.unwrap()));
             }
+            // Arguments are in the frame rather than in registers.
             Call => {
                 ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                match ir.op {
-                    IROp::Call(name, nargs, args) => {
-                        let mut args_new: [usize; 6] = [0; 6];
-                        for i in 0..nargs {
-                            args_new[i] = alloc(args[i]);
-                        }
-                        ir.op = IROp::Call(name, nargs, args_new);
-                    }
-                    _ => unreachable!(),
-                }
+                ir.rhs = ir.rhs.map(alloc);
             }
             _ => (),
         }
@@ -101,6 +93,7 @@ fn visit(irv: &mut Vec<IR>) {
 pub fn alloc_regs(fns: &mut Vec<Function>) {
     for f in fns {
         *USED.lock().unwrap() = [false; REGS_N];
+        REG_MAP.lock().unwrap().clear();
 
         visit(&mut f.ir);
     }
@@ -1,7 +1,9 @@
+use crate::diagnostic::{self, Code, Diagnostic, Result};
+use crate::source::{ExpansionId, Span};
 use crate::matches;
 use crate::parse::{Node, NodeType};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type, Var};
+use crate::{Ctype, IntType, Scope, TokenType, Type, Var};
 
 use std::collections::HashMap;
 use std::mem;
@@ -27,6 +29,9 @@ use std::sync::Mutex;
 // >   for integer and becomes ptr+8 for pointer.
 // >
 // > - Reject bad assignments, such as `1=2+3`.
+//
+// - Insert nodes to convert values between integer and floating-point
+//   types, which have different representations.
 
 fn swap(p: &mut Node, q: &mut Node) {
     mem::swap(p, q);
@@ -37,6 +42,16 @@ lazy_static! {
     static ref ENV: Mutex<Env> = Mutex::new(Env::new(None));
     static ref STRLABEL: Mutex<usize> = Mutex::new(0);
     static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
+    static ref RETURN_TYPE: Mutex<Type> = Mutex::new(Type::int_ty());
+    static ref SWITCHES: Mutex<Vec<SwitchLabels>> = Mutex::new(vec![]);
+    static ref LABELS: Mutex<Labels> = Mutex::new(Labels::default());
+}
+
+// Labels seen so far in a switch statement being walked.
+struct SwitchLabels {
+    ty: Type,                // Type of the condition
+    cases: Vec<(i64, Span)>, // Values converted to `ty`
+    default: Option<Span>,
 }
 
 #[derive(Debug, Clone)]
@@ -54,7 +69,7 @@ impl Env {
     }
 }
 
-fn into_new_range<T: Sized>(param: T, f: Box<dyn Fn(T) -> T>) -> T {
+fn into_new_range<T: Sized, U>(param: T, f: Box<dyn Fn(T) -> U>) -> U {
     let env = ENV.lock().unwrap().clone();
     *ENV.lock().unwrap() = Env::new(Some(Box::new(env)));
     let ret = f(param);
@@ -80,36 +95,333 @@ fn find_var(name: &str) -> Option<Var> {
     }
 }
 
+// Returns the visible variable whose name is closest to `name`, if it
+// is close enough to be a likely typo.
+fn similar_var(name: &str) -> Option<String> {
+    let max = name.chars().count().div_ceil(3);
+    let mut best: Option<(usize, String)> = None;
+    let mut env = Some(ENV.lock().unwrap().clone());
+    while let Some(e) = env {
+        for other in e.vars.keys() {
+            let d = edit_distance(name, other);
+            if d <= max && best.as_ref().is_none_or(|b| (d, other) < (b.0, &b.1)) {
+                best = Some((d, other.clone()));
+            }
+        }
+        env = e.next.map(|next| *next);
+    }
+    best.map(|b| b.1)
+}
+
+// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + (ca != cb) as usize).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
 fn maybe_decay(base: Node, decay: bool) -> Node {
     if !decay {
         return base;
     }
 
-    if let Ctype::Ary(ary_of, _) = base.ty.ty.clone() {
-        let mut node = Node::new(NodeType::Addr(Box::new(base)));
-        node.ty = Box::new(Type::ptr_to(ary_of.clone()));
-        node
-    } else {
-        base
+    let ty = match base.ty.ty {
+        Ctype::Ary(ref ary_of, _) => Type::ptr_to(ary_of.clone()),
+        // A function designator is converted to a pointer to the function.
+        Ctype::Func(_, _) => Type::ptr_to(base.ty.clone()),
+        _ => return base,
+    };
+    let mut node = Node::new(NodeType::Addr(Box::new(base)));
+    node.ty = Box::new(ty);
+    node
+}
+
+// Converts an arithmetic value to `ty`. Only conversions involving a
+// floating-point type need a node; integers of any size are held in 64-bit
+// registers, and are truncated when stored.
+fn conv(node: Node, ty: &Type) -> Node {
+    if !node.ty.is_arith() || !ty.is_arith() {
+        return node;
+    }
+    if node.ty.is_float() == ty.is_float() && (!ty.is_float() || node.ty.size == ty.size) {
+        return node;
+    }
+    let (span, expansion) = (node.span, node.expansion);
+    let mut ret = match node.op {
+        NodeType::Num(val) if node.ty.is_unsigned => Node::new(NodeType::FNum(val as u64 as f64)),
+        NodeType::Num(val) => Node::new(NodeType::FNum(val as f64)),
+        _ => Node::new(NodeType::Cast(Box::new(node))),
+    };
+    ret.ty = Box::new(ty.clone());
+    ret.span = span;
+    ret.expansion = expansion;
+    ret
+}
+
+// A floating-point value used as a condition is compared with 0.0, since
+// -0.0 is false and NaN is true.
+fn as_cond(node: Node) -> Node {
+    if !node.ty.is_float() {
+        return node;
     }
+    let mut zero = Node::new(NodeType::FNum(0.0));
+    zero.ty = node.ty.clone();
+    let mut ret = Node::new_binop(TokenType::NE, node, zero);
+    ret.ty = Box::new(Type::int_ty());
+    ret
 }
 
-fn check_lval(node: &Node) {
+fn check_lval(node: &Node) -> Result<()> {
     let op = &node.op;
     if !matches!(op, NodeType::Lvar(_))
         && !matches!(op, NodeType::Gvar(_, _, _))
         && !matches!(op, NodeType::Deref(_))
         && !matches!(op, NodeType::Dot(_, _, _))
     {
-        panic!("not an lvalue: {:?}", node.op);
+        return Err(node.error(Code::NotLvalue, "expression is not an lvalue"));
     }
+    Ok(())
 }
 
-fn walk(mut node: Node, decay: bool) -> Node {
+// Converts arguments of a call to the types of the parameters declared
+// for them. Other arguments, such as ones matching `...` or passed to a
+// function without a prototype, are passed as they are, except that
+// float is promoted to double.
+fn call_args(args: Vec<Node>, params: &[Node]) -> Result<Vec<Node>> {
+    args.into_iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let arg = walk(arg, true)?;
+            match params.get(i) {
+                Some(param) => Ok(conv(arg, &param.ty)),
+                None if matches!(arg.ty.ty, Ctype::Float) => Ok(conv(arg, &Type::double_ty())),
+                None => Ok(arg),
+            }
+        })
+        .collect()
+}
+
+// Nodes made in place of another one keep its location.
+// Operands of `%`, shifts and bitwise operators must be integers.
+fn check_int_operands(node: &Node, op: &TokenType, lhs: &Node, rhs: &Node) -> Result<()> {
+    use self::TokenType::*;
+    let int_only = matches!(
+        op,
+        Mod | SHL | SHR | And | VerticalBar | Hat | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ
+    );
+    if int_only && !(lhs.ty.is_integer() && rhs.ty.is_integer()) {
+        let d = node.error(Code::Type, "invalid operands to binary expression");
+        return Err(d.label(lhs.span, "").label(rhs.span, ""));
+    }
+    Ok(())
+}
+
+// Returns an integer value as held in a register after converting it to
+// `ty`: loads of 4 bytes sign-extend, and narrower loads zero-extend.
+fn trunc(val: i64, ty: &Type) -> i64 {
+    match ty.size {
+        1 => val as u8 as i64,
+        2 => val as u16 as i64,
+        4 if ty.is_unsigned => val as u32 as i64,
+        4 => val as i32 as i64,
+        _ => val,
+    }
+}
+
+// Evaluates an integer constant expression which has been walked, or
+// returns None if it is not one.
+fn eval(node: &Node) -> Option<i64> {
+    use self::NodeType::*;
+    if !node.ty.is_integer() {
+        return None;
+    }
+    let val = match node.op {
+        Num(val) => val,
+        Neg(ref expr) => eval(expr)?.wrapping_neg(),
+        Exclamation(ref expr) => (eval(expr)? == 0) as i64,
+        Cast(ref expr) => trunc(eval(expr)?, &node.ty),
+        Ternary(ref cond, ref then, ref els) => {
+            if eval(cond)? != 0 {
+                eval(then)?
+            } else {
+                eval(els)?
+            }
+        }
+        BinOp(ref op, ref lhs, ref rhs) => {
+            let (l, r) = (eval(lhs)?, eval(rhs)?);
+            let unsigned = lhs.ty.is_unsigned;
+            match op {
+                TokenType::Plus => l.wrapping_add(r),
+                TokenType::Minus => l.wrapping_sub(r),
+                TokenType::Mul => l.wrapping_mul(r),
+                TokenType::Div if unsigned => (l as u64).checked_div(r as u64)? as i64,
+                TokenType::Div => l.checked_div(r)?,
+                TokenType::Mod if unsigned => (l as u64).checked_rem(r as u64)? as i64,
+                TokenType::Mod => l.checked_rem(r)?,
+                TokenType::And => l & r,
+                TokenType::VerticalBar => l | r,
+                TokenType::Hat => l ^ r,
+                TokenType::SHL => l.wrapping_shl(r as u32),
+                TokenType::SHR if unsigned => (l as u64).wrapping_shr(r as u32) as i64,
+                TokenType::SHR => l.wrapping_shr(r as u32),
+                TokenType::EQ => (l == r) as i64,
+                TokenType::NE => (l != r) as i64,
+                TokenType::LE if unsigned => (l as u64 <= r as u64) as i64,
+                TokenType::LE => (l <= r) as i64,
+                TokenType::LeftAngleBracket if unsigned => ((l as u64) < r as u64) as i64,
+                TokenType::LeftAngleBracket => (l < r) as i64,
+                TokenType::Logand => (l != 0 && r != 0) as i64,
+                TokenType::Logor => (l != 0 || r != 0) as i64,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(trunc(val, &node.ty))
+}
+
+// Records a case label in the innermost switch statement, and returns its
+// value converted to the type of the condition.
+fn add_case(node: &Node, val: &Node) -> Result<i64> {
+    let msg = "expression is not an integer constant expression";
+    let v = eval(val).ok_or_else(|| val.error(Code::NotConstant, msg))?;
+    let mut switches = SWITCHES.lock().unwrap();
+    let sw = match switches.last_mut() {
+        Some(sw) => sw,
+        None => {
+            let msg = "'case' statement not in switch statement";
+            return Err(node.error(Code::Statement, msg));
+        }
+    };
+    let v = trunc(v, &sw.ty);
+    if let Some((_, prev)) = sw.cases.iter().find(|(v2, _)| *v2 == v) {
+        let msg = format!("duplicate case value '{}'", v);
+        let d = val.error(Code::DuplicateLabel, msg);
+        return Err(d.label(*prev, "previous case defined here"));
+    }
+    sw.cases.push((v, val.span));
+    Ok(v)
+}
+
+fn add_default(node: &Node) -> Result<()> {
+    let mut switches = SWITCHES.lock().unwrap();
+    let sw = match switches.last_mut() {
+        Some(sw) => sw,
+        None => {
+            let msg = "'default' statement not in switch statement";
+            return Err(node.error(Code::Statement, msg));
+        }
+    };
+    if let Some(prev) = sw.default {
+        let d = node.error(Code::DuplicateLabel, "multiple default labels in one switch");
+        return Err(d.label(prev, "previous default defined here"));
+    }
+    sw.default = Some(node.span);
+    Ok(())
+}
+
+// Named labels and gotos in the function being walked. Labels are in
+// scope in the whole function, so gotos are checked at its end.
+#[derive(Default)]
+struct Labels {
+    defined: Vec<(String, Span, Option<ExpansionId>)>,
+    gotos: Vec<(String, Span, Option<ExpansionId>)>,
+}
+
+fn add_label(node: &Node, name: &str) -> Result<()> {
+    let mut labels = LABELS.lock().unwrap();
+    if let Some((_, prev, _)) = labels.defined.iter().find(|(n, _, _)| n == name) {
+        let msg = format!("redefinition of label '{}'", name);
+        let d = node.error(Code::DuplicateLabel, msg);
+        return Err(d.label(*prev, "previous definition is here"));
+    }
+    labels.defined.push((name.into(), node.span, node.expansion));
+    Ok(())
+}
+
+// Reports gotos to labels which are not defined, and labels which are not
+// used, in the function just walked.
+fn check_labels() -> Result<()> {
+    let labels = mem::take(&mut *LABELS.lock().unwrap());
+    for (name, span, expansion) in &labels.gotos {
+        if !labels.defined.iter().any(|(n, _, _)| n == name) {
+            let msg = format!("use of undeclared label '{}'", name);
+            let d = Diagnostic::error(Code::UndefinedLabel, msg).at(*span, *expansion);
+            diagnostic::report_error(d)?;
+        }
+    }
+    for (name, span, expansion) in &labels.defined {
+        if !labels.gotos.iter().any(|(n, _, _)| n == name) {
+            let msg = format!("unused label '{}'", name);
+            let d = Diagnostic::warning(Code::UnusedLabel, msg).at(*span, *expansion);
+            diagnostic::report(d);
+        }
+    }
+    Ok(())
+}
+
+// Finds a member by name, also among the members of anonymous struct and
+// union members, and returns its type and offset.
+fn find_member(members: &[Node], name: &str) -> Option<(Box<Type>, usize)> {
+    for m in members {
+        if let NodeType::Vardef(ref m_name, _, Scope::Local(offset)) = m.op {
+            if m_name == name {
+                return Some((m.ty.clone(), offset));
+            }
+            if !m_name.is_empty() {
+                continue;
+            }
+            if let Ctype::Struct(ref inner) | Ctype::Union(ref inner) = m.ty.ty {
+                if let Some((ty, offset2)) = find_member(inner, name) {
+                    return Some((ty, offset + offset2));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn walk(node: Node, decay: bool) -> Result<Node> {
+    let (span, expansion) = (node.span, node.expansion);
+    let mut ret = walk2(node, decay)?;
+    ret.span = span;
+    ret.expansion = expansion;
+    Ok(ret)
+}
+
+fn walk2(mut node: Node, decay: bool) -> Result<Node> {
     use self::NodeType::*;
     let op = node.op.clone();
     match op {
-        Num(_) | Null | Break => (),
+        Num(_) | FNum(_) | Null | Break | Continue | Error => (),
+        EnumConst(name, val) => {
+            let val = walk(*val, true)?;
+            let msg = "expression is not an integer constant expression";
+            let val = eval(&val)
+                .ok_or_else(|| val.error(Code::NotConstant, msg))
+                .or_else(|e| diagnostic::report_error(e).map(|_| 0))?;
+            let ty = Type::int_ty();
+            let var = Var::new(Box::new(ty.clone()), name.clone(), Scope::Enum(trunc(val, &ty)));
+            ENV.lock().unwrap().vars.insert(name, var);
+            node.op = Null;
+        }
+        Goto(ref name) => {
+            let goto = (name.clone(), node.span, node.expansion);
+            LABELS.lock().unwrap().gotos.push(goto);
+        }
+        Label(name, stmt) => {
+            add_label(&node, &name).or_else(diagnostic::report_error)?;
+            node.op = Label(name, Box::new(walk(*stmt, true)?));
+        }
         Str(data, len) => {
             // Quoted from oxide
             // > A string literal is converted to a reference to an anonymous
@@ -120,9 +432,9 @@ fn walk(mut node: Node, decay: bool) -> Node {
             let name = var.name.clone();
             GLOBALS.lock().unwrap().push(var);
 
-            let mut ret = Node::new(NodeType::Gvar(name, "".into(), len));
+            let mut ret = Node::new(NodeType::Gvar(name, vec![], len));
             ret.ty = node.ty;
-            return maybe_decay(ret, decay);
+            return Ok(maybe_decay(ret, decay));
         }
         Ident(ref name) => {
             if let Some(var) = find_var(name) {
@@ -130,19 +442,36 @@ fn walk(mut node: Node, decay: bool) -> Node {
                     Scope::Local(offset) => {
                         let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
                         ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+                        return Ok(maybe_decay(ret, decay));
                     }
                     Scope::Global(ref data, len, _) => {
                         let mut ret =
                             Node::new(NodeType::Gvar(var.name.clone(), data.clone(), len));
                         ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+                        return Ok(maybe_decay(ret, decay));
                     }
+                    Scope::Enum(val) => return Ok(Node::new_num(val, IntType::Int)),
                 }
             } else {
-                panic!("undefined variable: {}", name);
+                let msg = format!("use of undeclared identifier '{}'", name);
+                // A suggestion inside a macro expansion would replace the
+                // macro's name rather than the identifier.
+                let similar = similar_var(name).filter(|_| node.expansion.is_none());
+                return Err(match similar {
+                    Some(other) => {
+                        let msg = format!("{}; did you mean '{}'?", msg, other);
+                        node.error(Code::UndefinedVar, msg).fix(node.span, other)
+                    }
+                    None => node.error(Code::UndefinedVar, msg),
+                });
             }
         }
+        Vardef(name, _, _) if matches!(node.ty.ty, Ctype::Func(_, _)) => {
+            // A function declaration in a block
+            let var = Var::new_global(node.ty.clone(), name.clone(), "".into(), 0, false);
+            ENV.lock().unwrap().vars.insert(name, var);
+            node.op = Null;
+        }
         Vardef(name, init_may, _) => {
             let stacksize = *STACKSIZE.lock().unwrap();
             *STACKSIZE.lock().unwrap() = roundup(stacksize, node.ty.align);
@@ -156,36 +485,41 @@ fn walk(mut node: Node, decay: bool) -> Node {
 
             let mut init = None;
             if let Some(init2) = init_may {
-                init = Some(Box::new(walk(*init2, true)));
+                init = Some(Box::new(conv(walk(*init2, true)?, &node.ty)));
             }
             node.op = Vardef(name, init, Scope::Local(offset));
         }
         If(mut cond, mut then, els_may) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
+            cond = Box::new(as_cond(walk(*cond, true)?));
+            then = Box::new(walk(*then, true)?);
             let mut new_els = None;
             if let Some(els) = els_may {
-                new_els = Some(Box::new(walk(*els, true)));
+                new_els = Some(Box::new(walk(*els, true)?));
             }
             node.op = If(cond, then, new_els);
         }
         Ternary(mut cond, mut then, mut els) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            els = Box::new(walk(*els, true));
+            cond = Box::new(as_cond(walk(*cond, true)?));
+            then = Box::new(walk(*then, true)?);
+            els = Box::new(walk(*els, true)?);
             node.ty = then.ty.clone();
+            if then.ty.is_arith() && els.ty.is_arith() {
+                node.ty = Box::new(Type::arith_conv(&then.ty, &els.ty));
+                then = Box::new(conv(*then, &node.ty));
+                els = Box::new(conv(*els, &node.ty));
+            }
             node.op = Ternary(cond, then, els);
         }
         For(init, cond, inc, body) => {
-            let f = |(init, cond, inc, body)| -> (Node, Node, Node, Node) {
-                (
-                    walk(init, true),
-                    walk(cond, true),
-                    walk(inc, true),
-                    walk(body, true),
-                )
+            let f = |(init, cond, inc, body)| -> Result<(Node, Node, Node, Node)> {
+                Ok((
+                    walk(init, true)?,
+                    as_cond(walk(cond, true)?),
+                    walk(inc, true)?,
+                    walk(body, true)?,
+                ))
             };
-            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f));
+            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f))?;
             node.op = For(
                 Box::new(init),
                 Box::new(cond),
@@ -194,169 +528,292 @@ fn walk(mut node: Node, decay: bool) -> Node {
             );
         }
         DoWhile(body, cond) => {
-            node.op = DoWhile(Box::new(walk(*body, true)), Box::new(walk(*cond, true)));
+            node.op = DoWhile(
+                Box::new(walk(*body, true)?),
+                Box::new(as_cond(walk(*cond, true)?)),
+            );
+        }
+        Switch(cond, body, _, _) => {
+            let cond = walk(*cond, true)?;
+            if !cond.ty.is_integer() {
+                let msg = "statement requires expression of integer type";
+                diagnostic::report_error(node.error(Code::Type, msg).label(cond.span, ""))?;
+            }
+            let ty = cond.ty.promote();
+            SWITCHES.lock().unwrap().push(SwitchLabels {
+                ty,
+                cases: vec![],
+                default: None,
+            });
+            let body = walk(*body, true);
+            let sw = SWITCHES.lock().unwrap().pop().unwrap();
+            let cases = sw.cases.into_iter().map(|(val, _)| val).collect();
+            node.op = Switch(Box::new(cond), Box::new(body?), cases, sw.default.is_some());
+        }
+        Case(val, stmt) => {
+            // A bad label is reported, and checking goes on with the
+            // rest of the switch.
+            let val = walk(*val, true)?;
+            let val = add_case(&node, &val).or_else(|e| diagnostic::report_error(e).map(|_| 0))?;
+            node.op = Case(Box::new(Node::new_int(val)), Box::new(walk(*stmt, true)?));
+        }
+        Default(stmt) => {
+            add_default(&node).or_else(diagnostic::report_error)?;
+            node.op = Default(Box::new(walk(*stmt, true)?));
         }
         Dot(mut expr, name, _) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             let offset;
-            if let Ctype::Struct(ref members) = expr.ty.ty {
+            if let Ctype::Struct(ref members) | Ctype::Union(ref members) = expr.ty.ty {
                 if members.is_empty() {
-                    panic!("incomplete type");
+                    return Err(node.error(Code::Type, "member access into incomplete type"));
                 }
-                let m_may = members.iter().find(|m| {
-                    if let NodeType::Vardef(ref m_name, _, _) = m.op {
-                        if m_name != &name {
-                            return false;
-                        }
-                        return true;
-                    }
-                    false
-                });
-
-                if let Some(m) = m_may {
-                    if let NodeType::Vardef(_, _, Scope::Local(offset2)) = m.op {
-                        node.ty = m.ty.clone();
-                        offset = offset2;
-                    } else {
-                        unreachable!()
-                    }
+                if let Some((ty, offset2)) = find_member(members, &name) {
+                    node.ty = ty;
+                    offset = offset2;
                 } else {
-                    panic!("member missing: {}", name);
+                    let kind = match expr.ty.ty {
+                        Ctype::Union(_) => "union",
+                        _ => "struct",
+                    };
+                    let msg = format!("no member named '{}' in {}", name, kind);
+                    return Err(node.error(Code::NoMember, msg));
                 }
             } else {
-                panic!("struct expected before '.'");
+                let msg = "member reference base type is not a structure or union";
+                return Err(node.error(Code::Type, msg).label(expr.span, ""));
             }
 
             node.op = NodeType::Dot(expr, name, offset);
-            return maybe_decay(node, decay);
+            return Ok(maybe_decay(node, decay));
         }
         BinOp(token_type, mut lhs, mut rhs) => {
             use self::TokenType::*;
             match token_type {
                 Plus | Minus => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
+                    lhs = Box::new(walk(*lhs, true)?);
+                    rhs = Box::new(walk(*rhs, true)?);
 
                     if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
                         swap(&mut lhs, &mut rhs);
                     }
                     if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        panic!("'pointer {:?} pointer' is not defined", node.op)
+                        let msg = "invalid operands to binary expression (pointer and pointer)";
+                        let d = node.error(Code::Type, msg);
+                        return Err(d.label(lhs.span, "").label(rhs.span, ""));
                     }
 
                     if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                        rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty));
                     }
 
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+                    node.ty = Box::new(Type::arith_conv(&lhs.ty, &rhs.ty));
+                    lhs = Box::new(conv(*lhs, &node.ty));
+                    rhs = Box::new(conv(*rhs, &node.ty));
+                    node.op = BinOp(token_type, lhs, rhs);
                 }
                 AddEQ | SubEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&lhs);
-                    rhs = Box::new(walk(*rhs, true));
+                    lhs = Box::new(walk(*lhs, false)?);
+                    check_lval(&lhs)?;
+                    rhs = Box::new(walk(*rhs, true)?);
 
                     if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                        rhs = Box::new(Node::scale_ptr(*rhs, &lhs.ty));
                     }
+                    let ty = Type::arith_conv(&lhs.ty, &rhs.ty);
+                    rhs = Box::new(conv(*rhs, &ty));
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
                 Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&lhs);
-                    node.op = BinOp(token_type, lhs.clone(), Box::new(walk(*rhs, true)));
+                    lhs = Box::new(walk(*lhs, false)?);
+                    check_lval(&lhs)?;
+                    rhs = Box::new(walk(*rhs, true)?);
+                    check_int_operands(&node, &token_type, &lhs, &rhs)?;
+                    // The value is converted to the type of the lhs, or
+                    // for compound assignment, to the type of the operation.
+                    let ty = if token_type == Equal {
+                        *lhs.ty.clone()
+                    } else {
+                        Type::arith_conv(&lhs.ty, &rhs.ty)
+                    };
+                    rhs = Box::new(conv(*rhs, &ty));
+                    node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
+                EQ | NE | LE | LeftAngleBracket | RightAngleBracket => {
+                    lhs = Box::new(walk(*lhs, true)?);
+                    rhs = Box::new(walk(*rhs, true)?);
+                    let ty = Type::arith_conv(&lhs.ty, &rhs.ty);
+                    lhs = Box::new(conv(*lhs, &ty));
+                    rhs = Box::new(conv(*rhs, &ty));
+                    node.op = BinOp(token_type, lhs, rhs);
+                    node.ty = Box::new(Type::int_ty());
+                }
+                Logand | Logor => {
+                    lhs = Box::new(as_cond(walk(*lhs, true)?));
+                    rhs = Box::new(as_cond(walk(*rhs, true)?));
+                    node.op = BinOp(token_type, lhs, rhs);
+                    node.ty = Box::new(Type::int_ty());
+                }
+                SHL | SHR => {
+                    lhs = Box::new(walk(*lhs, true)?);
+                    rhs = Box::new(walk(*rhs, true)?);
+                    check_int_operands(&node, &token_type, &lhs, &rhs)?;
+                    node.ty = Box::new(lhs.ty.promote());
+                    node.op = BinOp(token_type, lhs, rhs);
+                }
+                Comma => {
+                    lhs = Box::new(walk(*lhs, true)?);
+                    rhs = Box::new(walk(*rhs, true)?);
+                    node.ty = rhs.ty.clone();
+                    node.op = BinOp(token_type, lhs, rhs);
+                }
                 _ => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
-                    node.op = BinOp(token_type, lhs.clone(), rhs);
-                    node.ty = lhs.ty;
+                    lhs = Box::new(walk(*lhs, true)?);
+                    rhs = Box::new(walk(*rhs, true)?);
+                    check_int_operands(&node, &token_type, &lhs, &rhs)?;
+                    node.ty = Box::new(Type::arith_conv(&lhs.ty, &rhs.ty));
+                    lhs = Box::new(conv(*lhs, &node.ty));
+                    rhs = Box::new(conv(*rhs, &node.ty));
+                    node.op = BinOp(token_type, lhs, rhs);
                 }
             }
         }
         PostInc(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             node.ty = expr.ty.clone();
             node.op = PostInc(expr);
         }
         PostDec(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             node.ty = expr.ty.clone();
             node.op = PostDec(expr);
         }
         Neg(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             node.ty = expr.ty.clone();
             node.op = Neg(expr);
         }
         Exclamation(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(as_cond(walk(*expr, true)?));
             node.ty = expr.ty.clone();
             node.op = Exclamation(expr);
         }
         Addr(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            check_lval(&expr);
+            expr = Box::new(walk(*expr, false)?);
+            check_lval(&expr)?;
             node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
             node.op = Addr(expr);
         }
         Deref(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             match expr.ty.ty {
                 Ctype::Ptr(ref ptr_to) => node.ty = ptr_to.clone(),
-                Ctype::Void => panic!("cannot dereference void pointer"),
-                _ => panic!("operand must be a pointer"),
+                Ctype::Void => {
+                    return Err(node.error(Code::Type, "cannot dereference void pointer"));
+                }
+                _ => return Err(node.error(Code::Type, "indirection requires pointer operand")),
             }
             node.op = Deref(expr);
-            return maybe_decay(node, decay);
+            return Ok(maybe_decay(node, decay));
+        }
+        Return(expr) => {
+            let ty = RETURN_TYPE.lock().unwrap().clone();
+            node.op = Return(Box::new(conv(walk(*expr, true)?, &ty)));
         }
-        Return(expr) => node.op = Return(Box::new(walk(*expr, true))),
-        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true))),
+        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true)?)),
         Sizeof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.size as i32)
+            expr = Box::new(walk(*expr, false)?);
+            node = Node::new_int(expr.ty.size as i64)
         }
         Alignof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.align as i32)
+            expr = Box::new(walk(*expr, false)?);
+            node = Node::new_int(expr.ty.align as i64)
         }
-        Call(name, mut args) => {
+        Call(name, args) => {
+            let mut params = vec![];
             if let Some(var) = find_var(&name) {
-                if let Ctype::Func(returning) = var.ty.ty {
+                if let Ctype::Func(returning, params2) = var.ty.ty {
                     node.ty = returning;
+                    params = params2;
                 } else {
-                    // eprintln!("bad function: {}", name);
+                    // A variable holding a function pointer
+                    let mut expr = Node::new(Ident(name));
+                    expr.span = node.span;
+                    expr.expansion = node.expansion;
+                    node.op = CallPtr(Box::new(expr), args);
+                    return walk2(node, decay);
                 }
             } else {
                 // eprintln!("bad function: {}", name);
             }
-
-            args = args.into_iter().map(|arg| walk(arg, true)).collect();
-            node.op = Call(name, args);
+            node.op = Call(name, call_args(args, &params)?);
+        }
+        CallPtr(expr, args) => {
+            let expr = walk(*expr, true)?;
+            let params = match expr.ty.ty {
+                Ctype::Ptr(ref ptr_to) => match ptr_to.ty {
+                    Ctype::Func(ref returning, ref params) => {
+                        node.ty = returning.clone();
+                        Some(params.clone())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let msg = "called object is not a function or function pointer";
+            let params = params.ok_or_else(|| node.error(Code::Type, msg).label(expr.span, ""))?;
+            node.op = CallPtr(Box::new(expr), call_args(args, &params)?);
+        }
+        Cast(expr) => {
+            let expr = walk(*expr, true)?;
+            let is_scalar = |ty: &Type| ty.is_arith() || matches!(ty.ty, Ctype::Ptr(_));
+            let is_ptr = |ty: &Type| matches!(ty.ty, Ctype::Ptr(_));
+            if !matches!(node.ty.ty, Ctype::Void) {
+                if !is_scalar(&expr.ty) || !is_scalar(&node.ty) {
+                    let msg = "operand of type cast must have scalar type";
+                    return Err(node.error(Code::Type, msg).label(expr.span, ""));
+                }
+                if (is_ptr(&expr.ty) && node.ty.is_float())
+                    || (expr.ty.is_float() && is_ptr(&node.ty))
+                {
+                    let msg = "pointer cannot be cast to or from a floating-point type";
+                    return Err(node.error(Code::Type, msg).label(expr.span, ""));
+                }
+            }
+            if expr.ty.is_float() || node.ty.is_float() {
+                return Ok(conv(expr, &node.ty));
+            }
+            node.op = Cast(Box::new(expr));
         }
         CompStmt(mut stmts) => {
-            let f = |stmts: Vec<Node>| -> Vec<Node> {
+            let f = |stmts: Vec<Node>| -> Result<Vec<Node>> {
                 stmts.into_iter().map(|stmt| walk(stmt, true)).collect()
             };
-            stmts = into_new_range(stmts, Box::new(f));
+            stmts = into_new_range(stmts, Box::new(f))?;
             node.op = CompStmt(stmts);
         }
         VecStmt(mut stmts) => {
-            stmts = stmts.into_iter().map(|stmt| walk(stmt, true)).collect();
+            stmts = stmts
+                .into_iter()
+                .map(|stmt| walk(stmt, true))
+                .collect::<Result<_>>()?;
             node.op = VecStmt(stmts);
         }
         StmtExpr(body) => {
-            node.op = StmtExpr(Box::new(walk(*body, true)));
+            // `return` in a statement expression gives its value.
+            let ty = mem::replace(&mut *RETURN_TYPE.lock().unwrap(), Type::int_ty());
+            node.op = StmtExpr(Box::new(walk(*body, true)?));
+            *RETURN_TYPE.lock().unwrap() = ty;
             node.ty = Box::new(Type::int_ty())
         }
         _ => panic!("unknown node type"),
     };
-    node
+    Ok(node)
 }
 
-pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
+pub fn sema(nodes: Vec<Node>) -> Result<(Vec<Node>, Vec<Var>)> {
     let mut new_nodes = vec![];
 
     for mut node in nodes {
@@ -367,6 +824,11 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
             continue;
         }
 
+        if matches!(node.op, NodeType::EnumConst(_, _)) {
+            walk(node, true)?;
+            continue;
+        }
+
         let var;
         match &node.op {
             NodeType::Func(name, _, _, _) | NodeType::Decl(name) => {
@@ -381,11 +843,15 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
         }
 
         if let NodeType::Func(name, args, body, _) = node.op {
+            if let Ctype::Func(ref returning, _) = node.ty.ty {
+                *RETURN_TYPE.lock().unwrap() = *returning.clone();
+            }
             let mut args2 = vec![];
             for arg in args {
-                args2.push(walk(arg, true));
+                args2.push(walk(arg, true)?);
             }
-            let body2 = walk(*body, true);
+            let body2 = walk(*body, true)?;
+            check_labels()?;
             node.op = NodeType::Func(
                 name.clone(),
                 args2,
@@ -396,5 +862,5 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
             new_nodes.push(node);
         }
     }
-    (new_nodes, GLOBALS.lock().unwrap().clone())
+    Ok((new_nodes, GLOBALS.lock().unwrap().clone()))
 }
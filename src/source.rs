@@ -0,0 +1,208 @@
+// Source map
+//
+// All source text is owned here: input files, the command line and
+// built-in definitions, and scratch buffers holding tokens made by the
+// preprocessor, such as results of `##`. Tokens refer to their text by a
+// `Span`, a file id and a byte range, and the line containing a position
+// is found by binary search in a table of line starts.
+//
+// A file also remembers the `#include` it is read by, and a macro
+// expansion the invocation it comes from, so that a location can be
+// traced back through both.
+
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    pub static ref SOURCE_MAP: Mutex<SourceMap> = Mutex::new(SourceMap::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FileId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExpansionId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub lo: u32, // byte offsets
+    pub hi: u32,
+}
+
+impl Span {
+    pub fn new(file: FileId, lo: usize, hi: usize) -> Self {
+        Span {
+            file,
+            lo: lo as u32,
+            hi: hi as u32,
+        }
+    }
+
+    // Returns the span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { hi: other.hi, ..self }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub src: Arc<str>,
+    lines: Arc<Vec<u32>>, // byte offsets of line starts
+    pub included_from: Option<Span>,
+    pub line_delta: isize, // line number adjustment made by #line
+}
+
+impl SourceFile {
+    // Returns the 0-based index of the line containing `offset`.
+    pub fn line_index(&self, offset: u32) -> usize {
+        self.lines.partition_point(|start| *start <= offset) - 1
+    }
+
+    // Returns the text of a line without the newline.
+    pub fn line_text(&self, idx: usize) -> &str {
+        let start = self.lines[idx] as usize;
+        let end = self.lines.get(idx + 1).map_or(self.src.len(), |end| *end as usize - 1);
+        &self.src[start..end]
+    }
+
+    pub fn line_start(&self, idx: usize) -> u32 {
+        self.lines[idx]
+    }
+}
+
+// An expansion of a macro, `_Pragma` or a builtin such as `__LINE__`.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub name: String,
+    pub call_site: Span,
+    pub parent: Option<ExpansionId>, // expansion the invocation comes from
+}
+
+// A position as shown to users: the line is adjusted by #line, and the
+// column counts characters from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loc {
+    pub file: FileId,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    expansions: Vec<Expansion>,
+}
+
+impl SourceMap {
+    pub fn add_file(&mut self, name: String, src: String, included_from: Option<Span>) -> FileId {
+        let mut lines = vec![0];
+        lines.extend(src.match_indices('\n').map(|(i, _)| i as u32 + 1));
+        self.files.push(SourceFile {
+            name,
+            src: src.into(),
+            lines: Arc::new(lines),
+            included_from,
+            line_delta: 0,
+        });
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    // Returns a file with the same text as `file` but another name and
+    // line numbers, for tokens following a #line directive.
+    pub fn add_alias(&mut self, file: FileId, name: String, line_delta: isize) -> FileId {
+        let mut f = self.file(file).clone();
+        f.name = name;
+        f.line_delta = line_delta;
+        self.files.push(f);
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    // Returns an existing file with the given contents or adds one.
+    // This is used for files read back from the token cache, which are
+    // mostly loaded already.
+    pub(crate) fn intern(
+        &mut self,
+        name: String,
+        src: String,
+        line_delta: isize,
+        included_from: Option<Span>,
+    ) -> FileId {
+        let same = |f: &SourceFile| {
+            f.name == name
+                && *f.src == *src
+                && f.line_delta == line_delta
+                && f.included_from == included_from
+        };
+        if let Some(i) = self.files.iter().position(same) {
+            return FileId(i as u32);
+        }
+        let id = self.add_file(name, src, included_from);
+        self.files[id.0 as usize].line_delta = line_delta;
+        id
+    }
+
+    pub fn add_expansion(&mut self, name: String, call_site: Span, parent: Option<ExpansionId>) -> ExpansionId {
+        self.expansions.push(Expansion {
+            name,
+            call_site,
+            parent,
+        });
+        ExpansionId(self.expansions.len() as u32 - 1)
+    }
+
+    pub fn file(&self, id: FileId) -> &SourceFile {
+        &self.files[id.0 as usize]
+    }
+
+    pub fn expansion(&self, id: ExpansionId) -> &Expansion {
+        &self.expansions[id.0 as usize]
+    }
+
+    pub fn text(&self, span: Span) -> &str {
+        &self.file(span.file).src[span.lo as usize..span.hi as usize]
+    }
+
+    pub fn lookup(&self, span: Span) -> Loc {
+        let f = self.file(span.file);
+        let idx = f.line_index(span.lo);
+        let start = f.lines[idx] as usize;
+        let col = f.src[start..span.lo as usize].chars().count() + 1;
+        Loc {
+            file: span.file,
+            line: (idx as isize + 1 + f.line_delta) as usize,
+            col,
+        }
+    }
+
+    // Returns the `#include` directives through which a file is read,
+    // innermost first.
+    pub fn include_chain(&self, file: FileId) -> Vec<Span> {
+        let mut v = vec![];
+        let mut file = file;
+        while let Some(span) = self.file(file).included_from {
+            v.push(span);
+            file = span.file;
+        }
+        v
+    }
+
+    // Returns the expansions a token comes from, innermost first.
+    pub fn expansion_chain(&self, id: Option<ExpansionId>) -> Vec<&Expansion> {
+        let mut v = vec![];
+        let mut id = id;
+        while let Some(e) = id.map(|id| self.expansion(id)) {
+            v.push(e);
+            id = e.parent;
+        }
+        v
+    }
+
+    // Returns where a token written at `span` appears in the source: the
+    // outermost invocation if it comes from an expansion.
+    pub fn call_site(&self, span: Span, expansion: Option<ExpansionId>) -> Span {
+        self.expansion_chain(expansion)
+            .last()
+            .map_or(span, |e| e.call_site)
+    }
+}
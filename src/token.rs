@@ -1,41 +1,152 @@
+use crate::diagnostic::{self, Code, Diagnostic};
 use crate::preprocess;
+use crate::source::{ExpansionId, FileId, Span, SOURCE_MAP};
 use crate::CharacterType;
-use crate::TokenType;
+use crate::{Encoding, FloatType, IntType, TokenType};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::rc::Rc;
 
-pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> Vec<Token> {
-    let mut tokenizer = Tokenizer::new(Rc::new(path));
-    tokenizer.canonicalize_newline();
-    tokenizer.remove_backslash_newline();
+pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> diagnostic::Result<Vec<Token>> {
+    join_string_literals(preprocess_file(path, None, ctx)?)
+}
+
+// Tokenizes a file given by `-include`.
+pub fn tokenize_include(file: &str, ctx: &mut preprocess::Preprocessor) -> diagnostic::Result<Vec<Token>> {
+    join_string_literals(ctx.include_cmdline(file)?)
+}
+
+// Reads and preprocesses a file. Unlike `tokenize`, adjacent string
+// literals are kept apart so that the result can be printed as text.
+// `included_from` is the #include directive reading the file, if any.
+pub fn preprocess_file(
+    path: String,
+    included_from: Option<Span>,
+    ctx: &mut preprocess::Preprocessor,
+) -> diagnostic::Result<Vec<Token>> {
+    let input = match Tokenizer::read_file(&path) {
+        Ok(input) => remove_backslash_newline(&input.replace("\r\n", "\n")),
+        Err(e) => {
+            let d = Diagnostic::error(Code::FileNotFound, format!("cannot read '{}': {}", path, e));
+            return Err(match included_from {
+                Some(site) => d.at(site, None),
+                None => d,
+            });
+        }
+    };
+    let file = SOURCE_MAP
+        .lock()
+        .unwrap()
+        .add_file(path.clone(), input.clone(), included_from);
+    let path = Rc::new(path);
+    let mut tokenizer = Tokenizer::new(file, &input);
     tokenizer.scan(&keyword_map());
 
-    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx);
+    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, path, ctx)?;
+    for t in &tokenizer.tokens {
+        if let TokenType::Invalid(msg) = t.ty {
+            return Err(t.error(Code::InvalidToken, msg));
+        }
+    }
     tokenizer.strip_newlines_tokens();
-    tokenizer.join_string_literals();
+    Ok(tokenizer.tokens)
+}
+
+// Adjacent string literals are concatenated. If one of them has an
+// encoding prefix, the others must have the same prefix or none, and
+// unprefixed ones are read again in that encoding (C11 6.4.5p5).
+fn join_string_literals(tokens: Vec<Token>) -> diagnostic::Result<Vec<Token>> {
+    let mut v: Vec<Token> = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let n = tokens[i..]
+            .iter()
+            .take_while(|t| matches!(t.ty, TokenType::Str(_, _)))
+            .count();
+        if n < 2 {
+            v.push(tokens[i].clone());
+            i += 1;
+            continue;
+        }
+        let run = &tokens[i..i + n];
+        i += n;
+
+        let mut enc = Encoding::Plain;
+        for t in run {
+            match t.ty {
+                TokenType::Str(_, Encoding::Plain) => (),
+                TokenType::Str(_, e) if enc == Encoding::Plain || enc == e => enc = e,
+                _ => {
+                    let msg = "unsupported concatenation of string literals with different prefixes";
+                    return Err(t.error(Code::InvalidToken, msg).label(run[0].span, ""));
+                }
+            }
+        }
+
+        let mut units = vec![];
+        for t in run {
+            match t.ty {
+                TokenType::Str(ref u, e) if e == enc => units.extend(u),
+                _ => {
+                    let mut tokenizer = Tokenizer::new(t.span.file, &t.tokstr());
+                    tokenizer.string_literal(0, enc);
+                    match tokenizer.tokens[0].ty {
+                        TokenType::Str(ref u, _) => units.extend(u),
+                        TokenType::Invalid(msg) => return Err(t.error(Code::InvalidToken, msg)),
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        }
+        let mut t = run[0].clone();
+        t.ty = TokenType::Str(units, enc);
+        t.span = t.span.to(run[n - 1].span);
+        v.push(t);
+    }
+    Ok(v)
+}
+
+// Tokenizes a string which is not read from a file, such as a token
+// spelling built by the preprocessor. The string is added to the source
+// map under `name`. The result is not preprocessed.
+pub fn tokenize_str(input: String, name: &str) -> Vec<Token> {
+    let file = SOURCE_MAP
+        .lock()
+        .unwrap()
+        .add_file(name.into(), input.clone(), None);
+    let mut tokenizer = Tokenizer::new(file, &input);
+    tokenizer.scan(&keyword_map());
     tokenizer.tokens
 }
 
-fn keyword_map() -> HashMap<String, TokenType> {
+pub(crate) fn keyword_map() -> HashMap<String, TokenType> {
     let mut map = HashMap::new();
     map.insert("_Alignof".into(), TokenType::Alignof);
     map.insert("break".into(), TokenType::Break);
+    map.insert("case".into(), TokenType::Case);
     map.insert("char".into(), TokenType::Char);
+    map.insert("continue".into(), TokenType::Continue);
     map.insert("void".into(), TokenType::Void);
+    map.insert("default".into(), TokenType::Default);
     map.insert("do".into(), TokenType::Do);
+    map.insert("double".into(), TokenType::Double);
     map.insert("else".into(), TokenType::Else);
+    map.insert("enum".into(), TokenType::Enum);
     map.insert("extern".into(), TokenType::Extern);
+    map.insert("float".into(), TokenType::Float);
     map.insert("for".into(), TokenType::For);
+    map.insert("goto".into(), TokenType::Goto);
     map.insert("if".into(), TokenType::If);
     map.insert("int".into(), TokenType::Int);
     map.insert("return".into(), TokenType::Return);
     map.insert("sizeof".into(), TokenType::Sizeof);
     map.insert("struct".into(), TokenType::Struct);
+    map.insert("switch".into(), TokenType::Switch);
     map.insert("typedef".into(), TokenType::Typedef);
+    map.insert("union".into(), TokenType::Union);
     map.insert("while".into(), TokenType::While);
     map
 }
@@ -46,49 +157,88 @@ pub struct Token {
 
     // For preprocessor
     pub stringize: bool,
+    pub bol: bool,       // true if this token is at beginning of line
+    pub has_space: bool, // true if this token follows a space character
+    pub hideset: HashSet<String>, // macros that must not expand this token
+    pub expansion: Option<ExpansionId>, // macro expansion this token comes from
 
     // For error reporting
-    pub buf: Rc<Vec<char>>,
-    pub filename: Rc<String>,
-    pub start: usize,
-    pub end: usize,
+    pub span: Span,
 }
 
 impl Default for Token {
     fn default() -> Token {
         Token {
             ty: TokenType::Int,
-            buf: Rc::new(vec![]),
-            filename: Rc::new("".to_string()),
-            start: 0,
-            end: 0,
+            span: Span::default(),
             stringize: false,
+            bol: false,
+            has_space: false,
+            hideset: HashSet::new(),
+            expansion: None,
         }
     }
 }
 
 impl Token {
-    pub fn new(ty: TokenType, start: usize, filename: Rc<String>, buf: Rc<Vec<char>>) -> Self {
+    pub fn new(ty: TokenType, span: Span) -> Self {
         Token {
             ty,
-            buf,
-            filename,
-            start,
+            span,
             ..Default::default()
         }
     }
 
-    pub fn bad_token(&self, msg: &str) -> ! {
-        print_line(&self.buf, &self.filename, self.start);
-        panic!("{}", msg);
+    pub fn error(&self, code: Code, msg: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(code, msg).at(self.span, self.expansion)
+    }
+
+    pub fn warning(&self, code: Code, msg: impl Into<String>) -> Diagnostic {
+        Diagnostic::warning(code, msg).at(self.span, self.expansion)
     }
 
     pub fn tokstr(&self) -> String {
-        self.buf[self.start..self.end].iter().collect()
+        SOURCE_MAP.lock().unwrap().text(self.span).to_string()
+    }
+
+    pub fn filename(&self) -> String {
+        let map = SOURCE_MAP.lock().unwrap();
+        map.file(self.span.file).name.clone()
     }
 
     pub fn get_line_number(&self) -> usize {
-        self.buf[..self.end].iter().filter(|c| *c == &'\n').count()
+        SOURCE_MAP.lock().unwrap().lookup(self.span).line
+    }
+
+    // Returns a token at the place this token appears in the source,
+    // which is the outermost macro invocation if it comes from one.
+    pub fn loc(&self) -> Token {
+        let span = SOURCE_MAP
+            .lock()
+            .unwrap()
+            .call_site(self.span, self.expansion);
+        Token::new(self.ty.clone(), span)
+    }
+
+    // Returns the contents of a string literal as text.
+    pub fn str_value(&self) -> Option<String> {
+        match self.ty {
+            TokenType::Str(ref units, Encoding::Plain) | TokenType::Str(ref units, Encoding::UTF8) => {
+                let bytes: Vec<u8> = units.iter().map(|u| *u as u8).collect();
+                Some(String::from_utf8_lossy(&bytes).into_owned())
+            }
+            TokenType::Str(ref units, Encoding::UTF16) => {
+                let units: Vec<u16> = units.iter().map(|u| *u as u16).collect();
+                Some(String::from_utf16_lossy(&units))
+            }
+            TokenType::Str(ref units, _) => Some(
+                units
+                    .iter()
+                    .map(|u| char::from_u32(*u).unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            ),
+            _ => None,
+        }
     }
 
     pub fn is_ident(&self, s: &str) -> bool {
@@ -115,7 +265,9 @@ lazy_static! {
     static ref SYMBOLS: Vec<Symbol> = [
         Symbol::new("<<=", TokenType::ShlEQ),
         Symbol::new(">>=", TokenType::ShrEQ),
+        Symbol::new("...", TokenType::Ellipsis),
         Symbol::new("!=", TokenType::NE),
+        Symbol::new("##", TokenType::HashHash),
         Symbol::new("&&", TokenType::Logand),
         Symbol::new("++", TokenType::Inc),
         Symbol::new("--", TokenType::Dec),
@@ -138,42 +290,140 @@ lazy_static! {
     .to_vec();
 }
 
+// Returns the type of an integer constant, which is the first type in
+// the list for its suffix that can represent the value (C11 6.4.4.1), or
+// None if the suffix is invalid.
+fn int_type(val: u64, base: u32, suffix: &str) -> Option<IntType> {
+    use self::IntType::*;
+    if suffix.contains("lL") || suffix.contains("Ll") {
+        return None;
+    }
+    let decimal = base == 10;
+    let candidates: &[IntType] = match &*suffix.to_ascii_lowercase() {
+        "" if decimal => &[Int, Long],
+        "" => &[Int, UInt, Long, ULong],
+        "u" => &[UInt, ULong],
+        "l" | "ll" if decimal => &[Long],
+        "l" | "ll" => &[Long, ULong],
+        "ul" | "lu" | "ull" | "llu" => &[ULong],
+        _ => return None,
+    };
+    let fits = |ty: &IntType| match ty {
+        Int => val <= i32::MAX as u64,
+        UInt => val <= u32::MAX as u64,
+        Long => val <= i64::MAX as u64,
+        ULong => true,
+        UChar | UShort => false,
+    };
+    // Too large for any signed type, so it is unsigned as in GCC.
+    Some(candidates.iter().copied().find(fits).unwrap_or(ULong))
+}
+
+// Appends `c` to `units` in the given encoding.
+fn encode(c: char, enc: Encoding, units: &mut Vec<u32>) {
+    match enc {
+        Encoding::Plain | Encoding::UTF8 => {
+            units.extend(c.encode_utf8(&mut [0; 4]).bytes().map(u32::from))
+        }
+        Encoding::UTF16 => units.extend(c.encode_utf16(&mut [0; 2]).iter().map(|u| *u as u32)),
+        Encoding::UTF32 | Encoding::Wide => units.push(c as u32),
+    }
+}
+
+// Returns the value of a character constant consisting of `units`.
+fn char_value(units: &[u32], enc: Encoding) -> Result<TokenType, &'static str> {
+    use self::IntType::*;
+    let val = match (units, enc) {
+        ([], _) => return Err("empty character constant"),
+        // `char` is signed.
+        ([c], Encoding::Plain) => (*c as u8 as i8 as i64, Int),
+        // Each character of a multi-character constant is a byte of the
+        // value, the last one lowest, as in GCC.
+        (_, Encoding::Plain) => {
+            let val = units.iter().fold(0i32, |acc, c| (acc << 8) | *c as i32);
+            (val as i64, Int)
+        }
+        ([c], Encoding::UTF8) => (*c as i64, UChar),
+        ([c], Encoding::UTF16) => (*c as i64, UShort),
+        ([c], Encoding::UTF32) => (*c as i64, UInt),
+        ([c], Encoding::Wide) => (*c as i32 as i64, Int),
+        _ => return Err("character constant too long for its type"),
+    };
+    Ok(TokenType::Num(val.0, val.1))
+}
+
+// Returns the keyword or punctuator spelled `s`.
+pub(crate) fn keyword_or_symbol(s: &str, keywords: &HashMap<String, TokenType>) -> Option<TokenType> {
+    if let Some(ty) = keywords.get(s) {
+        return Some(ty.clone());
+    }
+    if let Some(sym) = SYMBOLS.iter().find(|sym| sym.name == s) {
+        return Some(sym.ty.clone());
+    }
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => TokenType::new_single_letter(c),
+        _ => None,
+    }
+}
+
 // Tokenizer
 struct Tokenizer {
-    p: Rc<Vec<char>>,
+    p: Vec<char>,
     pos: usize,
     tokens: Vec<Token>,
 
     // Error reporting
-    filename: Rc<String>,
+    file: FileId,
+    offsets: Vec<usize>, // byte offset of each char in `p`, and of the end
 }
 
 impl Tokenizer {
-    fn new(filename: Rc<String>) -> Self {
+    fn new(file: FileId, input: &str) -> Self {
+        let mut offsets: Vec<usize> = input.char_indices().map(|(i, _)| i).collect();
+        offsets.push(input.len());
         Tokenizer {
-            p: Rc::new(Self::read_file(&filename).chars().collect()),
-            filename,
+            p: input.chars().collect(),
+            file,
+            offsets,
             pos: 0,
             tokens: vec![],
         }
     }
 
-    fn read_file(filename: &str) -> String {
+    fn read_file(filename: &str) -> io::Result<String> {
         let mut input = String::new();
-        let mut fp = io::stdin();
-        if filename != &"-".to_string() {
-            let mut fp = File::open(filename).expect("file not found");
-            fp.read_to_string(&mut input)
-                .expect("something went wrong reading the file");
-            return input;
+        if filename != "-" {
+            File::open(filename)?.read_to_string(&mut input)?;
+        } else {
+            io::stdin().read_to_string(&mut input)?;
         }
-        fp.read_to_string(&mut input)
-            .expect("something went wrong reading the file");
-        input
+        Ok(input)
+    }
+
+    // Returns the span of the characters from `start` to the current
+    // position.
+    fn span(&self, start: usize) -> Span {
+        Span::new(self.file, self.offsets[start], self.offsets[self.pos])
     }
 
     fn new_token(&self, ty: TokenType) -> Token {
-        Token::new(ty, self.pos, self.filename.clone(), self.p.clone())
+        let mut t = Token::new(ty, self.span(self.pos));
+        t.bol = self
+            .tokens
+            .last()
+            .is_none_or(|last| last.ty == TokenType::NewLine);
+        t
+    }
+
+    // Lexical errors are not reported right away, because the bad
+    // characters may be in a group skipped by `#if 0`. They are reported
+    // in `tokenize` if they survive preprocessing.
+    fn invalid_token(&mut self, msg: &'static str) {
+        let mut t = self.new_token(TokenType::Invalid(msg));
+        self.pos += 1;
+        t.span = t.span.to(self.span(self.pos));
+        self.tokens.push(t);
     }
 
     // This does not support non-ASCII characters.
@@ -199,29 +449,35 @@ impl Tokenizer {
                 CharacterType::NewLine => {
                     let mut t = self.new_token(TokenType::NewLine);
                     self.pos += 1;
-                    t.end = self.pos;
+                    t.span = t.span.to(self.span(self.pos));
                     self.tokens.push(t);
                 }
                 CharacterType::Whitespace => self.pos += 1,
                 CharacterType::Alphabetic => self.ident(keywords),
                 CharacterType::Digit => self.number(),
 
-                CharacterType::NonAlphabetic('\'') => self.char_literal(),
-                CharacterType::NonAlphabetic('\"') => self.string_literal(),
+                CharacterType::NonAlphabetic('.')
+                    if self.p.get(self.pos + 1).is_some_and(|c| c.is_ascii_digit()) =>
+                {
+                    self.number()
+                }
+                CharacterType::NonAlphabetic('\'') => self.char_literal(self.pos, Encoding::Plain),
+                CharacterType::NonAlphabetic('\"') => self.string_literal(self.pos, Encoding::Plain),
+                CharacterType::NonAlphabetic('<') if self.in_include() => self.header_name(),
                 CharacterType::NonAlphabetic('/') => match self.p.get(self.pos + 1) {
                     Some('/') => self.line_comment(),
                     Some('*') => self.block_comment(),
                     Some('=') => {
                         let mut t = self.new_token(TokenType::DivEQ);
                         self.pos += 2;
-                        t.end = self.pos;
+                        t.span = t.span.to(self.span(self.pos));
                         self.tokens.push(t);
                     }
                     // This is Dividing operator
                     _ => {
                         let mut t = self.new_token(TokenType::Div);
                         self.pos += 1;
-                        t.end = self.pos;
+                        t.span = t.span.to(self.span(self.pos));
                         self.tokens.push(t);
                     }
                 },
@@ -241,7 +497,7 @@ impl Tokenizer {
 
                         let mut t = self.new_token(symbol.ty.clone());
                         self.pos += len;
-                        t.end = self.pos;
+                        t.span = t.span.to(self.span(self.pos));
                         self.tokens.push(t);
                         continue 'outer;
                     }
@@ -250,16 +506,19 @@ impl Tokenizer {
                     if let Some(ty) = TokenType::new_single_letter(c) {
                         let mut t = self.new_token(ty);
                         self.pos += 1;
-                        t.end = self.pos;
+                        t.span = t.span.to(self.span(self.pos));
                         self.tokens.push(t);
                         continue 'outer;
                     }
-                    self.bad_position("Unknown symbol.");
+                    self.invalid_token("Unknown symbol.");
                 }
-                CharacterType::Unknown(_) => self.bad_position("Unknwon character type."),
+                CharacterType::Unknown(_) => self.invalid_token("Unknwon character type."),
             }
         }
 
+        for i in 1..self.tokens.len() {
+            self.tokens[i].has_space = self.tokens[i].span.lo > self.tokens[i - 1].span.hi;
+        }
         self.tokens.clone()
     }
 
@@ -270,6 +529,7 @@ impl Tokenizer {
     }
 
     fn block_comment(&mut self) {
+        let start = self.pos;
         self.pos += 2;
         loop {
             if let Some(two_char) = self.p.get(self.pos..self.pos + 2) {
@@ -279,87 +539,203 @@ impl Tokenizer {
                     return;
                 }
             } else {
-                self.bad_position("unclosed comment");
+                let mut t = self.new_token(TokenType::Invalid("unclosed comment"));
+                self.pos = self.p.len();
+                t.span = self.span(start);
+                self.tokens.push(t);
+                return;
             }
         }
     }
 
-    fn escaped(c: char) -> Option<char> {
-        // Issue: https://github.com/rust-lang/rfcs/issues/751
-        match c {
-            // 'a' => Some('\a'),
-            // 'b' => Some('\b'),
-            // 'f' => Some('\f'),
-            'n' => Some('\n'),
-            'r' => Some('\r'),
-            't' => Some('\t'),
-            // 'v' => Some('\v'),
-            _ => None,
+    // Reads an escape sequence whose backslash is just before `pos` and
+    // returns its value and length.
+    fn escaped(&self, pos: usize) -> Result<(u32, usize), &'static str> {
+        let c = match self.p.get(pos) {
+            Some(c) => *c,
+            None => return Ok((0, 0)),
+        };
+
+        // Octal escapes have up to three digits.
+        if c.is_digit(8) {
+            let len = self.p[pos..]
+                .iter()
+                .take(3)
+                .take_while(|c| c.is_digit(8))
+                .count();
+            let val = self.p[pos..pos + len]
+                .iter()
+                .fold(0, |acc, c| acc * 8 + c.to_digit(8).unwrap());
+            return Ok((val, len));
+        }
+
+        // Hexadecimal escapes end at the first non-hex-digit.
+        if c == 'x' {
+            let len = self.p[pos + 1..]
+                .iter()
+                .take_while(|c| c.is_ascii_hexdigit())
+                .count();
+            if len == 0 {
+                return Err("\\x used with no following hex digits");
+            }
+            let val = self.p[pos + 1..pos + 1 + len]
+                .iter()
+                .fold(0u32, |acc, c| acc.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap()));
+            return Ok((val, len + 1));
         }
+
+        let val = match c {
+            'a' => 7,
+            'b' => 8,
+            'e' => 27, // GNU extension
+            'f' => 12,
+            'n' => 10,
+            'r' => 13,
+            't' => 9,
+            'v' => 11,
+            _ => c as u32,
+        };
+        Ok((val, 1))
     }
 
-    fn char_literal(&mut self) {
-        self.pos += 1;
-        let result: char;
-        let c = self.p.get(self.pos).expect("premature end of input");
-        if c != &'\\' {
-            result = *c;
-            self.pos += 1;
-        } else {
-            self.pos += 1;
-            let c2 = self.p.get(self.pos).unwrap();
-            result = if let Some(esc) = Self::escaped(*c2) {
-                esc
-            } else {
-                *c2
+    // Reads the characters of a string or character literal up to the
+    // closing quote, as code units in the given encoding. After an error,
+    // the rest of the literal is still read.
+    fn literal_units(&mut self, quote: char, enc: Encoding) -> Result<Vec<u32>, &'static str> {
+        let max = match enc {
+            Encoding::Plain | Encoding::UTF8 => 0xFF,
+            Encoding::UTF16 => 0xFFFF,
+            Encoding::UTF32 | Encoding::Wide => u32::MAX,
+        };
+        let mut units = vec![];
+        let mut err = None;
+        loop {
+            let c = match self.p.get(self.pos) {
+                Some(c) if c != &'\n' => *c,
+                _ if quote == '"' => return Err("unclosed string literal"),
+                _ => return Err("unclosed character literal"),
             };
             self.pos += 1;
-        }
+            if c == quote {
+                return err.map_or(Ok(units), Err);
+            }
+            if c != '\\' {
+                encode(c, enc, &mut units);
+                continue;
+            }
+            if self.p.get(self.pos) == Some(&'\n') {
+                continue;
+            }
+
+            // Universal character names are encoded as characters are,
+            // while other escapes are code units.
+            let ucn_len = match self.p.get(self.pos) {
+                Some('u') => 4,
+                Some('U') => 8,
+                _ => 0,
+            };
+            if ucn_len > 0 {
+                let digits: String = self.p[self.pos + 1..]
+                    .iter()
+                    .take(ucn_len)
+                    .take_while(|c| c.is_ascii_hexdigit())
+                    .collect();
+                self.pos += digits.len() + 1;
+                let c = u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() == ucn_len)
+                    .and_then(char::from_u32);
+                match c {
+                    Some(c) => encode(c, enc, &mut units),
+                    None => err = err.or(Some("invalid universal character name")),
+                }
+                continue;
+            }
 
-        if self.p.get(self.pos) != Some(&'\'') {
-            panic!("unclosed character literal");
+            let (val, len) = self.escaped(self.pos).unwrap_or_else(|msg| {
+                err = err.or(Some(msg));
+                (0, 1)
+            });
+            self.pos += len;
+            if val > max {
+                err = err.or(Some("escape sequence out of range"));
+            }
+            units.push(val);
         }
+    }
 
-        let mut t = self.new_token(TokenType::Num(result as u8 as i32));
+    // `start` is the position of the encoding prefix, if any.
+    fn char_literal(&mut self, start: usize, enc: Encoding) {
         self.pos += 1;
-        t.end = self.pos + 1;
+        let ty = self
+            .literal_units('\'', enc)
+            .and_then(|units| char_value(&units, enc));
+        let mut t = self.new_token(ty.unwrap_or_else(TokenType::Invalid));
+        t.span = self.span(start);
         self.tokens.push(t);
     }
 
-    fn string_literal(&mut self) {
+    // True if the next token is the operand of `#include`.
+    fn in_include(&self) -> bool {
+        match self.tokens.as_slice() {
+            [.., hash, name] => hash.ty == TokenType::HashMark && hash.bol && name.is_ident("include"),
+            _ => false,
+        }
+    }
+
+    fn header_name(&mut self) {
+        let len = self.p[self.pos..]
+            .iter()
+            .take_while(|c| **c != '\n')
+            .position(|c| *c == '>');
+        let len = match len {
+            Some(len) => len,
+            None => return self.invalid_token("missing terminating > character"),
+        };
+        let name = self.p[self.pos + 1..self.pos + len].iter().collect();
+        let mut t = self.new_token(TokenType::HeaderName(name));
+        self.pos += len + 1;
+        t.span = t.span.to(self.span(self.pos));
+        self.tokens.push(t);
+    }
+
+    // `start` is the position of the encoding prefix, if any.
+    fn string_literal(&mut self, start: usize, enc: Encoding) {
         self.pos += 1;
-        let mut sb = String::new();
-        let mut len = 0;
-        loop {
-            let mut c2 = self.p.get(self.pos + len).expect("PREMATURE end of input");
-            if c2 == &'"' {
-                len += 1;
-                self.pos += len;
-                let mut t = self.new_token(TokenType::Str(sb, len));
-                t.start = self.pos - len - 1;
-                t.end = self.pos + 1;
-                self.tokens.push(t);
-                return;
-            }
+        let ty = self
+            .literal_units('"', enc)
+            .map(|units| TokenType::Str(units, enc));
+        let mut t = self.new_token(ty.unwrap_or_else(TokenType::Invalid));
+        t.span = self.span(start);
+        self.tokens.push(t);
+    }
 
-            if c2 != &'\\' {
-                len += 1;
-                sb.push(*c2);
+    fn ident(&mut self, keywords: &HashMap<String, TokenType>) {
+        let prefixes = [
+            ("u8", Encoding::UTF8),
+            ("u", Encoding::UTF16),
+            ("U", Encoding::UTF32),
+            ("L", Encoding::Wide),
+        ];
+        for (prefix, enc) in prefixes {
+            let start = self.pos;
+            let end = start + prefix.len();
+            if !self.p[start..].iter().copied().take(prefix.len()).eq(prefix.chars()) {
                 continue;
             }
-
-            len += 1;
-            c2 = self.p.get(self.pos + len).unwrap();
-            if let Some(esc) = Self::escaped(*c2) {
-                sb.push(esc);
-            } else {
-                sb.push(*c2);
+            match self.p.get(end) {
+                Some('"') => {
+                    self.pos = end;
+                    return self.string_literal(start, enc);
+                }
+                Some('\'') => {
+                    self.pos = end;
+                    return self.char_literal(start, enc);
+                }
+                _ => (),
             }
-            len += 1;
         }
-    }
 
-    fn ident(&mut self, keywords: &HashMap<String, TokenType>) {
         let mut len = 1;
         while let Some(c2) = self.p.get(self.pos + len) {
             if c2.is_alphabetic() || c2.is_ascii_digit() || c2 == &'_' {
@@ -377,107 +753,149 @@ impl Tokenizer {
             t = self.new_token(TokenType::Ident(name.clone()));
         }
         self.pos += len;
-        t.end = self.pos;
+        t.span = t.span.to(self.span(self.pos));
         self.tokens.push(t);
     }
 
     fn number(&mut self) {
-        match self.p.get(self.pos..self.pos + 2) {
-            Some(&['0', 'x']) | Some(&['0', 'X']) => {
-                self.pos += 2;
-                self.parse_number(16);
-            }
-            Some(&['0', _]) => {
-                self.parse_number(8);
-            }
-            _ => self.parse_number(10),
+        if self.float_number() {
+            return;
         }
-    }
 
-    fn parse_number(&mut self, base: u32) {
-        let mut sum: i32 = 0;
-        let mut len = 0;
-        for c in self.p[self.pos..].iter() {
-            if let Some(val) = c.to_digit(base) {
-                sum = sum * base as i32 + val as i32;
-                len += 1;
+        let start = self.pos;
+        let base = match self.p.get(self.pos..self.pos + 2) {
+            Some(&['0', 'x']) | Some(&['0', 'X']) => 16,
+            Some(&['0', 'b']) | Some(&['0', 'B']) => 2,
+            Some(&['0', _]) => 8,
+            _ => 10,
+        };
+        if base == 16 || base == 2 {
+            self.pos += 2;
+        }
+
+        // Digits may be separated by `'` as in C23.
+        let mut val: Option<u64> = Some(0);
+        let mut ndigits = 0;
+        while let Some(c) = self.p.get(self.pos) {
+            if let Some(d) = c.to_digit(base) {
+                val = val.and_then(|v| v.checked_mul(base as u64)?.checked_add(d as u64));
+                ndigits += 1;
+                self.pos += 1;
+            } else if *c == '\''
+                && ndigits > 0
+                && self.p.get(self.pos + 1).is_some_and(|c| c.is_digit(base))
+            {
+                self.pos += 1;
             } else {
                 break;
             }
         }
-        let mut t = self.new_token(TokenType::Num(sum));
-        self.pos += len;
-        t.end = self.pos;
+
+        let suffix_len = self.p[self.pos..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .count();
+        let suffix: String = self.p[self.pos..self.pos + suffix_len].iter().collect();
+        self.pos += suffix_len;
+
+        let ty = match (val, int_type(val.unwrap_or(0), base, &suffix)) {
+            _ if ndigits == 0 && base != 8 => Err("invalid integer constant"),
+            _ if base == 8 && suffix.starts_with(['8', '9']) => Err("invalid digit in octal constant"),
+            (None, _) => Err("integer constant is too large"),
+            (_, None) => Err("invalid suffix on integer constant"),
+            (Some(val), Some(ty)) => Ok(TokenType::Num(val as i64, ty)),
+        };
+        let mut t = self.new_token(ty.unwrap_or_else(TokenType::Invalid));
+        t.span = self.span(start);
         self.tokens.push(t);
     }
 
-    fn canonicalize_newline(&mut self) {
-        let mut pos = 0;
-        while pos < self.p.len() {
-            if self.p[pos] == '\r' && self.p[pos + 1] == '\n' {
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-            }
-            pos += 1;
-        }
-    }
-
-    // Quoted from oxide
-    // > Concatenates continuation lines. We keep the total number of
-    // > newline characters the same to keep the line counter sane.
-    fn remove_backslash_newline(&mut self) {
-        let mut pos = 0;
-        let mut cnt = 0;
-        while pos < self.p.len() {
-            if self.p[pos] == '\\' && self.p[pos + 1] == '\n' {
-                cnt += 1;
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                Rc::get_mut(&mut self.p).unwrap().remove(pos);
-                pos += 1;
-            } else if self.p[pos] == '\n' {
-                for _ in 0..cnt {
-                    Rc::get_mut(&mut self.p).unwrap().insert(pos, '\n');
-                    pos += 1;
-                }
-                pos += 1;
-                cnt = 0;
-            } else {
-                pos += 1;
-            }
+    // Reads a floating constant such as `1.5`, `.5e-3f` or `0x1.8p3`.
+    // Returns false without reading anything if the number is an integer.
+    fn float_number(&mut self) -> bool {
+        let start = self.pos;
+        let hex = matches!(
+            self.p.get(self.pos..self.pos + 2),
+            Some(&['0', 'x']) | Some(&['0', 'X'])
+        );
+        let base = if hex { 16 } else { 10 };
+        if hex {
+            self.pos += 2;
         }
-    }
 
-    fn append(&mut self, x_str: &str, y_str: &str, start: usize) -> Token {
-        let concated = format!("{}{}", x_str, y_str);
-        let l = concated.len() + 1; // Because `+1` has `\0`.
-        Token::new(
-            TokenType::Str(concated, l),
-            start,
-            self.filename.clone(),
-            self.p.clone(),
-        )
-    }
+        let mut digits = String::new();
+        let mut frac_digits = None;
+        while let Some(c) = self.p.get(self.pos) {
+            if c.is_digit(base) {
+                digits.push(*c);
+                frac_digits = frac_digits.map(|n| n + 1);
+            } else if *c == '.' && frac_digits.is_none() {
+                frac_digits = Some(0);
+            } else if !(*c == '\''
+                && !digits.is_empty()
+                && self.p.get(self.pos + 1).is_some_and(|c| c.is_digit(base)))
+            {
+                break;
+            }
+            self.pos += 1;
+        }
 
-    fn join_string_literals(&mut self) {
-        let mut v = vec![];
-        let mut last_may: Option<Token> = None;
+        let exp_mark: &[char] = if hex { &['p', 'P'] } else { &['e', 'E'] };
+        let has_exp = self.p.get(self.pos).is_some_and(|c| exp_mark.contains(c));
+        if frac_digits.is_none() && !has_exp {
+            self.pos = start;
+            return false;
+        }
 
-        for t in self.tokens.clone().into_iter() {
-            if let Some(ref last) = last_may {
-                if let (TokenType::Str(ref last_str, _), TokenType::Str(ref t_str, _)) =
-                    (&last.ty, &t.ty)
-                {
-                    let new = self.append(last_str, t_str, last.start);
-                    v.pop();
-                    v.push(new);
-                    continue;
-                }
+        let mut exp = String::new();
+        if has_exp {
+            self.pos += 1;
+            if let Some(c @ ('+' | '-')) = self.p.get(self.pos) {
+                exp.push(*c);
+                self.pos += 1;
+            }
+            while let Some(c) = self.p.get(self.pos).filter(|c| c.is_ascii_digit()) {
+                exp.push(*c);
+                self.pos += 1;
             }
-
-            last_may = Some(t.clone());
-            v.push(t);
         }
-        self.tokens = v;
+
+        let suffix_len = self.p[self.pos..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .count();
+        let suffix: String = self.p[self.pos..self.pos + suffix_len].iter().collect();
+        self.pos += suffix_len;
+
+        let ty = match &*suffix {
+            "" | "l" | "L" => Some(FloatType::Double),
+            "f" | "F" => Some(FloatType::Float),
+            _ => None,
+        };
+        let exp: Option<i32> = if has_exp { exp.parse().ok() } else { Some(0) };
+        let ty = match (ty, exp) {
+            _ if digits.is_empty() => Err("invalid floating constant"),
+            _ if hex && !has_exp => Err("hexadecimal floating constant requires an exponent"),
+            (_, None) => Err("exponent has no digits"),
+            (None, _) => Err("invalid suffix on floating constant"),
+            (Some(ty), Some(exp)) => {
+                let frac_digits = frac_digits.unwrap_or(0);
+                let val = if hex {
+                    let mantissa = digits
+                        .chars()
+                        .fold(0.0, |m, c| m * 16.0 + c.to_digit(16).unwrap() as f64);
+                    mantissa * 2f64.powi(exp - 4 * frac_digits)
+                } else {
+                    let (int, frac) = digits.split_at(digits.len() - frac_digits as usize);
+                    format!("{}.{}e{}", int, frac, exp).parse().unwrap()
+                };
+                Ok(TokenType::FNum(val, ty))
+            }
+        };
+        let mut t = self.new_token(ty.unwrap_or_else(TokenType::Invalid));
+        t.span = self.span(start);
+        self.tokens.push(t);
+        true
     }
 
     fn strip_newlines_tokens(&mut self) {
@@ -488,48 +906,26 @@ impl Tokenizer {
             .filter(|t| t.ty != TokenType::NewLine)
             .collect()
     }
-
-    fn bad_position(&self, msg: &'static str) {
-        print_line(&self.p, &self.filename, self.pos);
-        panic!("{}", msg);
-    }
 }
 
-// Finds a line pointed by a given pointer from the input file
-// to print it out.
-fn print_line(buf: &[char], path: &str, pos: usize) {
-    let mut p = 0;
-    let mut start = 0;
-    let mut line = 0;
-    let mut col = 0;
-    for c in buf.iter() {
-        if c == &'\n' {
-            start = pos + 1;
-            line += 1;
-            col = 0;
-            p += 1;
+// Quoted from oxide
+// > Concatenates continuation lines. We keep the total number of
+// > newline characters the same to keep the line counter sane.
+fn remove_backslash_newline(input: &str) -> String {
+    let mut s = String::new();
+    let mut cnt = 0;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'\n') {
+            chars.next();
+            cnt += 1;
             continue;
         }
-
-        if p != pos {
-            col += 1;
-            p += 1;
-            continue;
+        if c == '\n' {
+            s.push_str(&"\n".repeat(cnt));
+            cnt = 0;
         }
-
-        print!("error at {}:{}:{}\n\n", path, line + 1, col);
-        break;
-    }
-
-    for p in buf[start..].iter() {
-        if p == &'\n' {
-            break;
-        }
-        print!("{}", p);
-    }
-    println!();
-    for _ in 0..col - 1 {
-        print!(" ");
+        s.push(c);
     }
-    print!("^\n\n");
+    s
 }
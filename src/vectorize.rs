@@ -1,4 +1,5 @@
 use crate::gen_ir::{Function, IROp, IR};
+use crate::parse::Pragma;
 
 // Detects if a loop can be vectorized
 fn can_vectorize_loop(ir: &[IR]) -> bool {
@@ -26,7 +27,7 @@ fn can_vectorize_loop(ir: &[IR]) -> bool {
         
         // Look for regular memory access (consecutive loads/stores)
         if let IROp::Load(_) = ir[i].op {
-            if i > 0 && i < ir.len() - 1 {
+            if i > 0 && i + 1 < ir.len() {
                 if let IROp::Load(_) = ir[i-1].op {
                     if let IROp::Load(_) = ir[i+1].op {
                         has_regular_access = true;
@@ -91,7 +92,7 @@ fn detect_reduction_patterns(ir: &[IR]) -> Vec<(usize, usize, IROp)> {
     let mut reductions = Vec::new();
     let mut i = 0;
     
-    while i < ir.len() - 2 {
+    while i + 2 < ir.len() {
         // Look for load followed by add/min/max and store to same variable
         if matches!(ir[i].op, IROp::Load(_)) {
             let load_dst = ir[i].lhs;
@@ -106,11 +107,13 @@ fn detect_reduction_patterns(ir: &[IR]) -> Vec<(usize, usize, IROp)> {
                 };
                 
                 if let Some(reduction_op) = op {
-                    if i + 2 < ir.len() && matches!(ir[i+2].op, IROp::Store(_)) {
-                        if ir[i+2].rhs == load_dst && ir[i+2].lhs == load_src {
-                            // Found a reduction pattern
-                            reductions.push((i, i+2, reduction_op));
-                        }
+                    if i + 2 < ir.len()
+                        && matches!(ir[i+2].op, IROp::Store(_))
+                        && ir[i+2].rhs == load_dst
+                        && ir[i+2].lhs == load_src
+                    {
+                        // Found a reduction pattern
+                        reductions.push((i, i+2, reduction_op));
                     }
                 }
             }
@@ -176,15 +179,15 @@ fn convert_to_avx512(ir: &mut [IR]) {
 // Apply vectorization to specific ranges of IR instructions
 fn vectorize_ranges(ir: &mut [IR], ranges: &[(usize, usize)]) {
     for &(start, end) in ranges {
-        for i in start..=end {
-            match ir[i].op {
-                IROp::Add => ir[i].op = IROp::AVX512Add,
-                IROp::Sub => ir[i].op = IROp::AVX512Sub,
-                IROp::Mul => ir[i].op = IROp::AVX512Mul,
-                IROp::Div => ir[i].op = IROp::AVX512Div,
-                IROp::Load(_) => ir[i].op = IROp::AVX512Load,
-                IROp::Store(_) => ir[i].op = IROp::AVX512Store,
-                IROp::Mov => ir[i].op = IROp::AVX512Mov,
+        for item in &mut ir[start..=end] {
+            match item.op {
+                IROp::Add => item.op = IROp::AVX512Add,
+                IROp::Sub => item.op = IROp::AVX512Sub,
+                IROp::Mul => item.op = IROp::AVX512Mul,
+                IROp::Div => item.op = IROp::AVX512Div,
+                IROp::Load(_) => item.op = IROp::AVX512Load,
+                IROp::Store(_) => item.op = IROp::AVX512Store,
+                IROp::Mov => item.op = IROp::AVX512Mov,
                 _ => {}
             }
         }
@@ -205,7 +208,8 @@ fn has_array_operations(f: &Function) -> bool {
 fn optimize_math_functions(ir: &mut [IR]) {
     let mut i = 0;
     while i < ir.len() {
-        if let IROp::Call(ref name, nargs, _) = ir[i].op {
+        if let IROp::Call(ref name, ref args, _) = ir[i].op {
+            let nargs = args.len();
             let _lhs = ir[i].lhs.unwrap_or(0);
             
             // Replace common math functions with SIMD instructions
@@ -232,8 +236,18 @@ fn optimize_math_functions(ir: &mut [IR]) {
 }
 
 // Main vectorization function
-pub fn vectorize(fns: &mut Vec<Function>) {
+// `enabled` is false with -no-vec. `#pragma oxide vectorize(on|off)`
+// before a function overrides it for that function.
+pub fn vectorize(fns: &mut Vec<Function>, enabled: bool) {
     for f in fns {
+        let on = f.pragmas.iter().rev().find_map(|p| match p {
+            Pragma::Vectorize(on) => Some(*on),
+            _ => None,
+        });
+        if !on.unwrap_or(enabled) {
+            continue;
+        }
+
         // Strategy 1: Check for vectorizable loops
         if can_vectorize_loop(&f.ir) {
             convert_to_avx512(&mut f.ir);
@@ -1,3 +1,12 @@
 // This file is compiled by gcc.
 
  int global_arr[1] = {5};
+
+float gcc_twice(float x) { return x * 2; }
+
+double gcc_many(int a, int b, int c, int d, int e, int f, int g, char h,
+                float x0, double x1, double x2, double x3, double x4,
+                double x5, double x6, double x7, float x8, double x9) {
+  return a + b + c + d + e + f + g * 10 + h * 100 + x0 + x1 + x2 + x3 + x4 +
+         x5 + x6 + x7 + x8 * 1000 + x9 * 10000;
+}
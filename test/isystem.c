@@ -0,0 +1,6 @@
+// Compiled with -isystem test/isystem1 -isystem test/isystem2, which
+// both have dir.h.
+#include <dir.h>
+#if ISYSTEM_DIR != 1
+#error "-isystem directories must be searched in command-line order"
+#endif
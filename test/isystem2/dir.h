@@ -0,0 +1 @@
+#define ISYSTEM_DIR 2
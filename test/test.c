@@ -17,6 +17,17 @@ int exit();
     }                                                           \
   } while (0)
 
+#define DEFINE_GETTER(name) int get_##name() { return 7; }
+#define PLUS(x, ...) plus(x, __VA_ARGS__)
+#define SUM(x, ...) x __VA_OPT__(+ __VA_ARGS__)
+#define FIRST(x, ...) one(x, ## __VA_ARGS__)
+#define CAT(x, y) x ## y
+#define XCAT(x, y) CAT(x, y)
+#define SELF SELF
+#define TWICE(x) mul(x, 2)
+
+DEFINE_GETTER(seven)
+
 int one() { return 1; }
 int two() { return 2; }
 int plus(int x, int y) { return x + y; }
@@ -26,10 +37,87 @@ int add2(int (*a)[2]) { return a[0][0] + a[1][0]; }
 int add3(int a[][2]) { return a[0][0] + a[1][0]; }
 int add4(int a[2][2]) { return a[0][0] + a[1][0]; }
 void nop() {}
+double half(double x) { return x / 2; }
+float twice(float x) { return x * 2; }
+double mix(int a, double b, int c, float d) { return a + b * c - d; }
+int add8(int a, int b, int c, int d, int e, int f, int g, int h) { return a+b+c+d+e+f+g*10+h*100; }
+double fsum10(double a, double b, double c, double d, double e, double f, double g, double h, float i, double j) { return a+b+c+d+e+f+g+h+i*10+j*100; }
+
+#pragma oxide vectorize(off)
+#pragma GCC diagnostic push
+int sum(int n) {
+  int s = 0;
+#pragma oxide unroll(4)
+  for (int i = 0; i < n; i++)
+    s = s + i;
+#pragma GCC diagnostic ignored "-Wunused"
+  _Pragma("GCC diagnostic pop") return s;
+}
+
+int dense_switch(int x) {
+  switch (x) {
+  case 0: return 10;
+  case 1: return 11;
+  case 2:
+  case 3: return 13;
+  case 5: return 15;
+  default: return -1;
+  }
+}
+
+int sparse_switch(int x) {
+  int r = 0;
+  switch (x) {
+  case -1000: r = 1; break;
+  case 7: r = 2; break;
+  case 100: r = 3; break;
+  case 5000: r = 4; break;
+  case 1 << 20: r = 5; break;
+  }
+  return r;
+}
+
+int goto_fail(int n) {
+  int r = 0;
+  if (n < 0)
+    goto fail;
+  r = n * 2;
+  goto out;
+fail:
+  r = -1;
+out:
+  return r;
+}
+
+enum color { RED, GREEN = 5, BLUE, };
+enum { FLAG = 1 << 3, MASK = FLAG * 2 - 1 };
+typedef enum { OFF, ON } state;
+
+union num { char c; int i; double d; };
+
+struct msg {
+  int kind;
+  union {
+    struct { int x; int y; };
+    double f;
+  };
+  int tail;
+};
+
+int sub(int x, int y) { return x - y; }
+int apply(int (*f)(int, int), int x, int y) { return f(x, y); }
+int (*pick(int i))(int, int) { if (i) return mul; return plus; }
+typedef int binop(int, int);
+double halve(double x) { return x / 2; }
+int counter;
+void bump(void) { counter = counter + 1; }
+void double_counter(void) { counter = counter * 2; }
 
 int var1;
 int var2[5];
 extern int global_arr[1];
+float gcc_twice(float x);
+double gcc_many(int a, int b, int c, int d, int e, int f, int g, char h, float x0, double x1, double x2, double x3, double x4, double x5, double x6, double x7, float x8, double x9);
 typedef int myint;
 
 // Single-line comment test
@@ -97,6 +185,7 @@ int main() {
 
   EXPECT(8, 1 << 3);
   EXPECT(4, 16 >> 2);
+  EXPECT(-4, -8 >> 1);
 
   EXPECT(4, 19 % 5);
   EXPECT(0, 9 % 3);
@@ -168,8 +257,88 @@ int main() {
   EXPECT('c', ({ char *p = "abc"; return p[2]; }));
   EXPECT(0, ({ char *p = "abc"; return p[3]; }));
   EXPECT(7, sizeof("abc" "def"));
+  EXPECT(3, sizeof("\x41\101"));
+
+  EXPECT(7, '\a');
+  EXPECT(8, '\b');
+  EXPECT(12, '\f');
+  EXPECT(11, '\v');
+  EXPECT(0, '\0');
+  EXPECT(65, '\101');
+  EXPECT(65, '\x41');
+  EXPECT(-1, '\377');
+  EXPECT(65, ({ char *p = "\x41"; return p[0]; }));
+
+  EXPECT(511, 0777);
+  EXPECT(5, 0b101);
+  EXPECT(255, 0XfF);
+  EXPECT(1000000, 1'000'000);
+  EXPECT(4, sizeof(2147483647));
+  EXPECT(8, sizeof(2147483648));
+  EXPECT(4, sizeof(0xFFFFFFFF));
+  EXPECT(8, sizeof(0x100000000));
+  EXPECT(4, sizeof(1u));
+  EXPECT(8, sizeof(1L));
+  EXPECT(8, sizeof(1ll));
+  EXPECT(8, sizeof(1ULL));
+  EXPECT(8, sizeof(1 + 1L));
+  EXPECT(1, 0xFFFFFFFF > 0);
+  EXPECT(0, -1 < 0u);
+  EXPECT(2147483647, 0xFFFFFFFFu / 2);
+  EXPECT(1, 0xFFFFFFFF + 1 == 0);
+  EXPECT(2147483646, -3u / 2);
+  EXPECT(1, 65536u * 65536u == 0);
+  EXPECT(1, 1u << 31 << 1 == 0);
+  EXPECT(2147483647, (0u - 1) / 2);
+  EXPECT(4, (1ULL << 40) >> 38);
+  EXPECT(1, 1ULL << 40 == 1099511627776);
   EXPECT(9, sizeof("ab\0c" "\0def"));
 
+  EXPECT(8, sizeof(1.5));
+  EXPECT(4, sizeof(1.5f));
+  EXPECT(8, sizeof(1.5L));
+  EXPECT(4, ({ float f; return sizeof(f); }));
+  EXPECT(8, sizeof(1 + 1.5f + 1.5));
+  EXPECT(1500, 1.5e3);
+  EXPECT(5, .5 * 10);
+  EXPECT(12, 0x1.8p3);
+  EXPECT(1000, 1'000.25);
+  EXPECT(6, 3 / 2.0 * 4);
+  EXPECT(-3, -1.5 * 2);
+  EXPECT(1, 0.1 + 0.2 != 0.3);
+  EXPECT(1, 1.5 < 2 && 2 > 1.5 && 2.0 <= 2 && !(2.0 < 2));
+  EXPECT(7, ({ float f = 7.9; return f; }));
+  EXPECT(15, ({ float f = .5f; f++; return f * 10; }));
+  EXPECT(2, ({ double d = 1; d -= 0.75; d *= 8; return d; }));
+  EXPECT(7, ({ int i = 7; i += 0.5; return i; }));
+  EXPECT(20, ({ double z = -0.0; return z ? 10 : 20; }));
+  EXPECT(1, ({ double z = 0; return !z; }));
+  EXPECT(15, half(30.0));
+  EXPECT(10, twice(5.0f));
+  EXPECT(10, mix(1, 2.5, 4, 0.5f));
+  EXPECT(891, add8(1, 2, 3, 4, 5, 6, 7, 8));
+  EXPECT(1981, add8(1, 2, 3, 4, 5, 6, add8(1, 1, 1, 1, 1, 1, 1, 1), 8));
+  EXPECT(891, ({ int (*fp)(int, int, int, int, int, int, int, int) = add8; return fp(1, 2, 3, 4, 5, 6, 7, 8); }));
+  EXPECT(213, fsum10(1, 1, 1, 1, 1, 1, 1, 1, 0.5f, 2));
+  EXPECT(5, gcc_twice(2.5f));
+  EXPECT(6148, gcc_many(1, 2, 3, 4, 5, 6, 7, 8, 0.5f, 1, 1, 1, 1, 1, 1, 1, 0.25f, 0.5));
+
+  EXPECT(12, sizeof(L"ab"));
+  EXPECT(6, sizeof(u"ab"));
+  EXPECT(12, sizeof(U"ab"));
+  EXPECT(3, sizeof(u8"é"));
+  EXPECT(3, sizeof("\u00e9"));
+  EXPECT(98, L"ab"[1]);
+  EXPECT(233, u"é"[0]);
+  EXPECT(56832, u"\U0001F600"[1]);
+  EXPECT(24930, 'ab');
+  EXPECT(2, sizeof(u'a'));
+  EXPECT(4, sizeof(U'a'));
+  EXPECT(233, L'é');
+  EXPECT(65535, u'\xffff');
+  EXPECT(12, sizeof("a" L"b"));
+  EXPECT(233, U"x" "\u00e9"[1]);
+
   EXPECT(1, ({ int x = 1; { int x = 2; } return x; }));
 
   EXPECT(0, var1);
@@ -221,6 +390,95 @@ int main() {
 
   EXPECT(11, ({ int x[3] = {10, 11, 12}; return x[1]; }));
 
+  EXPECT(7, get_seven());
+
+  int va1 = PLUS(3, 4);
+  int va2 = SUM(5);
+  int va3 = SUM(5, 6);
+  int va4 = FIRST();
+  EXPECT(7, va1);
+  EXPECT(5, va2);
+  EXPECT(11, va3);
+  EXPECT(1, va4);
+
+  int SELF = 3;
+  EXPECT(12, CAT(1, 2));
+  EXPECT(3, XCAT(SE, LF));
+  EXPECT(8, TWICE(TWICE(2)));
+
+  EXPECT(1, __STDC__);
+  EXPECT(4, __SIZEOF_INT__);
+  EXPECT(8, __SIZEOF_POINTER__);
+  EXPECT(0, __COUNTER__);
+  EXPECT(1, __COUNTER__);
+  EXPECT(1, sizeof(__FILE__) > 1);
+  EXPECT(3, __builtin_expect(3, 1));
+  EXPECT(10, sum(5));
+
+  EXPECT(10, dense_switch(0));
+  EXPECT(13, dense_switch(2));
+  EXPECT(13, dense_switch(3));
+  EXPECT(-1, dense_switch(4));
+  EXPECT(15, dense_switch(5));
+  EXPECT(-1, dense_switch(-1));
+  EXPECT(1, sparse_switch(-1000));
+  EXPECT(3, sparse_switch(100));
+  EXPECT(5, sparse_switch(1048576));
+  EXPECT(0, sparse_switch(8));
+  EXPECT(111, ({ int x=1; switch (x) { case 1: x+=10; case 2: x+=100; break; case 3: x=0; } return x; }));
+  EXPECT(4, ({ int x=4; switch (x) { case 1: x=0; } return x; }));
+  EXPECT(3, ({ int n=0; for (int i=0; i<5; i++) switch (i) { case 1: case 3: break; default: n++; } return n; }));
+  EXPECT(2, ({ int n=0; switch (1) { case 1: switch (2) { case 2: n=2; break; } break; default: n=5; } return n; }));
+  EXPECT(2, ({ char c='b'; int n=0; switch (c) { case 'a': n=1; break; case 'b': n=2; break; } return n; }));
+  EXPECT(1, ({ int c=-1; int n=0; switch (c + 0u) { case -1: n=1; break; case 2: n=2; } return n; }));
+
+  EXPECT(6, ({ int n=0; for (int i=0; i<6; i++) { if (i%2) continue; n+=i; } return n; }));
+  EXPECT(4, ({ int i=0; int n=0; do { i++; if (i==2) continue; n++; } while (i<5); return n; }));
+  EXPECT(3, ({ int n=0; for (int i=0; i<4; i++) switch (i) { case 1: continue; default: n++; } return n; }));
+  EXPECT(8, goto_fail(4));
+  EXPECT(-1, goto_fail(-4));
+  EXPECT(5, ({ int k=0; again: k++; if (k<5) goto again; return k; }));
+
+  EXPECT(0, RED);
+  EXPECT(5, GREEN);
+  EXPECT(6, BLUE);
+  EXPECT(15, MASK);
+  EXPECT(1, ({ state s = ON; return s; }));
+  EXPECT(4, ({ enum color c = BLUE; return sizeof(c); }));
+  EXPECT(11, ({ enum { RED = 10, NEXT }; return NEXT; }));
+  EXPECT(0, RED);
+  EXPECT(2, ({ enum color c = BLUE; int n=0; switch (c) { case RED: n=1; break; case BLUE: n=2; break; } return n; }));
+
+  EXPECT(8, ({ union num u; return sizeof(u); }));
+  EXPECT(8, ({ union { int a; char b[5]; } u; return sizeof(u); }));
+  EXPECT(68, ({ union num u; u.i = 0x41424344; return u.c; }));
+  EXPECT(24, ({ struct msg m; return sizeof(m); }));
+  EXPECT(15, ({ struct msg m; m.kind = 1; m.x = 7; m.y = 8; m.tail = 9; return m.x + m.y; }));
+  EXPECT(9, ({ struct msg m; m.tail = 9; m.y = 3; return m.tail; }));
+  EXPECT(5, ({ struct msg m; struct msg *p = &m; p->y = 5; return m.y; }));
+  EXPECT(12, ({ struct { char a; struct { char b; int c; } s; } x; return sizeof(x); }));
+
+  EXPECT(3, (int)3.7);
+  EXPECT(44, (char)300);
+  EXPECT(1, (char)-1 == 255);
+  EXPECT(65, ({ int x = 65; return *(char *)&x; }));
+  EXPECT(4, sizeof(int));
+  EXPECT(8, sizeof(int *));
+  EXPECT(12, sizeof(int[3]));
+  EXPECT(8, sizeof(int (*)(int, char *)));
+  EXPECT(32, sizeof(void (*[4])(void)));
+  EXPECT(12, ({ int (*p)[3]; return sizeof(*p); }));
+  EXPECT(8, _Alignof(double));
+  EXPECT(5, ({ int (*fp)(int, int) = plus; return fp(2, 3); }));
+  EXPECT(6, ({ int (*fp)(int, int) = &mul; return (*fp)(2, 3); }));
+  EXPECT(7, apply(sub, 9, 2));
+  EXPECT(18, pick(1)(9, 2));
+  EXPECT(11, pick(0)(9, 2));
+  EXPECT(3, ({ binop *op = plus; return op(1, 2); }));
+  EXPECT(3, ({ void (*handlers[4])(void); handlers[0] = bump; handlers[1] = double_counter; handlers[2] = bump; counter = 0; for (int i = 0; i < 3; i++) handlers[i](); return counter; }));
+  EXPECT(3, (int)halve(7));
+  EXPECT(1, halve(3) == 1.5);
+
   printf("OK\n");
   return 0;
  }
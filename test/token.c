@@ -9,5 +9,57 @@ to this line
 **
 */
 
-#include "test/test1.inc"
+#if 0
+#include "test/no-such-file.inc"
+Skipped groups are not tokenized, so this isn't an error: @ `
+#if 1
+#else
+#endif
+#elif defined(COND) || defined COND || 1 + 2 * 3 == 7
+#define COND 1
+#else
+#error "unreachable"
+#endif
+
+#ifndef COND
+#error "unreachable"
+#elif COND != 1 || (0 && 1 / 0)
+#error "unreachable"
+#endif
+
+// Operands are converted as in C: -1 becomes UINTMAX_MAX next to 0u.
+#if -1 < 0u || -6 / 4u < 1000 || (0u - 1) >> 63 != 1 || -8 >> 1 != -4
+#error "unreachable"
+#endif
+
+#define UNDEF_ME
+#undef UNDEF_ME
+#ifdef UNDEF_ME
+#error "unreachable"
+#endif
+
+#include "once.inc"
+#include "once.inc"
+#include "once2.inc"
+#include "once2.inc"
+
+#if !__has_include("test1.inc") || __has_include(<no-such-file.inc>)
+#error "unreachable"
+#endif
+#if !__has_builtin(__builtin_expect) || __has_builtin(no_such_builtin)
+#error "unreachable"
+#endif
+
+#pragma unknown pragmas are ignored with a warning
+
+#line 100
+#if __LINE__ != 100
+#error "unreachable"
+#endif
+#define LINE_ADD(x, y) x + y
+#if LINE_ADD(1, 2) != 3
+#error "unreachable"
+#endif
+
+#include "test1.inc"
 